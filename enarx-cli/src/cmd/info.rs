@@ -2,11 +2,11 @@ use crate::cmd::SubCommand;
 use structopt::StructOpt;
 use tokio::net::UnixStream;
 use tonic::transport::{Endpoint, Uri};
-use std::{convert::TryFrom, path::PathBuf, path::Path};
-use anyhow::{bail, Result};
+use std::path::PathBuf;
+use anyhow::Result;
 use tower::service_fn;
 
-use enarx_proto::v0::{InfoRequest, keepldr_client::KeepldrClient};
+use enarx_proto::v0::{HostInventory, InfoRequest, keepldr_client::KeepldrClient};
 
 // TODO rename to InfoCommandOptions or something..?
 #[derive(StructOpt, Debug)]
@@ -24,7 +24,7 @@ impl SubCommand for InfoOptions {
                     .path_and_query(self.socket_path.to_str().unwrap_or_default())
                     .build()
                     .unwrap();
-        let channel = Endpoint::try_from(uri)?
+        let channel = Endpoint::from(uri)
             .connect_with_connector(
                 service_fn(|u: Uri| { UnixStream::connect(u.path().to_string()) })
             ).await?;
@@ -33,10 +33,124 @@ impl SubCommand for InfoOptions {
 
         let request = tonic::Request::new(InfoRequest {});
 
-        let response = client.info(request).await?;
+        let mut response = client.info(request).await?;
+        let inventory = response.get_mut().inventory.take();
 
         println!("RESPONSE: {:?}", response);
+        if let Some(inventory) = inventory {
+            print!("{}", render_inventory(&inventory));
+        }
         
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Format the host inventory section of `enarx info`, leaving out
+/// anything the host didn't report
+fn render_inventory(inv: &HostInventory) -> String {
+    let mut rows: Vec<(&str, String)> = vec![];
+    if let Some(kernel) = &inv.kernel_version {
+        rows.push(("kernel", kernel.clone()));
+    }
+    match (&inv.cpu_model, inv.cpu_count) {
+        (Some(model), Some(n)) => rows.push(("cpu", format!("{} ({} CPUs)", model, n))),
+        (Some(model), None) => rows.push(("cpu", model.clone())),
+        (None, Some(n)) => rows.push(("cpu", format!("{} CPUs", n))),
+        (None, None) => {}
+    }
+    match (inv.mem_total, inv.mem_available) {
+        (Some(total), Some(avail)) => rows.push((
+            "memory",
+            format!("{} total, {} available", fmt_bytes(total), fmt_bytes(avail)),
+        )),
+        (Some(total), None) => rows.push(("memory", format!("{} total", fmt_bytes(total)))),
+        (None, Some(avail)) => rows.push(("memory", format!("{} available", fmt_bytes(avail)))),
+        (None, None) => {}
+    }
+    if let (Some(total), Some(free)) = (inv.hugepages_total, inv.hugepages_free) {
+        let mut pages = format!("{} of {} free", free, total);
+        if let Some(size) = inv.hugepage_size {
+            pages += &format!(" ({} each)", fmt_bytes(size));
+        }
+        rows.push(("hugepages", pages));
+    }
+    if let Some(iommu) = inv.iommu {
+        rows.push(("iommu", if iommu { "yes" } else { "no" }.to_string()));
+    }
+    if let Some(epc) = inv.sgx_epc_size {
+        rows.push(("sgx epc", fmt_bytes(epc)));
+    }
+    match (inv.sev_asids, inv.sev_es_asids) {
+        (Some(sev), Some(es)) => rows.push(("sev asids", format!("{} (sev-es: {})", sev, es))),
+        (Some(sev), None) => rows.push(("sev asids", sev.to_string())),
+        (None, Some(es)) => rows.push(("sev asids", format!("sev-es: {}", es))),
+        (None, None) => {}
+    }
+
+    let mut out = String::from("host inventory:\n");
+    if rows.is_empty() {
+        out += "  (nothing reported)\n";
+    }
+    for (name, value) in rows {
+        out += &format!("  {:<11}{}\n", format!("{}:", name), value);
+    }
+    out
+}
+
+/// Format a size in bytes with binary units, e.g. "1.5 GiB"
+fn fmt_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    let size = format!("{:.1}", size);
+    format!("{} {}", size.trim_end_matches(".0"), UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fmt_bytes_units() {
+        assert_eq!(fmt_bytes(0), "0 B");
+        assert_eq!(fmt_bytes(1023), "1023 B");
+        assert_eq!(fmt_bytes(2 << 20), "2 MiB");
+        assert_eq!(fmt_bytes(3 << 29), "1.5 GiB");
+        assert_eq!(fmt_bytes(u64::MAX), "16 EiB");
+    }
+
+    #[test]
+    fn inventory_section() {
+        let inv = HostInventory {
+            kernel_version: Some("6.2.0".into()),
+            cpu_model: Some("AMD EPYC 7763 64-Core Processor".into()),
+            cpu_count: Some(2),
+            mem_total: Some(4 << 30),
+            hugepage_size: Some(2 << 20),
+            hugepages_total: Some(8),
+            hugepages_free: Some(6),
+            iommu: Some(false),
+            sev_asids: Some(410),
+            sev_es_asids: Some(99),
+            ..Default::default()
+        };
+        assert_eq!(
+            render_inventory(&inv),
+            "host inventory:\n\
+             \x20 kernel:    6.2.0\n\
+             \x20 cpu:       AMD EPYC 7763 64-Core Processor (2 CPUs)\n\
+             \x20 memory:    4 GiB total\n\
+             \x20 hugepages: 6 of 8 free (2 MiB each)\n\
+             \x20 iommu:     no\n\
+             \x20 sev asids: 410 (sev-es: 99)\n"
+        );
+        assert_eq!(
+            render_inventory(&HostInventory::default()),
+            "host inventory:\n  (nothing reported)\n"
+        );
+    }
+}
@@ -2,7 +2,6 @@
 
 use crate::cmd::{Result, SubCommand};
 use log::info;
-///
 use structopt::{clap::AppSettings, StructOpt};
 
 /// Noop command. Really just a template for adding new commands.
@@ -17,6 +16,7 @@ pub struct NoopOptions {
 
 impl SubCommand for NoopOptions {
     fn execute(self) -> Result<()> {
-        Ok(info!("it works! great job! here, have a hot dog: 🌭"))
+        info!("it works! great job! here, have a hot dog: 🌭");
+        Ok(())
     }
 }
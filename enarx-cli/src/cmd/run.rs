@@ -5,12 +5,24 @@ use anyhow::{bail, Context, Result};
 use log::{debug, info};
 use structopt::StructOpt;
 
-use std::{fmt::Debug, path::PathBuf};
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+};
 
 use std::fs::File;
 //use std::net::Shutdown;
 
-use enarx_config::EnvConfig;
+use enarx_config::{
+    default_arg0, is_sensitive_name, load_workload_config, parse_size, validate_env_name,
+    EnvConfig, HandleParseError, NetRule, ReadHandle, ResourceLimits, SecretSource, WasmConfig,
+    WasmFeatureChanges, WasmFeatureFlags, WasmPreset, WorkloadConfig, WriteHandle, WASM_PAGE_SIZE,
+};
+use enarx_proto::display::SafeDisplay;
+use enarx_proto::v0::boot_request::{boot_item, BootItem};
+use enarx_proto::v0::BootRequest;
+use enarx_proto::validate::{Limits, Validate};
+use prost::Message;
 use std::io::Read;
 #[cfg(unix)]
 use std::os::unix::{io::AsRawFd, net::UnixStream};
@@ -26,14 +38,158 @@ pub struct RunOptions {
         value_name = "NAME=VAL",
         parse(try_from_str=parse_env_var),
     )]
-    pub envs: Vec<(String, String)>,
+    pub envs: Vec<EnvVar>,
+
+    /// Read NAME=VALUE lines from FILE, like a dotenv file. Values are
+    /// used as they are, quotes included, and --env overrides them.
+    #[structopt(
+        long = "env-file",
+        number_of_values = 1,
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    pub env_files: Vec<PathBuf>,
+
+    /// Like --env, but the value is never logged
+    #[structopt(
+        long = "env-secret",
+        number_of_values = 1,
+        value_name = "NAME=VAL",
+        parse(try_from_str=parse_env_secret),
+    )]
+    pub env_secrets: Vec<EnvVar>,
+
+    /// Let --env values like `@cmd:COMMAND` run COMMAND to get the value
+    #[structopt(long)]
+    pub allow_secret_commands: bool,
+
+    /// Expand $NAME and ${NAME} in --env values from the host environment.
+    /// ${NAME:-DEFAULT} allows NAME to be unset, and $$ is a literal $.
+    /// Values from --env-secret are never expanded.
+    #[structopt(long)]
+    pub expand_env: bool,
+
+    /// Let a later --env override an earlier one for the same variable
+    #[structopt(long)]
+    pub allow_duplicate_env: bool,
+
+    /// Pass the host's environment variables through to the program
+    #[structopt(long)]
+    pub inherit_env: bool,
+
+    /// Drop the variables set in the --config file, and make sure nothing
+    /// is inherited from the host; the program only gets the variables set
+    /// with --env-file, --env and --env-secret
+    #[structopt(long, conflicts_with = "inherit-env")]
+    pub clear_env: bool,
+
+    /// Only inherit environment variables whose names match PATTERN
+    #[structopt(
+        long,
+        number_of_values = 1,
+        value_name = "PATTERN",
+        requires = "inherit-env"
+    )]
+    pub env_allow: Vec<String>,
+
+    /// Don't inherit environment variables whose names match PATTERN
+    #[structopt(
+        long,
+        number_of_values = 1,
+        value_name = "PATTERN",
+        requires = "inherit-env"
+    )]
+    pub env_deny: Vec<String>,
+
+    /// Start from a named set of WebAssembly features: strict (the MVP),
+    /// default or experimental (every proposal)
+    #[structopt(long, value_name = "PROFILE")]
+    pub wasm_profile: Option<WasmPreset>,
+
+    /// WebAssembly features to turn on, or off with a leading '-', on top
+    /// of the profile
+    #[structopt(long, value_name = "FEATURE,...", allow_hyphen_values = true)]
+    pub wasm_features: Option<WasmFeatureChanges>,
+
+    /// Limit the program's memory, e.g. `256MiB`
+    #[structopt(long, value_name = "SIZE", parse(try_from_str = parse_max_memory))]
+    pub max_memory: Option<u64>,
+
+    /// Stop the program after it's used N units of fuel
+    #[structopt(long, value_name = "N", parse(try_from_str = parse_nonzero))]
+    pub fuel: Option<u64>,
+
+    /// Limit the keep process's address space (RLIMIT_AS), e.g. `4GiB`
+    #[structopt(long, value_name = "SIZE", parse(try_from_str = parse_nonzero_size))]
+    pub rlimit_as: Option<u64>,
+
+    /// Limit the keep process's CPU time (RLIMIT_CPU), in seconds
+    #[structopt(long, value_name = "SECS", parse(try_from_str = parse_nonzero))]
+    pub rlimit_cpu: Option<u64>,
+
+    /// Limit how many files the keep process can have open (RLIMIT_NOFILE)
+    #[structopt(long, value_name = "N", parse(try_from_str = parse_nonzero))]
+    pub rlimit_nofile: Option<u64>,
+
+    /// Make a host directory available to the program, at GUEST if given
+    #[structopt(
+        long = "dir",
+        number_of_values = 1,
+        value_name = "HOST[::GUEST]",
+        parse(try_from_str = parse_dir)
+    )]
+    pub dirs: Vec<(PathBuf, String)>,
+
+    /// Let the program connect to HOST:PORT; may be given more than once.
+    /// HOST can be a name, `*.domain`, an address or a CIDR network, and
+    /// PORT can be a range like `8000-8080` or `*`
+    #[structopt(long, number_of_values = 1, value_name = "HOST:PORT")]
+    pub allow_net: Vec<NetRule>,
+
+    /// What the program sees as argv[0] [default: the module's file stem]
+    #[structopt(long, value_name = "NAME")]
+    pub arg0: Option<String>,
+
+    /// The program's working directory
+    #[structopt(long, value_name = "GUEST", parse(from_os_str))]
+    pub workdir: Option<PathBuf>,
 
-    // TODO: --inherit-env
     /// Name of the function to invoke
     #[structopt(long, value_name = "FUNCTION")]
     pub invoke: Option<String>,
 
-    // TODO: --stdin, --stdout, --stderr
+    /// Read workload settings from an Enarx.toml file; other flags override it
+    #[structopt(long, value_name = "FILE", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Write the assembled BootRequest to FILE and exit without connecting
+    #[structopt(long, value_name = "FILE", parse(from_os_str))]
+    pub emit_request: Option<PathBuf>,
+
+    /// The shim to put in the BootRequest
+    #[structopt(long, value_name = "FILE", parse(from_os_str))]
+    pub shim: Option<PathBuf>,
+
+    /// The exec (the loader that runs the module) to put in the BootRequest
+    #[structopt(long, value_name = "FILE", parse(from_os_str))]
+    pub exec: Option<PathBuf>,
+
+    /// Check the configuration and module, then exit without running anything
+    #[structopt(long)]
+    pub config_check: bool,
+
+    /// Where the program's stdin comes from [default: inherit]
+    #[structopt(long, value_name = "HANDLE")]
+    pub stdin: Option<ReadHandle>,
+
+    /// Where the program's stdout goes [default: inherit]
+    #[structopt(long, value_name = "HANDLE", parse(try_from_str = parse_stdout))]
+    pub stdout: Option<WriteHandle>,
+
+    /// Where the program's stderr goes [default: inherit]
+    #[structopt(long, value_name = "HANDLE", parse(try_from_str = parse_stderr))]
+    pub stderr: Option<WriteHandle>,
+
     /// Path of the WebAssembly module to run
     #[structopt(index = 1, value_name = "MODULE", parse(from_os_str))]
     pub module: PathBuf,
@@ -43,12 +199,84 @@ pub struct RunOptions {
     pub args: Vec<String>,
 }
 
-fn parse_env_var(s: &str) -> Result<(String, String)> {
+/// A NAME=VAL pair from --env or --env-secret. The value is left out of
+/// the `Debug` output if it's from --env-secret or the name looks like it
+/// holds something secret.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EnvVar {
+    pub name: String,
+    pub value: String,
+    pub sensitive: bool,
+}
+
+impl Debug for EnvVar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.sensitive || is_sensitive_name(&self.name) {
+            write!(f, "{:?}=<redacted>", self.name)
+        } else {
+            write!(f, "{:?}={:?}", self.name, self.value)
+        }
+    }
+}
+
+fn parse_env_var(s: &str) -> Result<EnvVar> {
     let parts: Vec<&str> = s.splitn(2, '=').collect();
     if parts.len() != 2 {
         bail!("must be of the form `NAME=VAL`");
     }
-    Ok((parts[0].to_owned(), parts[1].to_owned()))
+    validate_env_name(parts[0])?;
+    Ok(EnvVar {
+        name: parts[0].to_owned(),
+        value: parts[1].to_owned(),
+        sensitive: false,
+    })
+}
+
+fn parse_env_secret(s: &str) -> Result<EnvVar> {
+    Ok(EnvVar {
+        sensitive: true,
+        ..parse_env_var(s)?
+    })
+}
+
+/// Parse HOST[::GUEST]; without a GUEST, the directory appears at the same
+/// path in the guest as on the host, so HOST has to be absolute.
+fn parse_dir(s: &str) -> Result<(PathBuf, String)> {
+    match s.split_once("::") {
+        Some((host, guest)) => Ok((host.into(), guest.into())),
+        None if s.starts_with('/') => Ok((s.into(), s.into())),
+        None => bail!("relative paths need a guest path, like `{}::/{}`", s, s),
+    }
+}
+
+fn parse_max_memory(s: &str) -> Result<u64> {
+    let bytes = parse_size(s)?;
+    if bytes < WASM_PAGE_SIZE {
+        bail!("must be at least one WebAssembly page (64KiB)");
+    }
+    Ok(bytes)
+}
+
+fn parse_nonzero(s: &str) -> Result<u64> {
+    match s.parse()? {
+        0 => bail!("must be more than 0"),
+        n => Ok(n),
+    }
+}
+
+fn parse_nonzero_size(s: &str) -> Result<u64> {
+    match parse_size(s)? {
+        0 => bail!("must be more than 0"),
+        n => Ok(n),
+    }
+}
+
+fn parse_stdout(s: &str) -> Result<WriteHandle, HandleParseError> {
+    WriteHandle::parse_with_fd(s, 1)
+}
+
+fn parse_stderr(s: &str) -> Result<WriteHandle, HandleParseError> {
+    WriteHandle::parse_with_fd(s, 2)
 }
 
 impl RunOptions {
@@ -65,7 +293,144 @@ impl RunOptions {
         File::open(&self.module).with_context(|| format!("could not open {:?}", self.module))
     }
 
+    fn read_module(&self) -> Result<Vec<u8>> {
+        let mut module = Vec::new();
+        self.get_module_reader()?
+            .read_to_end(&mut module)
+            .with_context(|| format!("could not read {:?}", self.module))?;
+        Ok(module)
+    }
+
+    /// Combine the --config file (if any) with the other flags, using
+    /// `EnvConfig::merge()`: variables set with --env replace the ones from
+    /// --env-file, which replace the file's, and --arg0, ARGS, --dir,
+    /// --workdir and --stdin/--stdout/--stderr replace the file's settings
+    /// if they're given. So does --invoke. --clear-env empties the file's
+    /// environment before anything else is added.
+    fn workload_config(&self) -> Result<WorkloadConfig> {
+        let mut config = match &self.config {
+            Some(path) => load_workload_config(path)?,
+            None => WorkloadConfig::default(),
+        };
+        if self.clear_env {
+            config.env = config.env.clear_env();
+        }
+        // --env-file goes over the config file, and under everything else
+        let mut env_files = EnvConfig::default();
+        for path in &self.env_files {
+            env_files = env_files.envs_from_file(path)?;
+        }
+        config.env = config.env.merge(env_files);
+        // Repeated --env flags are kept as-is; see --allow-duplicate-env
+        let mut flags = EnvConfig::default();
+        for var in &self.envs {
+            flags
+                .push_value(var.name.clone(), &var.value)
+                .with_context(|| format!("invalid value for --env {}", var.name))?;
+        }
+        for var in &self.env_secrets {
+            flags.push_secret(var.name.clone(), var.value.clone());
+        }
+        flags.arg0 = self.arg0.clone();
+        flags.args = self.args.clone();
+        flags.preopens = self.dirs.clone();
+        flags.cwd = self.workdir.clone();
+        flags.stdin = self.stdin.clone();
+        flags.stdout = self.stdout.clone();
+        flags.stderr = self.stderr.clone();
+        flags.limits = ResourceLimits {
+            address_space: self.rlimit_as,
+            cpu_seconds: self.rlimit_cpu,
+            open_files: self.rlimit_nofile,
+        };
+        flags.network.allow = self.allow_net.clone();
+        // Expand before merging, so only the flags' values are expanded
+        flags.expand_env = self.expand_env;
+        let flags = flags.expand()?;
+        config.env = config.env.merge(flags);
+        // Fill in argv[0] here, so whatever the config is sent to gets it too
+        (config.env.arg0).get_or_insert_with(|| default_arg0(&self.module));
+        if self.invoke.is_some() {
+            config.invoke = self.invoke.clone();
+        }
+        Ok(config)
+    }
+
+    fn wasm_config(&self) -> WasmConfig {
+        WasmConfig {
+            features: self
+                .wasm_features
+                .clone()
+                .unwrap_or_default()
+                .apply_to(WasmFeatureFlags::preset(
+                    self.wasm_profile.unwrap_or_default(),
+                ))
+                .into(),
+            max_memory_bytes: self.max_memory,
+            fuel: self.fuel,
+            ..Default::default()
+        }
+    }
+
+    /// Check that the module can be read and is valid WebAssembly
+    fn check_config(&self) -> Result<()> {
+        let mut env = self.workload_config()?.env;
+        if self.allow_duplicate_env {
+            env = env.dedup_envs();
+        }
+        env.validate()?;
+        if !self.allow_secret_commands {
+            if let Some((name, _)) =
+                (env.secrets.iter()).find(|(_, source)| matches!(source, SecretSource::Command(_)))
+            {
+                bail!(
+                    "{} is set by a command; that needs --allow-secret-commands",
+                    name
+                );
+            }
+        }
+        let module = self.read_module()?;
+        self.wasm_config()
+            .validate(&module)
+            .with_context(|| format!("{:?} is not a valid WebAssembly module", self.module))?;
+        Ok(())
+    }
+
+    /// Assemble a BootRequest that carries the module as its workload,
+    /// along with the --shim and --exec files if they're given
+    fn boot_request(&self) -> Result<BootRequest> {
+        let blob = |bytes| BootItem {
+            from: Some(boot_item::From::Blob(bytes)),
+        };
+        let read = |path: &Option<PathBuf>| -> Result<Option<BootItem>> {
+            match path {
+                Some(path) => Ok(Some(blob(
+                    std::fs::read(path).with_context(|| format!("could not read {:?}", path))?,
+                ))),
+                None => Ok(None),
+            }
+        };
+        Ok(BootRequest {
+            shim: read(&self.shim)?, // TODO: pick a shim for the backend
+            exec: read(&self.exec)?, // TODO: default to wasmldr
+            work: Some(blob(self.read_module()?)),
+            listen_fds: vec![],
+        })
+    }
+
+    /// Write the length-delimited BootRequest to the given path
+    fn emit_request(&self, path: &Path) -> Result<()> {
+        let request = self.boot_request()?;
+        debug!("boot request: {}", request.safe_display());
+        request.validate(&Limits::default())?;
+        std::fs::write(path, request.encode_length_delimited_to_vec())
+            .with_context(|| format!("could not write {:?}", path))?;
+        info!("wrote BootRequest to {:?}", path);
+        Ok(())
+    }
+
     #[cfg(unix)]
+    #[allow(dead_code)]
     fn local_keepmgr(&self) -> Result<()> {
         let (sock_l, sock_r) = UnixStream::pair()?;
         debug!(
@@ -75,6 +440,47 @@ impl RunOptions {
         );
         bail!("Not implemented yet!");
     }
+
+    /// Build a keep from the workload config, then load and run the module
+    fn launch(&self) -> Result<Report> {
+        let WorkloadConfig { env, invoke } = self.workload_config()?;
+
+        let module = self.get_module_reader()?;
+        debug!("module open on fd{}", module.as_raw_fd());
+
+        // Build a new, empty keep
+        let keep = KeepBuilder::new(env)
+            .default_loader()
+            .inherit_stdio(true)
+            .inherit_env(self.inherit_env, &self.env_allow, &self.env_deny)
+            .allow_duplicate_env(self.allow_duplicate_env)
+            .build()?;
+        debug!("built keep: {:?}", keep);
+
+        // Send what the keep was built with, after inheriting and deduping
+        let env = &keep.env_config;
+        let (mut envs, argv, cwd) = (env.envs.clone(), env.argv(&self.module), env.cwd.clone());
+        let secrets = env.secrets.clone();
+
+        // Look up secrets as late as possible, so they're not kept around
+        for (name, source) in secrets {
+            let value = source.resolve(self.allow_secret_commands)?;
+            envs.push((name, value.into_inner()));
+        }
+
+        // Configure wasmldr, load code into keep, and run it
+        keep
+            // Configure wasmldr/wasmtime
+            .config(self.wasm_config())?
+            // Configure the WASI environment
+            .envs(envs)?.args(argv)?.cwd(cwd)?
+            // Load the module into the keep
+            .module(module)?
+            // Look up the function we want to run
+            .function(invoke)?
+            // And run it!
+            .run()
+    }
 }
 
 #[derive(Debug)]
@@ -82,15 +488,36 @@ struct KeepBuilder {
     env_config: EnvConfig,
 }
 impl KeepBuilder {
-    fn new() -> Self {
-        Self {
-            env_config: Default::default(),
-        }
+    fn new(env_config: EnvConfig) -> Self {
+        Self { env_config }
     }
 
+    /// Inherit whichever of stdin/stdout/stderr haven't been set up already
     fn inherit_stdio(mut self, inherit: bool) -> Self {
         if inherit {
-            self.env_config = self.env_config.inherit_stdio();
+            let inherited = EnvConfig::default().inherit_stdio();
+            let env = &mut self.env_config;
+            env.stdin = env.stdin.take().or(inherited.stdin);
+            env.stdout = env.stdout.take().or(inherited.stdout);
+            env.stderr = env.stderr.take().or(inherited.stderr);
+        }
+        self
+    }
+
+    fn inherit_env(mut self, inherit: bool, allow: &[String], deny: &[String]) -> Self {
+        if inherit {
+            let allow: Vec<&str> = allow.iter().map(String::as_str).collect();
+            let deny: Vec<&str> = deny.iter().map(String::as_str).collect();
+            self.env_config = self.env_config.inherit_env_filtered(&allow, &deny);
+        }
+        self
+    }
+
+    /// Keep only the last setting of each environment variable, rather than
+    /// failing the build
+    fn allow_duplicate_env(mut self, allow: bool) -> Self {
+        if allow {
+            self.env_config = self.env_config.dedup_envs();
         }
         self
     }
@@ -100,34 +527,82 @@ impl KeepBuilder {
         self
     }
 
+    /// Check the final config and hand it over to the keep. Anything that
+    /// changes the environment has to happen before this.
     fn build(self) -> Result<KeepConn> {
-        Ok(KeepConn {})
+        self.env_config.validate()?;
+        let env = &self.env_config;
+        debug!(
+            "stdin: {}, stdout: {}, stderr: {}",
+            display_or_unset(env.stdin.as_ref()),
+            display_or_unset(env.stdout.as_ref()),
+            display_or_unset(env.stderr.as_ref())
+        );
+        Ok(KeepConn {
+            env_config: self.env_config,
+            sent: Report::default(),
+        })
     }
 }
 
-#[derive(Debug)]
-struct KeepConn {}
+fn display_or_unset(handle: Option<&impl std::fmt::Display>) -> String {
+    handle.map_or_else(|| "unset".into(), ToString::to_string)
+}
 
 #[derive(Debug)]
-struct Report {}
+struct KeepConn {
+    /// The config the keep was built with
+    env_config: EnvConfig,
+    /// What's been sent to the keep so far
+    sent: Report,
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    envs: Vec<(String, String)>,
+    args: Vec<String>,
+    cwd: Option<PathBuf>,
+    wasm: WasmConfig,
+}
 
 impl KeepConn {
-    fn config(self) -> Result<Self> {
+    fn config(mut self, wasm: WasmConfig) -> Result<Self> {
+        debug!(
+            "max memory: {:?} bytes, fuel: {:?}",
+            wasm.max_memory_bytes, wasm.fuel
+        );
+        // TODO: send this to the keep
+        self.sent.wasm = wasm;
         Ok(self)
     }
 
-    fn envs<K, V>(self, envs: impl IntoIterator<Item = (K, V)>) -> Result<Self>
+    fn envs<K, V>(mut self, envs: impl IntoIterator<Item = (K, V)>) -> Result<Self>
     where
         K: AsRef<str>,
         V: AsRef<str>,
     {
+        // TODO: send these to the keep
+        (self.sent.envs).extend(
+            (envs.into_iter()).map(|(k, v)| (k.as_ref().to_string(), v.as_ref().to_string())),
+        );
         Ok(self)
     }
 
-    fn args<A>(self, args: impl IntoIterator<Item = A>) -> Result<Self>
+    fn args<A>(mut self, args: impl IntoIterator<Item = A>) -> Result<Self>
     where
         A: AsRef<str>,
     {
+        // TODO: send these to the keep
+        (self.sent.args).extend(args.into_iter().map(|a| a.as_ref().to_string()));
+        Ok(self)
+    }
+
+    fn cwd(mut self, cwd: Option<PathBuf>) -> Result<Self> {
+        if let Some(ref dir) = cwd {
+            debug!("will start in {:?}", dir);
+            // TODO
+        }
+        self.sent.cwd = cwd;
         Ok(self)
     }
 
@@ -151,38 +626,619 @@ impl KeepConn {
     }
 
     fn run(self) -> Result<Report> {
-        Ok(Report {})
+        Ok(self.sent)
     }
 }
 
 impl SubCommand for RunOptions {
     /// Run a WebAssembly workload.
     fn execute(self) -> Result<()> {
-        let module = self.get_module_reader()?;
-        debug!("module open on fd{}", module.as_raw_fd());
+        if self.config_check {
+            self.check_config()?;
+            info!("configuration OK");
+            return Ok(());
+        }
 
-        // Build a new, empty keep
-        let keep = KeepBuilder::new()
-            .default_loader()
-            .inherit_stdio(true) // TODO: get from CLI
-            .build()?;
-        debug!("built keep: {:?}", keep);
+        if let Some(ref path) = self.emit_request {
+            return self.emit_request(path);
+        }
 
-        // Configure wasmldr, load code into keep, and run it
-        let report = keep
-            // Configure wasmldr/wasmtime
-            .config(/*self.loader_config*/)?
-            // Configure the WASI environment
-            .envs(self.envs)?.args(self.args)?
-            // Load the module into the keep
-            .module(module)?
-            // Look up the function we want to run
-            .function(self.invoke)?
-            // And run it!
-            .run()?;
+        let report = self.launch()?;
         debug!("report: {:?}", report);
 
         // Tada!
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use std::io::Write;
+
+    fn check_module(bytes: &[u8]) -> Result<()> {
+        check_module_with(bytes, &[])
+    }
+
+    fn check_module_with(bytes: &[u8], flags: &[&str]) -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(bytes).unwrap();
+        let path = file.path().to_str().unwrap();
+        let mut args = vec!["run", "--config-check"];
+        args.extend(flags);
+        args.push(path);
+        RunOptions::from_iter(&args).execute()
+    }
+
+    #[test]
+    fn config_check_valid() {
+        check_module(b"\0asm\x01\0\0\0").unwrap();
+    }
+
+    #[test]
+    fn config_check_invalid_module() {
+        let err = check_module(b"\0asm\x02\0\0\0").unwrap_err();
+        assert!(err.to_string().contains("not a valid WebAssembly module"));
+    }
+
+    #[test]
+    fn emit_empty_module() {
+        let module = tempfile::NamedTempFile::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("boot.req");
+        let (shim, exec) = (dir.path().join("shim"), dir.path().join("exec"));
+        std::fs::write(&shim, b"shim").unwrap();
+        std::fs::write(&exec, b"exec").unwrap();
+        let emit = |extra: &[&str]| {
+            let mut args = vec!["run", "--emit-request", out.to_str().unwrap()];
+            args.extend(extra);
+            args.push(module.path().to_str().unwrap());
+            RunOptions::from_iter(&args).execute()
+        };
+
+        let err = emit(&["--exec", exec.to_str().unwrap()]).unwrap_err();
+        assert_eq!(err.to_string(), "shim: missing");
+        let both = [
+            "--shim",
+            shim.to_str().unwrap(),
+            "--exec",
+            exec.to_str().unwrap(),
+        ];
+        let err = emit(&both).unwrap_err();
+        assert_eq!(err.to_string(), "work.blob: empty");
+        assert!(!out.exists());
+
+        std::fs::write(module.path(), b"\0asm\x01\0\0\0").unwrap();
+        emit(&both).unwrap();
+        let bytes = std::fs::read(&out).unwrap();
+        let request = BootRequest::decode_length_delimited(bytes.as_slice()).unwrap();
+        let blob = |item: Option<BootItem>| match item.unwrap().from {
+            Some(boot_item::From::Blob(blob)) => blob,
+            None => panic!("no blob"),
+        };
+        assert_eq!(blob(request.shim), b"shim");
+        assert_eq!(blob(request.exec), b"exec");
+    }
+
+    #[test]
+    #[serial]
+    fn launch_sends_final_env() {
+        std::env::set_var("ENARX_TEST_LAUNCH_X", "host");
+        std::env::set_var("ENARX_TEST_LAUNCH_Y", "host");
+        let module = tempfile::NamedTempFile::new().unwrap();
+        let args = [
+            "run",
+            "--inherit-env",
+            "--env-allow",
+            "ENARX_TEST_LAUNCH_X",
+            "-e",
+            "A=1",
+            "-e",
+            "A=2",
+            "--allow-duplicate-env",
+            "--workdir",
+            "/work",
+            module.path().to_str().unwrap(),
+            "--",
+            "arg",
+        ];
+        let report = RunOptions::from_iter(&args).launch().unwrap();
+        assert_eq!(
+            report.envs,
+            vec![
+                ("ENARX_TEST_LAUNCH_X".to_string(), "host".to_string()),
+                ("A".to_string(), "2".to_string()),
+            ]
+        );
+        assert_eq!(report.args[1..], ["arg"]);
+        assert_eq!(report.cwd, Some(PathBuf::from("/work")));
+    }
+
+    #[test]
+    fn config_check_missing_module() {
+        let opts = RunOptions::from_iter(&["run", "--config-check", "/nonexistent/x.wasm"]);
+        let err = opts.execute().unwrap_err();
+        assert!(err.to_string().contains("could not open"));
+    }
+
+    #[test]
+    fn config_check_wasm_features() {
+        // One function that does `v128.const 0; drop`
+        let mut simd = b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x03\x02\x01\0".to_vec();
+        simd.extend(b"\x0a\x17\x01\x15\0\xfd\x0c");
+        simd.extend([0; 16]);
+        simd.extend(b"\x1a\x0b");
+
+        check_module(&simd).unwrap_err();
+        check_module_with(&simd, &["--wasm-features", "simd"]).unwrap();
+        check_module_with(&simd, &["--wasm-features", "simd,-simd"]).unwrap_err();
+
+        let err =
+            RunOptions::from_iter_safe(&["run", "--wasm-features", "-gc", "x.wasm"]).unwrap_err();
+        assert!(
+            err.message.contains("unknown WebAssembly feature"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn config_check_wasm_profile() {
+        // One function that returns two i32s, which needs multi-value
+        let multi = b"\0asm\x01\0\0\0\x01\x06\x01\x60\0\x02\x7f\x7f\x03\x02\x01\0\
+                      \x0a\x08\x01\x06\0\x41\0\x41\0\x0b";
+
+        check_module(multi).unwrap();
+        check_module_with(multi, &["--wasm-profile", "default"]).unwrap();
+        check_module_with(multi, &["--wasm-profile", "strict"]).unwrap_err();
+        let args = ["--wasm-profile", "strict", "--wasm-features", "multi-value"];
+        check_module_with(multi, &args).unwrap();
+        let args = [
+            "--wasm-profile",
+            "experimental",
+            "--wasm-features",
+            "-multi-value",
+        ];
+        check_module_with(multi, &args).unwrap_err();
+
+        let err =
+            RunOptions::from_iter_safe(&["run", "--wasm-profile", "mvp", "x.wasm"]).unwrap_err();
+        assert!(
+            err.message.contains("unknown WebAssembly profile"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn workload_config_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Enarx.toml");
+        std::fs::write(
+            &path,
+            "invoke = \"main\"\nargs = [\"from-file\"]\n[env]\nFOO = \"file\"\nBAR = \"file\"\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        // Nothing on the command line: everything comes from the file
+        let opts = RunOptions::from_iter(&["run", "--config", path, "x.wasm"]);
+        let config = opts.workload_config().unwrap();
+        assert_eq!(config.invoke.as_deref(), Some("main"));
+        assert_eq!(config.env.args, vec!["from-file"]);
+
+        // Flags win over the file
+        let opts = RunOptions::from_iter(&[
+            "run", "--config", path, "-e", "FOO=cli", "--invoke", "other", "x.wasm", "--", "a", "b",
+        ]);
+        let config = opts.workload_config().unwrap();
+        assert_eq!(config.invoke.as_deref(), Some("other"));
+        assert_eq!(config.env.args, vec!["a", "b"]);
+        assert_eq!(
+            config.env.envs,
+            vec![
+                ("BAR".to_string(), "file".to_string()),
+                ("FOO".to_string(), "cli".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn env_file_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = dir.path().join("Enarx.toml");
+        std::fs::write(&toml, "[env]\nA = \"toml\"\nB = \"toml\"\n").unwrap();
+        let first = dir.path().join("first.env");
+        std::fs::write(&first, "# first\nB=first\nC=first\nD=first\n").unwrap();
+        let second = dir.path().join("second.env");
+        std::fs::write(&second, "D=\"second\"\n").unwrap();
+
+        let opts = RunOptions::from_iter(&[
+            "run",
+            "--config",
+            toml.to_str().unwrap(),
+            "--env-file",
+            first.to_str().unwrap(),
+            "--env-file",
+            second.to_str().unwrap(),
+            "-e",
+            "C=cli",
+            "x.wasm",
+        ]);
+        let env = opts.workload_config().unwrap().env;
+        let pairs: Vec<(&str, &str)> = (env.envs.iter())
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("A", "toml"),
+                ("B", "first"),
+                ("D", "\"second\""),
+                ("C", "cli")
+            ]
+        );
+
+        std::fs::write(&second, "oops\n").unwrap();
+        let module = b"\0asm\x01\0\0\0";
+        let err = check_module_with(module, &["--env-file", second.to_str().unwrap()]).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("second.env:1: expected NAME=VALUE"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn config_check_bad_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Enarx.toml");
+        std::fs::write(&path, "invok = \"main\"\n").unwrap();
+        let err = check_module_with(b"\0asm\x01\0\0\0", &["--config", path.to_str().unwrap()])
+            .unwrap_err();
+        assert!(err.to_string().contains("invok"), "{}", err);
+    }
+
+    #[test]
+    fn config_check_env() {
+        let module = b"\0asm\x01\0\0\0";
+        let err = check_module_with(module, &["-e", "A=1", "-e", "A=2"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "environment variable A is set more than once"
+        );
+        check_module_with(module, &["-e", "A=1", "-e", "A=2", "--allow-duplicate-env"]).unwrap();
+    }
+
+    #[test]
+    fn stdio_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Enarx.toml");
+        std::fs::write(&path, "[stdio]\nstdin = \"null\"\nstdout = \"null\"\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let opts = RunOptions::from_iter(&[
+            "run",
+            "--config",
+            path,
+            "--stdout",
+            "file:/tmp/out.log",
+            "--stderr",
+            "inherit",
+            "x.wasm",
+        ]);
+        let env = opts.workload_config().unwrap().env;
+        assert!(matches!(env.stdin, Some(ReadHandle::Null)));
+        assert!(matches!(env.stdout, Some(WriteHandle::File(_))));
+        assert!(matches!(env.stderr, Some(WriteHandle::Inherit(2))));
+
+        let opts = RunOptions::from_iter(&["run", "--stdout", "inherit", "x.wasm"]);
+        assert!(matches!(opts.stdout, Some(WriteHandle::Inherit(1))));
+
+        let err = RunOptions::from_iter_safe(&["run", "--stdin", "foo:bar", "x.wasm"]).unwrap_err();
+        assert!(err.message.contains("unknown handle scheme"), "{}", err);
+    }
+
+    #[test]
+    fn dir_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let host = dir.path().to_str().unwrap();
+        let guest_dir = format!("{}::/data", host);
+        let opts = RunOptions::from_iter(&[
+            "run",
+            "--dir",
+            host,
+            "--dir",
+            &guest_dir,
+            "--workdir",
+            "/data",
+            "x.wasm",
+        ]);
+        let env = opts.workload_config().unwrap().env;
+        assert_eq!(
+            env.preopens,
+            vec![
+                (dir.path().to_owned(), host.to_string()),
+                (dir.path().to_owned(), "/data".to_string()),
+            ]
+        );
+        assert_eq!(env.cwd.as_deref(), Some(Path::new("/data")));
+
+        let err = RunOptions::from_iter_safe(&["run", "--dir", ".", "x.wasm"]).unwrap_err();
+        assert!(err.message.contains("need a guest path"), "{}", err);
+
+        let module = b"\0asm\x01\0\0\0";
+        let twice = ["--dir", &guest_dir, "--dir", "/::/data"];
+        let err = check_module_with(module, &twice).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "guest path \"/data\" is used more than once"
+        );
+    }
+
+    #[test]
+    fn arg0_flag() {
+        let opts = RunOptions::from_iter(&["run", "./target/foo.wasm"]);
+        let env = opts.workload_config().unwrap().env;
+        assert_eq!(env.arg0.as_deref(), Some("foo"));
+
+        let opts = RunOptions::from_iter(&["run", "--arg0", "ls", "busybox", "--", "-l"]);
+        let env = opts.workload_config().unwrap().env;
+        assert_eq!(env.argv(&opts.module), vec!["ls", "-l"]);
+    }
+
+    #[test]
+    #[serial]
+    fn expand_env_flag() {
+        std::env::set_var("ENARX_TEST_EXPAND_HOME", "/home/me");
+        let flags = [
+            "-e",
+            "DIR=${ENARX_TEST_EXPAND_HOME}/.app",
+            "--env-secret",
+            "PASS=$ENARX_TEST_EXPAND_HOME",
+            "x.wasm",
+        ];
+        let env = |extra: &[&str]| {
+            let mut args = vec!["run"];
+            args.extend(extra);
+            args.extend(&flags);
+            RunOptions::from_iter(&args)
+                .workload_config()
+                .map(|c| c.env)
+        };
+        let expanded = env(&["--expand-env"]).unwrap();
+        assert_eq!(
+            expanded.envs,
+            vec![
+                ("DIR".to_string(), "/home/me/.app".to_string()),
+                ("PASS".to_string(), "$ENARX_TEST_EXPAND_HOME".to_string()),
+            ]
+        );
+        assert!(!expanded.expand_env);
+        assert_eq!(
+            env(&[]).unwrap().envs[0].1,
+            "${ENARX_TEST_EXPAND_HOME}/.app"
+        );
+
+        let opts =
+            RunOptions::from_iter(&["run", "--expand-env", "-e", "X=$ENARX_TEST_UNSET", "x.wasm"]);
+        let err = opts.workload_config().unwrap_err();
+        assert!(
+            err.to_string().contains("ENARX_TEST_UNSET is not set"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn secret_env_flags() {
+        let opts = RunOptions::from_iter(&[
+            "run",
+            "-e",
+            "AT=@@home",
+            "-e",
+            "TOKEN=@env:ENARX_TEST_TOKEN",
+            "x.wasm",
+        ]);
+        let env = opts.workload_config().unwrap().env;
+        assert_eq!(env.envs, vec![("AT".to_string(), "@home".to_string())]);
+        assert_eq!(
+            env.secrets,
+            vec![(
+                "TOKEN".to_string(),
+                SecretSource::Env("ENARX_TEST_TOKEN".into())
+            )]
+        );
+
+        let opts = RunOptions::from_iter(&["run", "-e", "AT=@home", "x.wasm"]);
+        let err = opts.workload_config().unwrap_err();
+        assert!(format!("{:#}", err).contains("--env AT"), "{:#}", err);
+
+        let module = b"\0asm\x01\0\0\0";
+        let err = check_module_with(module, &["-e", "PW=@cmd:pass show db"]).unwrap_err();
+        assert!(
+            err.to_string().contains("--allow-secret-commands"),
+            "{}",
+            err
+        );
+        check_module_with(
+            module,
+            &["--allow-secret-commands", "-e", "PW=@cmd:pass show db"],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn limit_flags() {
+        let opts =
+            RunOptions::from_iter(&["run", "--max-memory", "256MiB", "--fuel", "5000", "x.wasm"]);
+        let config = opts.wasm_config();
+        assert_eq!(config.max_memory_bytes, Some(256 << 20));
+        assert_eq!(config.fuel, Some(5000));
+
+        for bad in &[
+            &["--max-memory", "0"][..],
+            &["--max-memory", "64000"],
+            &["--max-memory", "lots"],
+            &["--fuel", "0"],
+            &["--fuel", "-1"],
+        ] {
+            let args = [&["run"][..], bad, &["x.wasm"]].concat();
+            assert!(RunOptions::from_iter_safe(&args).is_err(), "{:?}", bad);
+        }
+        RunOptions::from_iter(&["run", "--max-memory", "64KiB", "x.wasm"]);
+    }
+
+    #[test]
+    fn launch_sends_limits() {
+        let module = tempfile::NamedTempFile::new().unwrap();
+        let module = module.path().to_str().unwrap();
+        let args = ["run", "--max-memory", "1MiB", "--fuel", "5000", module];
+        let report = RunOptions::from_iter(&args).launch().unwrap();
+        assert_eq!(report.wasm.max_memory_bytes, Some(1 << 20));
+        assert_eq!(report.wasm.fuel, Some(5000));
+
+        let report = RunOptions::from_iter(&["run", module]).launch().unwrap();
+        assert_eq!(report.wasm.max_memory_bytes, None);
+        assert_eq!(report.wasm.fuel, None);
+    }
+
+    #[test]
+    fn rlimit_flags() {
+        let opts = RunOptions::from_iter(&[
+            "run",
+            "--rlimit-as",
+            "4GiB",
+            "--rlimit-cpu",
+            "30",
+            "--rlimit-nofile",
+            "128",
+            "x.wasm",
+        ]);
+        let env = opts.workload_config().unwrap().env;
+        assert_eq!(
+            env.limits,
+            ResourceLimits {
+                address_space: Some(4 << 30),
+                cpu_seconds: Some(30),
+                open_files: Some(128),
+            }
+        );
+        let opts = RunOptions::from_iter(&["run", "x.wasm"]);
+        assert!(opts.workload_config().unwrap().env.limits.is_empty());
+
+        for bad in &[
+            &["--rlimit-as", "0"][..],
+            &["--rlimit-as", "lots"],
+            &["--rlimit-cpu", "0"],
+            &["--rlimit-nofile", "-1"],
+        ] {
+            let args = [&["run"][..], bad, &["x.wasm"]].concat();
+            assert!(RunOptions::from_iter_safe(&args).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn allow_net_flag() {
+        let opts = RunOptions::from_iter(&[
+            "run",
+            "--allow-net",
+            "api.example.com:443",
+            "--allow-net",
+            "10.0.0.0/8:5432",
+            "x.wasm",
+        ]);
+        let network = opts.workload_config().unwrap().env.network;
+        let rules: Vec<String> = network.allow.iter().map(|r| r.to_string()).collect();
+        assert_eq!(rules, ["api.example.com:443", "10.0.0.0/8:5432"]);
+        assert_eq!(network.default_allow, None);
+
+        let opts = RunOptions::from_iter(&["run", "x.wasm"]);
+        assert!(opts.workload_config().unwrap().env.network.is_default());
+
+        for bad in &["example.com", "10.0.0.1/8:80", "fd00::1:80"] {
+            let args = ["run", "--allow-net", bad, "x.wasm"];
+            assert!(RunOptions::from_iter_safe(&args).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn clear_env_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = dir.path().join("Enarx.toml");
+        std::fs::write(&toml, "[env]\nFILE = \"toml\"\n").unwrap();
+        let env_file = dir.path().join("vars.env");
+        std::fs::write(&env_file, "B=file\n").unwrap();
+        let args = [
+            "run",
+            "--clear-env",
+            "--config",
+            toml.to_str().unwrap(),
+            "--env-file",
+            env_file.to_str().unwrap(),
+            "-e",
+            "A=1",
+            "x.wasm",
+        ];
+        let env = RunOptions::from_iter(&args).workload_config().unwrap().env;
+        assert_eq!(env.env_cleared, Some(true));
+        assert_eq!(
+            env.envs,
+            vec![
+                ("B".to_string(), "file".to_string()),
+                ("A".to_string(), "1".to_string()),
+            ]
+        );
+
+        // With the flag set, the keep doesn't inherit anything
+        std::env::set_var("ENARX_TEST_CLEAR_ENV_FLAG", "host");
+        let keep = KeepBuilder::new(env).inherit_env(true, &[], &[]);
+        assert_eq!(keep.env_config.envs.len(), 2);
+
+        let opts = RunOptions::from_iter(&["run", "x.wasm"]);
+        assert_eq!(opts.workload_config().unwrap().env.env_cleared, None);
+        let args = ["run", "--clear-env", "--inherit-env", "x.wasm"];
+        assert!(RunOptions::from_iter_safe(&args).is_err());
+    }
+
+    #[test]
+    fn debug_redacts_env_flags() {
+        let opts = RunOptions::from_iter(&[
+            "run",
+            "-e",
+            "RUST_LOG=debug",
+            "-e",
+            "API_TOKEN=hunter2",
+            "--env-secret",
+            "MINE=hunter3",
+            "x.wasm",
+        ]);
+        let debug = format!("{:#?}", opts);
+        assert!(!debug.contains("hunter"), "{}", debug);
+        assert!(debug.contains("\"RUST_LOG\"=\"debug\""), "{}", debug);
+        assert!(debug.contains("\"MINE\"=<redacted>"), "{}", debug);
+
+        let env = opts.workload_config().unwrap().env;
+        assert!(env.is_sensitive("MINE"));
+        assert!(env
+            .envs
+            .contains(&("MINE".to_string(), "hunter3".to_string())));
+        assert!(!format!("{:?}", env).contains("hunter"));
+    }
+
+    #[test]
+    fn env_flag_names() {
+        RunOptions::from_iter(&["run", "-e", "GOOD_NAME=1", "x.wasm"]);
+        for bad in &["=val", "A\0B=c"] {
+            let err = RunOptions::from_iter_safe(&["run", "-e", bad, "x.wasm"]).unwrap_err();
+            assert!(
+                err.message.contains("invalid environment variable name"),
+                "{}",
+                err
+            );
+        }
+    }
+}
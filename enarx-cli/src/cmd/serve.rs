@@ -1,37 +1,59 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::cmd::SubCommand;
-use crate::util::ListenFds;
+use crate::util::{inventory, FdKind, ListenFd, ListenFds};
 
-use anyhow::{bail, Result};
-use log::{debug, info};
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::time::Duration;
-use tokio::net::UnixListener;
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::TlsAcceptor;
 
 use structopt::StructOpt;
 
-use futures_util::TryFutureExt;
-use tonic::transport::server::Connected;
+use futures_util::future::{try_join, BoxFuture, Future};
+use futures_util::stream::{select_all, BoxStream, Stream, StreamExt};
+use prost::Message;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tonic::transport::NamedService;
 use tonic::{transport::Server, Request, Response, Status};
 
+use enarx_config::{parse_size, ClientAuthMode, TLSOptions};
+use enarx_proto::display::SafeDisplay;
 use enarx_proto::v0;
+use enarx_proto::validate::{Limits, Reason, Validate, ValidationError};
 use v0::keepldr_server::{Keepldr, KeepldrServer};
 use v0::{BackendInfo, InfoRequest, KeepldrInfo};
 
 #[cfg(unix)]
-use std::os::unix::{io::AsRawFd, io::FromRawFd};
+use std::os::unix::{io::AsRawFd, io::FromRawFd, io::OwnedFd, io::RawFd};
 
 type TonicResult<T> = std::result::Result<Response<T>, Status>;
 
+/// WASI gives the workload fds 0-2 for stdio, so its sockets start at 3
+const WORKLOAD_FDS_START: u32 = 3;
+
 #[derive(Debug, Default)]
-struct KeepldrState {}
+struct KeepldrState {
+    limits: Limits,
+    advertise_inventory: bool,
+    listen_fds: Vec<v0::boot_request::ListenFd>,
+}
 
 #[tonic::async_trait]
 impl Keepldr for KeepldrState {
-    async fn info(&self, _req: Request<InfoRequest>) -> TonicResult<KeepldrInfo> {
+    async fn info(&self, req: Request<InfoRequest>) -> TonicResult<KeepldrInfo> {
+        // Only clients with a trusted certificate get to see the inventory
+        let show_inventory = self.advertise_inventory && peer_certificates(&req).is_some();
         let keepldrinfo = KeepldrInfo {
             name: "enarx serve".to_string(),
             version: env!("CARGO_PKG_VERSION").to_string(),
@@ -41,16 +63,27 @@ impl Keepldr for KeepldrState {
                 kvm: None,
                 sev: None,
             }),
+            inventory: show_inventory.then(inventory::probe),
         };
         Ok(Response::new(keepldrinfo))
     }
 
     async fn boot(&self, request: Request<v0::BootRequest>) -> TonicResult<v0::Result> {
-        let boot = request.get_ref();
+        match peer_certificates(&request) {
+            Some(chain) => info!("boot request from {}", cert_fingerprint(&chain[0])),
+            None => info!("boot request from a client with no certificate"),
+        }
+        let mut boot = request.into_inner();
+        debug!("boot request: {}", boot.safe_display());
+        if !boot.listen_fds.is_empty() {
+            return Err(ValidationError::new("listen_fds", Reason::HostOnly).into());
+        }
+        boot.validate(&self.limits)?;
+        boot.listen_fds = self.listen_fds.clone();
 
         let result = v0::Result {
             code: v0::Code::Unknown as i32,
-            message: format!("shim: {:?} exec: {:?}", boot.shim, boot.exec),
+            message: boot.safe_display().to_string(),
             details: vec![],
         };
 
@@ -65,21 +98,68 @@ pub struct ServeOptions {
     #[structopt(long)]
     pub systemd_socket_accept: bool,
 
+    /// Listen on all sockets passed from a systemd socket unit with "Accept=no"
+    #[structopt(long, conflicts_with = "systemd-socket-accept")]
+    pub systemd_socket_listen: bool,
+
     /// Idle connection timeout time, in milliseconds (0=forever)
     #[structopt(long, default_value = "5000")]
     pub idle_timeout: u64,
 
+    /// Maximum size of any single blob in a BootRequest, e.g. `64MiB`
+    #[structopt(
+        long,
+        value_name = "SIZE",
+        default_value = "256MiB",
+        parse(try_from_str = parse_blob_size)
+    )]
+    pub max_blob_size: usize,
+
+    /// Handle a BootRequest from FILE (see `enarx run --emit-request`) and exit
+    #[structopt(long, value_name = "FILE", parse(from_os_str))]
+    pub replay_request: Option<PathBuf>,
+
+    /// Check the configuration and exit without binding or serving anything
+    #[structopt(long)]
+    pub config_check: bool,
+
+    /// Include the host's OS and hardware inventory in Info() replies to
+    /// clients with a trusted certificate; needs --cert and --client-auth
+    #[structopt(long)]
+    pub advertise_inventory: bool,
+
+    /// Hand the systemd-passed listening socket named NAME (its
+    /// FileDescriptorName) to workloads instead of serving on it. Workloads
+    /// get these as fd 3, 4, ... in the order they're given here.
+    #[structopt(
+        long,
+        value_name = "NAME",
+        number_of_values = 1,
+        requires = "systemd-socket-listen"
+    )]
+    pub workload_listen_fd: Vec<String>,
+
+    /// Certificate and key to serve TLS with, and how to check client
+    /// certificates. If --cert is given, every TCP listener speaks TLS; unix
+    /// sockets are always plaintext.
+    #[structopt(flatten)]
+    pub tls: TLSOptions,
+
     /// Socket path to listen on
-    #[structopt(required_unless = "systemd-socket-accept")]
+    #[structopt(required_unless_one = &["systemd-socket-accept", "systemd-socket-listen", "replay-request"])]
     pub socket_path: Option<PathBuf>,
 }
 
+fn parse_blob_size(s: &str) -> Result<usize> {
+    Ok(usize::try_from(parse_size(s)?)?)
+}
+
 pub struct TonicUnixStream(pub tokio::net::UnixStream);
 
 impl FromRawFd for TonicUnixStream {
     unsafe fn from_raw_fd(fd: std::os::unix::prelude::RawFd) -> Self {
         let std = std::os::unix::net::UnixStream::from_raw_fd(fd);
-        Self(tokio::net::UnixStream::from_std(std).unwrap())
+        Self::from_std(std).unwrap()
     }
 }
 
@@ -89,6 +169,7 @@ impl AsRawFd for TonicUnixStream {
     }
 }
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 impl Connected for TonicUnixStream {
     type ConnectInfo = (
@@ -104,11 +185,10 @@ impl Connected for TonicUnixStream {
 }
 
 impl TonicUnixStream {
-    fn local_addr(&self) -> std::io::Result<tokio::net::unix::SocketAddr> {
-        self.0.local_addr()
-    }
-
+    /// Must be called from inside a tokio runtime. Sockets inherited from
+    /// systemd are usually blocking, so this makes `std` non-blocking first.
     fn from_std(std: std::os::unix::net::UnixStream) -> std::io::Result<Self> {
+        std.set_nonblocking(true)?;
         tokio::net::UnixStream::from_std(std).map(Self)
     }
 }
@@ -149,6 +229,353 @@ impl AsyncWrite for TonicUnixStream {
     }
 }
 
+/// A connection accepted from any of our listeners
+pub enum TonicStream {
+    Unix(TonicUnixStream),
+    Tcp(TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+// Only the TLS peer certificates are read by the handlers so far, but tonic
+// needs all of it to be Clone
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum TonicStreamConnectInfo {
+    Unix(<TonicUnixStream as Connected>::ConnectInfo),
+    Tcp(TcpConnectInfo),
+    Tls {
+        tcp: TcpConnectInfo,
+        /// The client's certificate chain, leaf first, if it sent one
+        peer_certificates: Option<Arc<[CertificateDer<'static>]>>,
+    },
+}
+
+impl Connected for TonicStream {
+    type ConnectInfo = TonicStreamConnectInfo;
+    fn connect_info(&self) -> Self::ConnectInfo {
+        match self {
+            Self::Unix(s) => TonicStreamConnectInfo::Unix(s.connect_info()),
+            Self::Tcp(s) => TonicStreamConnectInfo::Tcp(s.connect_info()),
+            Self::Tls(s) => {
+                let (tcp, conn) = s.get_ref();
+                TonicStreamConnectInfo::Tls {
+                    tcp: tcp.connect_info(),
+                    peer_certificates: conn
+                        .peer_certificates()
+                        .map(|chain| chain.iter().map(|c| c.clone().into_owned()).collect()),
+                }
+            }
+        }
+    }
+}
+
+/// The certificate chain the client authenticated with, if it connected
+/// over TLS and sent one
+fn peer_certificates<T>(request: &Request<T>) -> Option<&[CertificateDer<'static>]> {
+    match request.extensions().get::<TonicStreamConnectInfo>()? {
+        TonicStreamConnectInfo::Tls {
+            peer_certificates, ..
+        } => peer_certificates.as_deref(),
+        _ => None,
+    }
+}
+
+/// The SHA-256 fingerprint of a certificate, as hex
+fn cert_fingerprint(cert: &CertificateDer<'_>) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert.as_ref());
+    let hex: Vec<String> = digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("sha256:{}", hex.concat())
+}
+
+impl AsyncRead for TonicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TonicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        match self.get_mut() {
+            Self::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        match self.get_mut() {
+            Self::Unix(s) => Pin::new(s).poll_flush(cx),
+            Self::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Self::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        match self.get_mut() {
+            Self::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The single connection we get from systemd with `Accept=yes`. Dropping it
+/// fires `closed`, which is how we know the server is done with it.
+struct OnlyConnection {
+    stream: TonicStream,
+    _closed: tokio::sync::oneshot::Sender<()>,
+}
+
+impl Connected for OnlyConnection {
+    type ConnectInfo = TonicStreamConnectInfo;
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.stream.connect_info()
+    }
+}
+
+impl AsyncRead for OnlyConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for OnlyConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// A listening socket that we can accept connections from
+#[derive(Debug)]
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    /// Adopt an inherited listening socket. Must be called from inside a
+    /// tokio runtime.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open fd that nothing else owns; the returned Listener
+    /// takes ownership of it. If this fails, the fd is closed.
+    unsafe fn from_raw_fd(raw_fd: RawFd, kind: FdKind) -> Result<Self> {
+        let fd = OwnedFd::from_raw_fd(raw_fd);
+        match kind {
+            FdKind::Unix { listening: true } => {
+                let std = std::os::unix::net::UnixListener::from(fd);
+                std.set_nonblocking(true)?;
+                Ok(Self::Unix(UnixListener::from_std(std)?))
+            }
+            FdKind::Tcp { listening: true } => {
+                let std = std::net::TcpListener::from(fd);
+                std.set_nonblocking(true)?;
+                Ok(Self::Tcp(TcpListener::from_std(std)?))
+            }
+            kind => bail!("fd {} is not a listening socket ({})", raw_fd, kind),
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<TonicStream> {
+        match self {
+            Self::Unix(l) => {
+                let (sock, _addr) = l.accept().await?;
+                Ok(TonicStream::Unix(TonicUnixStream(sock)))
+            }
+            Self::Tcp(l) => {
+                let (sock, _addr) = l.accept().await?;
+                Ok(TonicStream::Tcp(sock))
+            }
+        }
+    }
+
+    /// A Stream that yields each connection accepted on this listener. If
+    /// `tls` is given, TCP connections are wrapped in TLS.
+    fn incoming(
+        self,
+        tls: Option<TlsAcceptor>,
+    ) -> BoxStream<'static, std::io::Result<TonicStream>> {
+        match (self, tls) {
+            (Self::Tcp(listener), Some(acceptor)) => tls_incoming(listener, acceptor),
+            (listener, _) => async_stream::stream! {
+                loop {
+                    let conn = listener.accept().await;
+                    match &conn {
+                        Ok(_) => debug!("new connection on {:?}", listener),
+                        Err(e) => warn!("accepting a connection on {:?} failed: {}", listener, e),
+                    }
+                    yield conn;
+                }
+            }
+            .boxed(),
+        }
+    }
+}
+
+/// How long a client gets to finish the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A Stream of TLS connections accepted on `listener`. Each handshake runs
+/// in its own task, so a slow client can't hold up the others, and clients
+/// that fail the handshake are dropped.
+fn tls_incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> BoxStream<'static, std::io::Result<TonicStream>> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while !tx.is_closed() {
+            let (sock, addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("accepting a connection on {:?} failed: {}", listener, e);
+                    if tx.send(Err(e)).is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let (acceptor, tx) = (acceptor.clone(), tx.clone());
+            tokio::spawn(async move {
+                let handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(sock));
+                match handshake.await {
+                    Ok(Ok(tls)) => {
+                        debug!("new TLS connection from {}", addr);
+                        tx.send(Ok(TonicStream::Tls(Box::new(tls)))).ok();
+                    }
+                    Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", addr, e),
+                    Err(_) => warn!("TLS handshake with {} timed out", addr),
+                }
+            });
+        }
+    });
+    async_stream::stream! {
+        while let Some(conn) = rx.recv().await {
+            yield conn;
+        }
+    }
+    .boxed()
+}
+
+/// Merge the incoming connections from all the given listeners into one
+/// Stream, wrapping TCP connections in TLS if `tls` is given
+fn merged_incoming(
+    listeners: Vec<Listener>,
+    tls: Option<TlsAcceptor>,
+) -> impl Stream<Item = std::io::Result<TonicStream>> {
+    select_all(listeners.into_iter().map(|l| l.incoming(tls.clone())))
+}
+
+/// Shared flag saying whether the server has finished starting up
+#[derive(Clone, Debug, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn set_ready(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// RPCs that are safe to handle before the server is ready
+const UNGATED_METHODS: &[&str] = &["Info"];
+
+/// Wraps a gRPC service and rejects calls with `Status::unavailable` until
+/// the server is ready, except for the methods in UNGATED_METHODS.
+///
+/// tonic's Interceptors can't see which method is being called, so this is
+/// a plain tower Service instead.
+#[derive(Clone, Debug)]
+pub struct ReadinessGate<S> {
+    inner: S,
+    ready: Readiness,
+}
+
+impl<S> ReadinessGate<S> {
+    pub fn new(inner: S, ready: Readiness) -> Self {
+        Self { inner, ready }
+    }
+}
+
+impl<S: NamedService> NamedService for ReadinessGate<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> tower::Service<http::Request<B>> for ReadinessGate<S>
+where
+    S: tower::Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, std::result::Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::result::Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let method = req.uri().path().rsplit('/').next().unwrap_or_default();
+        if !self.ready.is_ready() && !UNGATED_METHODS.contains(&method) {
+            debug!("rejecting {} call: server not ready", method);
+            let response = Status::unavailable("server starting").to_http();
+            return Box::pin(async move { Ok(response) });
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
+
 impl ServeOptions {
     /// Handle an already-accepted connection on an already-opened socket
     fn serve(&self, sock: UnixStream) -> Result<()> {
@@ -157,38 +584,180 @@ impl ServeOptions {
             .build()?;
 
         rt.block_on(async {
-            Server::builder()
-                .timeout(Duration::from_millis(self.idle_timeout))
-                .add_service(KeepldrServer::new(KeepldrState::default()))
-                .serve_with_incoming(
-                    async_stream::stream! { yield TonicUnixStream::from_std(sock) },
-                )
-                .await
-        })?;
-        Ok(())
+            let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+            let stream = TonicUnixStream::from_std(sock).map(TonicStream::Unix)?;
+            let conn = OnlyConnection {
+                stream,
+                _closed: closed_tx,
+            };
+            // The server stops as soon as `incoming` ends, so hold it open
+            // until the server has finished with the connection.
+            let incoming = async_stream::stream! {
+                yield Ok(conn);
+                closed_rx.await.ok();
+            };
+            self.serve_gated(incoming, self.start_up()).await
+        })
+    }
+
+    fn keepldr_state(&self) -> KeepldrState {
+        KeepldrState {
+            limits: Limits {
+                max_blob_size: self.max_blob_size,
+            },
+            advertise_inventory: self.advertise_inventory,
+            listen_fds: self.workload_listen_fds(),
+        }
+    }
+
+    /// Where each --workload-listen-fd socket goes in the workload's fd table
+    fn workload_listen_fds(&self) -> Vec<v0::boot_request::ListenFd> {
+        (self.workload_listen_fd.iter().zip(WORKLOAD_FDS_START..))
+            .map(|(name, fd)| v0::boot_request::ListenFd {
+                name: name.clone(),
+                fd,
+            })
+            .collect()
+    }
+
+    /// Split the systemd-passed fds into the ones we serve on and the ones
+    /// named by --workload-listen-fd, in that order. Each name has to match
+    /// exactly one listening socket.
+    fn split_workload_fds<'a>(
+        &self,
+        inherited: impl IntoIterator<Item = ListenFd<'a>>,
+    ) -> Result<(Vec<ListenFd<'a>>, Vec<ListenFd<'a>>)> {
+        let (workload, ours): (Vec<_>, Vec<_>) = (inherited.into_iter())
+            .partition(|lfd| self.workload_listen_fd.iter().any(|name| name == lfd.name));
+        for name in &self.workload_listen_fd {
+            let mut matches = workload.iter().filter(|lfd| lfd.name == name);
+            match (matches.next(), matches.next()) {
+                (None, _) => bail!(
+                    "--workload-listen-fd {:?}: systemd passed no such socket",
+                    name
+                ),
+                (Some(_), Some(_)) => bail!(
+                    "--workload-listen-fd {:?}: systemd passed more than one socket by that name",
+                    name
+                ),
+                (Some(lfd), None) if !lfd.kind.is_listening() => bail!(
+                    "{:?} (fd {}) is not a listening socket ({})",
+                    lfd.name,
+                    lfd.fd,
+                    lfd.kind
+                ),
+                (Some(_), None) => {}
+            }
+        }
+        if ours.is_empty() {
+            bail!("--workload-listen-fd took every systemd-passed socket; none left to serve on");
+        }
+        Ok((ours, workload))
+    }
+
+    /// Do any setup that needs to happen before we can handle boots. This
+    /// runs once the sockets are bound and being served, so Info calls get
+    /// answered in the meantime.
+    async fn start_up(&self) -> Result<()> {
+        // FUTURE: probe backends, tell systemd we're READY, etc.
+        self.check_serve_config()
     }
 
     /// Listen for & handle connections on the given socket
     #[tokio::main]
     async fn listen(&self, socket_path: &Path) -> Result<()> {
-        // Build an incoming connection Stream that binds to the socket and
-        // yields a new TonicUnixStream for each accepted connection.
-        let incoming = {
-            debug!("binding to socket {:?}", socket_path);
-            let sock = UnixListener::bind(socket_path)?;
-            async_stream::stream! {
-                while let conn = sock.accept().map_ok(|(sock, _addr)| TonicUnixStream(sock)).await {
-                    debug!("new connection on {:?}", socket_path);
-                    yield conn;
-                }
-            }
+        debug!("binding to socket {:?}", socket_path);
+        let sock = UnixListener::bind(socket_path)?;
+        self.serve_listeners(vec![Listener::Unix(sock)]).await
+    }
+
+    /// Listen for & handle connections on all the sockets systemd gave us
+    #[tokio::main]
+    async fn listen_systemd(&self) -> Result<()> {
+        let listen_fds = ListenFds::take_from_env()?;
+        debug!("got fds: {:?}", listen_fds);
+        let (ours, workload) = self.split_workload_fds(listen_fds.iter_detailed())?;
+        // FUTURE: pass these to the keeps we boot. Until then, just keep
+        // them open so systemd doesn't see the sockets as abandoned.
+        let _workload_fds: Vec<OwnedFd> = (workload.iter())
+            .map(|lfd| unsafe { OwnedFd::from_raw_fd(lfd.fd) })
+            .collect();
+        let mut listeners = Vec::with_capacity(listen_fds.count());
+        for lfd in ours {
+            debug!("adopting fd {} ({:?}): {}", lfd.fd, lfd.name, lfd.kind);
+            let listener = unsafe { Listener::from_raw_fd(lfd.fd, lfd.kind) }
+                .map_err(|e| anyhow::anyhow!("can't listen on {:?}: {}", lfd.name, e))?;
+            listeners.push(listener);
+        }
+        self.serve_listeners(listeners).await
+    }
+
+    /// Handle connections from all the given listeners until they're closed
+    async fn serve_listeners(&self, listeners: Vec<Listener>) -> Result<()> {
+        let tls = self.tls_acceptor()?;
+        self.serve_gated(merged_incoming(listeners, tls), self.start_up())
+            .await
+    }
+
+    /// Handle incoming connections until the stream ends, rejecting boots
+    /// until `start_up` has finished. If it fails, stop serving and return
+    /// its error.
+    async fn serve_gated<S, IO>(
+        &self,
+        incoming: S,
+        start_up: impl Future<Output = Result<()>>,
+    ) -> Result<()>
+    where
+        S: Stream<Item = std::io::Result<IO>>,
+        IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+        IO::ConnectInfo: Clone + Send + Sync + 'static,
+    {
+        let ready = Readiness::default();
+        let server = self.serve_incoming(incoming, ready.clone());
+        let start_up = async {
+            start_up.await.context("start-up failed")?;
+            ready.set_ready();
+            info!("ready to handle boot requests");
+            Ok(())
         };
+        try_join(server, start_up).await?;
+        Ok(())
+    }
 
+    /// The acceptor to wrap TCP connections in, if --cert was given
+    fn tls_acceptor(&self) -> Result<Option<TlsAcceptor>> {
+        let tls = &self.tls;
+        let serve_tls = tls.cert.is_some() || tls.cert_pem.is_some();
+        if self.advertise_inventory && (!serve_tls || tls.client_auth == ClientAuthMode::Off) {
+            bail!(
+                "--advertise-inventory needs --cert, --key and --client-auth, \
+                so that only clients with a trusted certificate see the inventory"
+            );
+        }
+        if !serve_tls {
+            if tls.key.is_some() || tls.client_auth != ClientAuthMode::Off {
+                bail!("--key and --client-auth need --cert");
+            }
+            return Ok(None);
+        }
+        let mut config = tls.server_config().context("can't set up TLS")?;
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    /// Handle incoming connections until the stream ends
+    async fn serve_incoming<S, IO>(&self, incoming: S, ready: Readiness) -> Result<()>
+    where
+        S: Stream<Item = std::io::Result<IO>>,
+        IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+        IO::ConnectInfo: Clone + Send + Sync + 'static,
+    {
         // Fire up a tonic Server that implements the Keepldr service and
         // asynchronously handles incoming connections
+        let keepldr = KeepldrServer::new(self.keepldr_state());
         Server::builder()
             .timeout(Duration::from_millis(self.idle_timeout))
-            .add_service(KeepldrServer::new(KeepldrState::default()))
+            .add_service(ReadinessGate::new(keepldr, ready))
             .serve_with_incoming(incoming)
             .await?;
 
@@ -196,13 +765,66 @@ impl ServeOptions {
         Ok(())
     }
 
+    /// Handle a length-delimited BootRequest read from a file
+    #[tokio::main]
+    async fn replay(&self, path: &Path) -> Result<v0::Result> {
+        let bytes = std::fs::read(path).with_context(|| format!("could not read {:?}", path))?;
+        let boot = v0::BootRequest::decode_length_delimited(bytes.as_slice())
+            .with_context(|| format!("{:?} does not contain a valid BootRequest", path))?;
+        let response = self.keepldr_state().boot(Request::new(boot)).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Check the parts of the config that don't depend on where the sockets
+    /// come from, so they can be checked again once they're bound
+    fn check_serve_config(&self) -> Result<()> {
+        self.tls_acceptor()?;
+        for (i, name) in self.workload_listen_fd.iter().enumerate() {
+            if self.workload_listen_fd[..i].contains(name) {
+                bail!("--workload-listen-fd {:?} given more than once", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that we could actually serve with the given options, without
+    /// binding or accepting anything.
+    fn check_config(&self) -> Result<()> {
+        self.check_serve_config()?;
+        if self.systemd_socket_accept {
+            let listen_fds = ListenFds::from_env().context("no sockets passed from systemd")?;
+            if listen_fds.get_connection_fd().is_none() {
+                bail!("can't find fd for incoming socket connection");
+            }
+        } else if self.systemd_socket_listen {
+            let listen_fds = ListenFds::from_env().context("no sockets passed from systemd")?;
+            let (ours, _workload) = self.split_workload_fds(listen_fds.iter_detailed())?;
+            for lfd in ours {
+                if !lfd.kind.is_listening() {
+                    bail!(
+                        "{:?} (fd {}) is not a listening socket ({})",
+                        lfd.name,
+                        lfd.fd,
+                        lfd.kind
+                    );
+                }
+            }
+        }
+        if let Some(ref socket_path) = self.socket_path {
+            if !self.systemd_socket_accept {
+                check_socket_path(socket_path)?;
+            }
+        }
+        Ok(())
+    }
+
     fn accept_from_systemd(&self) -> Result<UnixStream> {
         // Get systemd socket info
         let listen_fds = ListenFds::take_from_env()?;
         debug!("got fds: {:?}", listen_fds);
         let sock = match listen_fds.get_connection_fd() {
             None => bail!("can't find fd for incoming socket connection"),
-            Some(fd) => unsafe { UnixStream::from_raw_fd(fd) },
+            Some(fd) => unsafe { connection_from_raw_fd(fd)? },
         };
         debug!(
             "fd {} local_addr {:?}",
@@ -226,14 +848,73 @@ impl ServeOptions {
     }
 }
 
+/// Wrap a systemd-passed connection fd, after checking that it's actually
+/// an open, connected unix stream socket.
+///
+/// # Safety
+///
+/// If `fd` is valid, the returned UnixStream takes ownership of it.
+unsafe fn connection_from_raw_fd(fd: RawFd) -> Result<UnixStream> {
+    match FdKind::of(fd) {
+        FdKind::Unix { listening: false } => Ok(UnixStream::from_raw_fd(fd)),
+        // With Accept=no, systemd hands us the listening socket itself
+        FdKind::Unix { listening: true } => bail!(
+            "systemd passed a listening socket (fd {}) where a connection was expected; \
+            set Accept=yes in the socket unit, or use --systemd-socket-listen for Accept=no",
+            fd
+        ),
+        kind => bail!("systemd passed an invalid connection fd {} ({})", fd, kind),
+    }
+}
+
+/// Check that we'd be able to create a new unix socket at the given path
+fn check_socket_path(socket_path: &Path) -> Result<()> {
+    if socket_path.exists() {
+        bail!("socket path {:?} already exists", socket_path);
+    }
+    let dir = match socket_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !dir.is_dir() {
+        bail!("socket directory {:?} does not exist", dir);
+    }
+    let cdir = CString::new(dir.as_os_str().as_bytes())?;
+    if unsafe { libc::access(cdir.as_ptr(), libc::W_OK | libc::X_OK) } != 0 {
+        bail!(
+            "socket directory {:?} is not writable: {}",
+            dir,
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
 impl SubCommand for ServeOptions {
     fn execute(self) -> Result<()> {
+        // FUTURE: skip this if we're given a user/group to drop privileges to
+        if unsafe { libc::geteuid() } == 0 {
+            warn!("running as root; enarx serve has no way to drop privileges yet");
+        }
+        if self.config_check {
+            self.check_config()?;
+            info!("configuration OK");
+            return Ok(());
+        }
+        if let Some(ref path) = self.replay_request {
+            let result = self.replay(path)?;
+            println!("{}", result.safe_display());
+            return Ok(());
+        }
         if self.systemd_socket_accept {
             info!("looking for a systemd-passed socket");
             match self.accept_from_systemd() {
                 Err(e) => bail!("Failed to get socket from systemd: {}", e),
                 Ok(sock) => self.serve(sock),
             }
+        } else if self.systemd_socket_listen {
+            info!("looking for systemd-passed listening sockets");
+            self.listen_systemd()
         } else {
             info!("looking for socket path to listen on");
             match &self.socket_path {
@@ -243,3 +924,523 @@ impl SubCommand for ServeOptions {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::RunOptions;
+    use enarx_proto::v0::keepldr_client::KeepldrClient;
+    use serial_test::serial;
+    use std::os::unix::io::IntoRawFd;
+    use tonic::transport::{Endpoint, Uri};
+    use tower::service_fn;
+
+    fn adopt(fd: RawFd) -> Listener {
+        unsafe { Listener::from_raw_fd(fd, FdKind::of(fd)) }.unwrap()
+    }
+
+    #[tokio::test]
+    async fn serve_mixed_listeners() {
+        let dir = tempfile::tempdir().unwrap();
+        let sock_path = dir.path().join("enarx.sock");
+        let unix_fd = std::os::unix::net::UnixListener::bind(&sock_path)
+            .unwrap()
+            .into_raw_fd();
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let listeners = vec![adopt(unix_fd), adopt(tcp.into_raw_fd())];
+
+        let opts = ServeOptions {
+            systemd_socket_accept: false,
+            systemd_socket_listen: true,
+            idle_timeout: 5000,
+            max_blob_size: 1024,
+            replay_request: None,
+            config_check: false,
+            advertise_inventory: false,
+            workload_listen_fd: vec![],
+            tls: TLSOptions::default(),
+            socket_path: None,
+        };
+        tokio::spawn(async move { opts.serve_listeners(listeners).await });
+
+        let channel = Endpoint::from_static("http://enarx.dev")
+            .connect_with_connector(service_fn(move |_: Uri| {
+                tokio::net::UnixStream::connect(sock_path.clone())
+            }))
+            .await
+            .unwrap();
+        let mut unix_client = KeepldrClient::new(channel);
+        let unix_info = unix_client.info(InfoRequest {}).await.unwrap();
+        assert_eq!(unix_info.get_ref().name, "enarx serve");
+        assert_eq!(unix_info.get_ref().inventory, None);
+
+        let mut tcp_client = KeepldrClient::connect(format!("http://{}", tcp_addr))
+            .await
+            .unwrap();
+        let tcp_info = tcp_client.info(InfoRequest {}).await.unwrap();
+        assert_eq!(tcp_info.get_ref().name, "enarx serve");
+    }
+
+    #[tokio::test]
+    async fn boot_rejected_until_ready() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let listeners = vec![adopt(tcp.into_raw_fd())];
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel::<()>();
+
+        let opts = ServeOptions::from_iter(&["serve", "--systemd-socket-listen"]);
+        tokio::spawn(async move {
+            let start_up = async {
+                started_rx.await.ok();
+                Ok(())
+            };
+            opts.serve_gated(merged_incoming(listeners, None), start_up)
+                .await
+        });
+
+        let mut client = KeepldrClient::connect(format!("http://{}", tcp_addr))
+            .await
+            .unwrap();
+        let item = || {
+            Some(v0::boot_request::BootItem {
+                from: Some(v0::boot_request::boot_item::From::Blob(vec![0; 4])),
+            })
+        };
+        let boot = || v0::BootRequest {
+            shim: item(),
+            exec: item(),
+            work: None,
+            listen_fds: vec![],
+        };
+
+        let err = client.boot(boot()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+        assert_eq!(err.message(), "server starting");
+        client.info(InfoRequest {}).await.unwrap();
+
+        // Once start-up has finished, boots go through
+        started_tx.send(()).unwrap();
+        let result = loop {
+            match client.boot(boot()).await {
+                Err(e) if e.code() == tonic::Code::Unavailable => tokio::task::yield_now().await,
+                result => break result.unwrap(),
+            }
+        };
+        assert_eq!(result.get_ref().code(), v0::Code::Unknown);
+    }
+
+    #[tokio::test]
+    async fn start_up_failure_stops_server() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listeners = vec![adopt(tcp.into_raw_fd())];
+        let opts = ServeOptions::from_iter(&["serve", "--systemd-socket-listen"]);
+        let start_up = async { Err(anyhow::anyhow!("no backends")) };
+        let err = opts
+            .serve_gated(merged_incoming(listeners, None), start_up)
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("start-up failed: no backends"));
+    }
+
+    /// Write a fresh self-signed cert for localhost and its key into `dir`
+    /// as `{name}.pem` and `{name}.key`.
+    fn write_cert(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let ck = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert = dir.join(format!("{}.pem", name));
+        let key = dir.join(format!("{}.key", name));
+        std::fs::write(&cert, ck.cert.pem()).unwrap();
+        std::fs::write(&key, ck.key_pair.serialize_pem()).unwrap();
+        (cert, key)
+    }
+
+    /// Connect to the TLS server at `addr` as localhost, using `tls` for
+    /// the trusted CAs and the client certificate (if any)
+    async fn tls_client(
+        addr: std::net::SocketAddr,
+        tls: TLSOptions,
+    ) -> std::result::Result<KeepldrClient<tonic::transport::Channel>, tonic::transport::Error>
+    {
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(tls.client_config().unwrap()));
+        let channel = Endpoint::from_static("http://localhost")
+            .connect_with_connector(service_fn(move |_: Uri| {
+                let connector = connector.clone();
+                async move {
+                    let tcp = TcpStream::connect(addr).await?;
+                    let name = tokio_rustls::rustls::pki_types::ServerName::try_from("localhost");
+                    connector.connect(name.unwrap(), tcp).await
+                }
+            }))
+            .await?;
+        Ok(KeepldrClient::new(channel))
+    }
+
+    /// Whether a client can make a call at all
+    async fn can_call(
+        client: std::result::Result<
+            KeepldrClient<tonic::transport::Channel>,
+            tonic::transport::Error,
+        >,
+    ) -> bool {
+        match client {
+            Ok(mut client) => client.info(InfoRequest {}).await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Start a server with `args` on a local TCP listener, and return its
+    /// address
+    fn spawn_tcp_server(args: &[&str]) -> std::net::SocketAddr {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listeners = vec![adopt(tcp.into_raw_fd())];
+        let args = [&["serve", "--systemd-socket-listen"][..], args].concat();
+        let opts = ServeOptions::from_iter(&args);
+        tokio::spawn(async move { opts.serve_listeners(listeners).await.unwrap() });
+        addr
+    }
+
+    #[tokio::test]
+    async fn serve_tls_client_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server_cert, server_key) = write_cert(dir.path(), "server");
+        let (client_cert, client_key) = write_cert(dir.path(), "client");
+        let (other_cert, other_key) = write_cert(dir.path(), "other");
+        let path = |p: &PathBuf| p.to_str().unwrap().to_string();
+        let addr = spawn_tcp_server(&[
+            "--cert",
+            &path(&server_cert),
+            "--key",
+            &path(&server_key),
+            "--cacert",
+            &path(&client_cert),
+            "--client-auth",
+            "required",
+        ]);
+
+        let client = |identity: Option<(&PathBuf, &PathBuf)>| TLSOptions {
+            cacert: Some(server_cert.clone()),
+            cert: identity.map(|i| i.0.clone()),
+            key: identity.map(|i| i.1.clone()),
+            ..Default::default()
+        };
+        let trusted = Some((&client_cert, &client_key));
+        let untrusted = Some((&other_cert, &other_key));
+        assert!(can_call(tls_client(addr, client(trusted)).await).await);
+        assert!(!can_call(tls_client(addr, client(untrusted)).await).await);
+        assert!(!can_call(tls_client(addr, client(None)).await).await);
+        let plaintext = KeepldrClient::connect(format!("http://{}", addr)).await;
+        assert!(!can_call(plaintext).await);
+        // A failed handshake doesn't stop the server
+        assert!(can_call(tls_client(addr, client(trusted)).await).await);
+    }
+
+    #[test]
+    fn tls_options_need_cert() {
+        let tls = |args: &[&str]| {
+            let args = [&["serve"][..], args, &["x.sock"]].concat();
+            ServeOptions::from_iter(&args)
+                .tls_acceptor()
+                .map(|a| a.is_some())
+        };
+        assert!(!tls(&[]).unwrap());
+        let err = tls(&["--client-auth", "required"]).unwrap_err();
+        assert!(err.to_string().contains("need --cert"), "{}", err);
+        let err = tls(&["--cert", "/nonexistent.pem", "--key", "/nonexistent.key"]).unwrap_err();
+        assert!(err.to_string().contains("can't set up TLS"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn advertise_inventory() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server_cert, server_key) = write_cert(dir.path(), "server");
+        let (client_cert, client_key) = write_cert(dir.path(), "client");
+        let path = |p: &PathBuf| p.to_str().unwrap().to_string();
+        let addr = spawn_tcp_server(&[
+            "--advertise-inventory",
+            "--cert",
+            &path(&server_cert),
+            "--key",
+            &path(&server_key),
+            "--cacert",
+            &path(&client_cert),
+            "--client-auth",
+            "optional",
+        ]);
+
+        let client = |identity: bool| TLSOptions {
+            cacert: Some(server_cert.clone()),
+            cert: identity.then(|| client_cert.clone()),
+            key: identity.then(|| client_key.clone()),
+            ..Default::default()
+        };
+        let info = |identity: bool| {
+            let tls = client(identity);
+            async move {
+                let mut client = tls_client(addr, tls).await.unwrap();
+                client.info(InfoRequest {}).await.unwrap().into_inner()
+            }
+        };
+        let inventory = info(true).await.inventory.unwrap();
+        assert!(inventory.kernel_version.is_some());
+        // Anonymous callers can still call Info(), but get no inventory
+        let anonymous = info(false).await;
+        assert_eq!(anonymous.name, "enarx serve");
+        assert_eq!(anonymous.inventory, None);
+
+        // Without client auth there'd be no way to tell callers apart
+        for args in &[
+            &["--advertise-inventory"][..],
+            &["--advertise-inventory", "--cert", "c.pem", "--key", "c.key"],
+        ] {
+            let args = [&["serve"][..], args, &["x.sock"]].concat();
+            let err = ServeOptions::from_iter(&args).tls_acceptor().err().unwrap();
+            assert!(err.to_string().contains("--client-auth"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn config_check_socket_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let sock_path = dir.path().join("enarx.sock");
+        let check = |path: &Path| {
+            ServeOptions::from_iter(&["serve", "--config-check", path.to_str().unwrap()]).execute()
+        };
+
+        check(&sock_path).unwrap();
+
+        let missing_dir = dir.path().join("nope").join("enarx.sock");
+        let err = check(&missing_dir).unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+
+        std::fs::write(&sock_path, b"").unwrap();
+        let err = check(&sock_path).unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+    }
+
+    #[test]
+    #[serial]
+    fn config_check_systemd_not_present() {
+        ListenFds::unset_env();
+        for flag in &["--systemd-socket-accept", "--systemd-socket-listen"] {
+            let opts = ServeOptions::from_iter(&["serve", "--config-check", flag]);
+            let err = opts.execute().unwrap_err();
+            assert!(err.to_string().contains("no sockets passed from systemd"));
+        }
+    }
+
+    #[test]
+    fn invalid_connection_fd() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        for fd in &[file.as_raw_fd(), RawFd::MAX] {
+            let err = unsafe { connection_from_raw_fd(*fd) }.unwrap_err();
+            assert!(err
+                .to_string()
+                .contains("systemd passed an invalid connection fd"));
+        }
+    }
+
+    #[test]
+    fn emit_and_replay_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let module_path = dir.path().join("module.wasm");
+        let request_path = dir.path().join("boot.req");
+        let module = b"\0asm\x01\0\0\0";
+        std::fs::write(&module_path, module).unwrap();
+        let (shim_path, exec_path) = (dir.path().join("shim"), dir.path().join("exec"));
+        std::fs::write(&shim_path, b"shim").unwrap();
+        std::fs::write(&exec_path, b"exec").unwrap();
+
+        let run_args = [
+            "run",
+            "--emit-request",
+            request_path.to_str().unwrap(),
+            "--shim",
+            shim_path.to_str().unwrap(),
+            "--exec",
+            exec_path.to_str().unwrap(),
+            module_path.to_str().unwrap(),
+        ];
+        RunOptions::from_iter(&run_args).execute().unwrap();
+
+        let bytes = std::fs::read(&request_path).unwrap();
+        let boot = v0::BootRequest::decode_length_delimited(bytes.as_slice()).unwrap();
+        let work = boot.work.unwrap().from.unwrap();
+        assert_eq!(
+            work,
+            v0::boot_request::boot_item::From::Blob(module.to_vec())
+        );
+
+        let serve_args = ["serve", "--replay-request", request_path.to_str().unwrap()];
+        let result = ServeOptions::from_iter(&serve_args)
+            .replay(&request_path)
+            .unwrap();
+        assert_eq!(result.code(), v0::Code::Unknown);
+        assert_eq!(
+            result.message,
+            format!(
+                "shim: blob (4 bytes), exec: blob (4 bytes), work: blob ({} bytes)",
+                module.len()
+            )
+        );
+    }
+
+    #[test]
+    fn listening_connection_fd() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = std::os::unix::net::UnixListener::bind(dir.path().join("sock")).unwrap();
+        let err = unsafe { connection_from_raw_fd(listener.as_raw_fd()) }.unwrap_err();
+        assert!(err.to_string().contains("set Accept=yes"));
+    }
+
+    #[test]
+    fn max_blob_size_units() {
+        let size = |args: &[&str]| {
+            let args = [&["serve", "--replay-request", "x"][..], args].concat();
+            ServeOptions::from_iter_safe(&args).map(|o| o.max_blob_size)
+        };
+        assert_eq!(size(&[]).unwrap(), 256 << 20);
+        assert_eq!(size(&["--max-blob-size", "4096"]).unwrap(), 4096);
+        assert_eq!(size(&["--max-blob-size", "1.5MiB"]).unwrap(), 3 << 19);
+        assert_eq!(size(&["--max-blob-size", "2 MB"]).unwrap(), 2_000_000);
+        let err = size(&["--max-blob-size", "1,024"]).unwrap_err();
+        assert!(err.message.contains("did you mean 1024?"), "{}", err);
+    }
+
+    #[test]
+    fn replay_invalid_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let request_path = dir.path().join("boot.req");
+        let boot = v0::BootRequest {
+            shim: Some(v0::boot_request::BootItem {
+                from: Some(v0::boot_request::boot_item::From::Blob(vec![0; 4])),
+            }),
+            exec: Some(v0::boot_request::BootItem {
+                from: Some(v0::boot_request::boot_item::From::Blob(vec![0; 17])),
+            }),
+            work: None,
+            listen_fds: vec![],
+        };
+        std::fs::write(&request_path, boot.encode_length_delimited_to_vec()).unwrap();
+
+        let path = request_path.to_str().unwrap();
+        let opts =
+            ServeOptions::from_iter(&["serve", "--max-blob-size", "16", "--replay-request", path]);
+        let err = opts.replay(&request_path).unwrap_err();
+        let status = err.downcast::<Status>().unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "exec.blob: too large (17 bytes, limit 16)"
+        );
+    }
+
+    #[tokio::test]
+    async fn workload_listen_fd_mapping() {
+        let args = [
+            "serve",
+            "--systemd-socket-listen",
+            "--workload-listen-fd",
+            "web",
+            "--workload-listen-fd",
+            "admin",
+        ];
+        let opts = ServeOptions::from_iter(&args);
+        let lfd = |fd, name, kind| ListenFd { fd, name, kind };
+        let listening = FdKind::Tcp { listening: true };
+        let inherited = [
+            lfd(3, "grpc", FdKind::Unix { listening: true }),
+            lfd(4, "admin", listening),
+            lfd(5, "web", listening),
+        ];
+        let (ours, workload) = opts.split_workload_fds(inherited.iter().copied()).unwrap();
+        assert_eq!(ours, [inherited[0]]);
+        assert_eq!(workload, [inherited[1], inherited[2]]);
+
+        // The workload's fds follow the order of --workload-listen-fd
+        let item = || {
+            Some(v0::boot_request::BootItem {
+                from: Some(v0::boot_request::boot_item::From::Blob(vec![0; 4])),
+            })
+        };
+        let boot = || v0::BootRequest {
+            shim: item(),
+            exec: item(),
+            work: None,
+            listen_fds: vec![],
+        };
+        let state = opts.keepldr_state();
+        let result = state.boot(Request::new(boot())).await.unwrap();
+        assert!(
+            (result.get_ref().message).ends_with(r#"listen_fds: "web" on fd 3, "admin" on fd 4"#),
+            "{}",
+            result.get_ref().message
+        );
+
+        // Clients don't get to pick
+        let mut request = boot();
+        request.listen_fds = state.listen_fds.clone();
+        let status = state.boot(Request::new(request)).await.unwrap_err();
+        assert_eq!(
+            status.message(),
+            "listen_fds: set by the host, not the client"
+        );
+
+        let err = |inherited: &[ListenFd<'_>]| {
+            let err = opts.split_workload_fds(inherited.iter().copied());
+            err.unwrap_err().to_string()
+        };
+        let missing = [inherited[0], inherited[2]];
+        assert!(err(&missing).contains("\"admin\": systemd passed no such socket"));
+        let connected = [
+            inherited[0],
+            lfd(4, "admin", FdKind::NotSocket),
+            inherited[2],
+        ];
+        assert_eq!(
+            err(&connected),
+            "\"admin\" (fd 4) is not a listening socket (not a socket)"
+        );
+        let twice = [
+            inherited[0],
+            inherited[1],
+            inherited[2],
+            lfd(6, "web", listening),
+        ];
+        assert!(err(&twice).contains("more than one socket"));
+        assert!(err(&inherited[1..]).contains("none left to serve on"));
+    }
+
+    #[test]
+    fn workload_listen_fd_args() {
+        let check = |args: &[&str]| {
+            let args = [&["serve", "--config-check"][..], args].concat();
+            ServeOptions::from_iter_safe(&args).map(|opts| opts.check_serve_config())
+        };
+        assert!(check(&["--workload-listen-fd", "web", "sock"]).is_err());
+        let dup = [
+            "--systemd-socket-listen",
+            "--workload-listen-fd",
+            "a",
+            "--workload-listen-fd",
+            "a",
+        ];
+        let err = check(&dup).unwrap().unwrap_err();
+        assert!(err.to_string().contains("\"a\" given more than once"));
+    }
+
+    #[tokio::test]
+    async fn adopt_non_listener() {
+        let (sock, _peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        let fd = sock.into_raw_fd();
+        // The Listener owns the fd even when it fails, and closes it
+        let err = unsafe { Listener::from_raw_fd(fd, FdKind::of(fd)) }.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "fd {} is not a listening socket (connected unix socket)",
+                fd
+            )
+        );
+    }
+}
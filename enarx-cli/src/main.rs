@@ -4,7 +4,7 @@
 pub mod cmd;
 mod util;
 
-use anyhow::{bail, Result};
+use anyhow::Result;
 use log::{debug, info};
 use structopt::{clap::AppSettings, StructOpt};
 
@@ -49,6 +49,8 @@ impl LogOpts {
 }
 
 /// Subcommands
+// There's only ever one of these, so it doesn't matter that Run is big
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt, Debug)]
 enum EnarxCommand {
     Run(RunOptions),
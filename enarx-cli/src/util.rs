@@ -1,5 +1,6 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod listenfds;
+pub mod inventory;
 
-pub use listenfds::{ListenFdError, ListenFds};
+pub use listenfds::{FdKind, ListenFd, ListenFds};
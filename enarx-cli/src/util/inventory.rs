@@ -0,0 +1,304 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Best-effort host inventory for `Info()` replies.
+//!
+//! Everything here is read from /proc and /sys without privileges. Files
+//! that are missing or unreadable just leave their fields unset; nothing in
+//! here returns an error.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use enarx_proto::v0::HostInventory;
+
+/// Take an inventory of this host
+pub fn probe() -> HostInventory {
+    probe_root(Path::new("/"))
+}
+
+/// Take an inventory of the host whose /proc and /sys are under `root`
+fn probe_root(root: &Path) -> HostInventory {
+    let read = |path: &str| std::fs::read_to_string(root.join(path)).ok();
+    let mut inv = HostInventory {
+        kernel_version: read("proc/sys/kernel/osrelease").map(|s| s.trim().to_string()),
+        ..Default::default()
+    };
+
+    if let Some(cpuinfo) = read("proc/cpuinfo") {
+        let cpu = parse_cpuinfo(&cpuinfo);
+        inv.cpu_model = cpu.model;
+        inv.cpu_count = Some(cpu.count).filter(|&n| n > 0);
+    }
+
+    if let Some(meminfo) = read("proc/meminfo") {
+        let mem = parse_meminfo(&meminfo);
+        inv.mem_total = mem.get("MemTotal").copied();
+        inv.mem_available = mem.get("MemAvailable").copied();
+        inv.hugepage_size = mem.get("Hugepagesize").copied();
+        inv.hugepages_total = mem.get("HugePages_Total").copied();
+        inv.hugepages_free = mem.get("HugePages_Free").copied();
+    }
+
+    // The directory exists whenever the kernel has IOMMU support built in;
+    // it only has entries if an IOMMU was actually found.
+    if let Ok(mut entries) = std::fs::read_dir(root.join("sys/class/iommu")) {
+        inv.iommu = Some(entries.next().is_some());
+    }
+
+    // Linux 6.0+ reports EPC per NUMA node
+    if let Ok(nodes) = std::fs::read_dir(root.join("sys/devices/system/node")) {
+        let sizes: Vec<u64> = nodes
+            .filter_map(|node| {
+                let path = node.ok()?.path().join("x86/sgx_total_bytes");
+                std::fs::read_to_string(path).ok()?.trim().parse().ok()
+            })
+            .collect();
+        if !sizes.is_empty() {
+            inv.sgx_epc_size = Some(sizes.iter().sum());
+        }
+    }
+
+    // The misc cgroup controller knows how many SEV ASIDs there are
+    if let Some(capacity) = read("sys/fs/cgroup/misc.capacity") {
+        for line in capacity.lines() {
+            let mut words = line.split_whitespace();
+            let (name, count) = (words.next(), words.next().and_then(|n| n.parse().ok()));
+            match name {
+                Some("sev") => inv.sev_asids = count,
+                Some("sev_es") => inv.sev_es_asids = count,
+                _ => {}
+            }
+        }
+    }
+
+    inv
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct CpuInfo {
+    model: Option<String>,
+    count: u32,
+}
+
+/// Keys that name the CPU model, in order of preference. x86 uses "model
+/// name", MIPS uses "cpu model", and POWER uses "cpu". arm64 has none.
+const CPU_MODEL_KEYS: &[&str] = &["model name", "cpu model", "cpu"];
+
+/// Parse /proc/cpuinfo: the CPU model name, if there is one, and how many
+/// CPUs are listed
+fn parse_cpuinfo(text: &str) -> CpuInfo {
+    let mut info = CpuInfo::default();
+    let mut models = BTreeMap::new();
+    for line in text.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        // s390x lists its CPUs as "processor 0: version = ..."
+        let numbered = key
+            .strip_prefix("processor ")
+            .is_some_and(|n| n.chars().all(|c| c.is_ascii_digit()));
+        if key == "processor" || numbered {
+            info.count += 1;
+        } else if let Some(rank) = CPU_MODEL_KEYS.iter().position(|k| *k == key) {
+            if !value.is_empty() {
+                models.entry(rank).or_insert(value);
+            }
+        }
+    }
+    info.model = models.values().next().map(|m| m.to_string());
+    info
+}
+
+/// Parse /proc/meminfo into a map of field name to value. Sizes given in
+/// kB are converted to bytes; counts are left as they are.
+fn parse_meminfo(text: &str) -> BTreeMap<&str, u64> {
+    let mut fields = BTreeMap::new();
+    for line in text.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let mut words = value.split_whitespace();
+        let number: u64 = match words.next().and_then(|n| n.parse().ok()) {
+            Some(n) => n,
+            None => continue,
+        };
+        let value = match words.next() {
+            None => Some(number),
+            Some("kB") => number.checked_mul(1024),
+            Some(_) => None,
+        };
+        if let Some(value) = value {
+            fields.insert(key.trim(), value);
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const GIB: u64 = 1 << 30;
+
+    #[test]
+    fn cpuinfo() {
+        let cases: &[(&str, Option<&str>, u32)] = &[
+            ("", None, 0),
+            (
+                "processor\t: 0\nvendor_id\t: GenuineIntel\ncpu family\t: 6\n\
+                 model name\t: Intel(R) Xeon(R) Gold 6330\ncpu MHz\t\t: 2000.000\n\n\
+                 processor\t: 1\nmodel name\t: Intel(R) Xeon(R) Gold 6330\n",
+                Some("Intel(R) Xeon(R) Gold 6330"),
+                2,
+            ),
+            // arm64 doesn't name the CPU
+            (
+                "processor\t: 0\nBogoMIPS\t: 50.00\nCPU implementer\t: 0x41\n\n\
+                 processor\t: 1\nBogoMIPS\t: 50.00\n",
+                None,
+                2,
+            ),
+            (
+                "processor\t: 0\ncpu\t\t: POWER9 (raw), altivec supported\n",
+                Some("POWER9 (raw), altivec supported"),
+                1,
+            ),
+            (
+                "system type\t: MT7621\nprocessor\t: 0\ncpu model\t: MIPS 1004Kc V2.15\n",
+                Some("MIPS 1004Kc V2.15"),
+                1,
+            ),
+            (
+                "vendor_id       : IBM/S390\n# processors    : 2\n\
+                 processor 0: version = FF,  identification = 0123\n\
+                 processor 1: version = FF,  identification = 0124\n",
+                None,
+                2,
+            ),
+            // "model name" wins even if another key comes first
+            (
+                "processor : 0\ncpu : something\nmodel name : better\n",
+                Some("better"),
+                1,
+            ),
+            ("no colons here\n:\nprocessor\n", None, 0),
+        ];
+        for (text, model, count) in cases {
+            let expected = CpuInfo {
+                model: model.map(String::from),
+                count: *count,
+            };
+            assert_eq!(parse_cpuinfo(text), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn meminfo() {
+        let cases: &[(&str, &[(&str, u64)])] = &[
+            ("", &[]),
+            (
+                "MemTotal:       16384 kB\nMemFree:  1024 kB\nHugePages_Total:       4\n",
+                &[
+                    ("MemTotal", 16 << 20),
+                    ("MemFree", 1 << 20),
+                    ("HugePages_Total", 4),
+                ],
+            ),
+            // Unknown units, garbage values and missing colons are skipped
+            (
+                "MemTotal: 1 MB\nMemFree: lots kB\nCached 12 kB\nBuffers:\nShmem: 2 kB\n",
+                &[("Shmem", 2048)],
+            ),
+            ("Huge: 18446744073709551615 kB\n", &[]),
+        ];
+        for (text, fields) in cases {
+            let expected: BTreeMap<&str, u64> = fields.iter().copied().collect();
+            assert_eq!(parse_meminfo(text), expected, "{:?}", text);
+        }
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/inventory")
+            .join(name)
+    }
+
+    #[test]
+    fn probe_fixtures() {
+        let sgx = HostInventory {
+            kernel_version: Some("6.2.0-39-generic".into()),
+            cpu_model: Some("Intel(R) Xeon(R) Gold 6338N CPU @ 2.20GHz".into()),
+            cpu_count: Some(4),
+            mem_total: Some(65_516_096 * 1024),
+            mem_available: Some(61_237_520 * 1024),
+            hugepage_size: Some(2 << 20),
+            hugepages_total: Some(512),
+            hugepages_free: Some(500),
+            iommu: Some(true),
+            sgx_epc_size: Some(128 * GIB),
+            sev_asids: None,
+            sev_es_asids: None,
+        };
+        let sev = HostInventory {
+            kernel_version: Some("5.19.0-rc6-snp-host".into()),
+            cpu_model: Some("AMD EPYC 7763 64-Core Processor".into()),
+            cpu_count: Some(2),
+            mem_total: Some(263_866_076 * 1024),
+            mem_available: Some(259_044_640 * 1024),
+            hugepage_size: Some(2 << 20),
+            hugepages_total: Some(0),
+            hugepages_free: Some(0),
+            iommu: Some(true),
+            sgx_epc_size: None,
+            sev_asids: Some(410),
+            sev_es_asids: Some(99),
+        };
+        // 3.10 predates MemAvailable, and this kernel has no IOMMU support
+        let old = HostInventory {
+            kernel_version: Some("3.10.0-1160.el7.x86_64".into()),
+            cpu_model: Some("Intel(R) Core(TM) i5-2400 CPU @ 3.10GHz".into()),
+            cpu_count: Some(1),
+            mem_total: Some(3_882_068 * 1024),
+            mem_available: None,
+            hugepage_size: Some(2 << 20),
+            hugepages_total: Some(0),
+            hugepages_free: Some(0),
+            ..Default::default()
+        };
+        let arm = HostInventory {
+            kernel_version: Some("6.1.0-13-arm64".into()),
+            cpu_count: Some(2),
+            mem_total: Some(8_039_560 * 1024),
+            mem_available: Some(7_204_116 * 1024),
+            hugepage_size: Some(2 << 20),
+            hugepages_total: Some(0),
+            hugepages_free: Some(0),
+            ..Default::default()
+        };
+        let cases = [
+            ("sgx-6.2", sgx),
+            ("sev-5.19", sev),
+            ("el7-3.10", old),
+            ("arm64-6.1", arm),
+        ];
+        for (name, expected) in cases.iter() {
+            assert_eq!(&probe_root(&fixture(name)), expected, "{}", name);
+        }
+
+        let empty = tempfile::tempdir().unwrap();
+        assert_eq!(probe_root(empty.path()), HostInventory::default());
+        std::fs::create_dir_all(empty.path().join("sys/class/iommu")).unwrap();
+        assert_eq!(probe_root(empty.path()).iommu, Some(false));
+    }
+
+    #[test]
+    fn probe_this_host() {
+        // Whatever this host is, it has a kernel and some memory
+        let inv = probe();
+        assert!(inv.kernel_version.is_some());
+        assert!(inv.mem_total.unwrap() > 0);
+    }
+}
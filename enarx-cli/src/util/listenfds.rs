@@ -2,10 +2,13 @@
 
 // systemd socket activation helpers
 
+use std::convert::TryFrom;
 use std::env::{var, VarError};
 use std::num::ParseIntError;
 use std::os::unix::io::RawFd;
 
+use libc::{c_int, socklen_t};
+
 const LISTEN_FDS_START: RawFd = 3;
 
 type Pid = i32;
@@ -84,7 +87,7 @@ impl ListenFds {
         }
 
         let fds = Self::get_listen_fds()?;
-        if fds <= 0 || fds > (RawFd::MAX - LISTEN_FDS_START) as usize {
+        if fds == 0 || fds > (RawFd::MAX - LISTEN_FDS_START) as usize {
             return Err(ListenFdError::CountError);
         }
 
@@ -114,8 +117,10 @@ impl ListenFds {
 
     pub fn iter(&self) -> impl ExactSizeIterator<Item = RawFd> {
         let start = LISTEN_FDS_START;
-        let end = LISTEN_FDS_START.saturating_add(self.fds as i32);
-        (start..end).map(RawFd::from)
+        // `fds` is public, so it might not have been range-checked by
+        // from_env(); `as i32` would wrap a huge count around to a small one
+        let count = RawFd::try_from(self.fds).unwrap_or(RawFd::MAX);
+        start..start.saturating_add(count)
     }
 
     pub fn iter_names(&self) -> impl ExactSizeIterator<Item = &str> {
@@ -126,6 +131,16 @@ impl ListenFds {
         self.iter().zip(self.iter_names())
     }
 
+    /// Iterate over the fds along with their names and what kind of socket
+    /// each one actually is.
+    pub fn iter_detailed(&self) -> impl ExactSizeIterator<Item = ListenFd<'_>> {
+        self.iter_with_names().map(|(fd, name)| ListenFd {
+            fd,
+            name,
+            kind: FdKind::of(fd),
+        })
+    }
+
     /// Get the first FD labeled "connection", which is how systemd indicates
     /// the activating socket for services with `Accept=yes` in the socket
     /// unit file. See sd_listen_fds(3) for details.
@@ -157,7 +172,7 @@ impl<'a> Iterator for ListenFdNamesIter<'a> {
             return None;
         }
         let name = match &self.lfd.fdnames {
-            Some(v) => &v[self.cur],
+            Some(v) => v.get(self.cur).map_or("unknown", String::as_str),
             None => "unknown",
         };
         self.cur += 1;
@@ -172,6 +187,108 @@ impl<'a> Iterator for ListenFdNamesIter<'a> {
 
 impl<'a> ExactSizeIterator for ListenFdNamesIter<'a> {}
 
+/// An inherited fd, its name, and what kind of socket it is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListenFd<'a> {
+    pub fd: RawFd,
+    pub name: &'a str,
+    pub kind: FdKind,
+}
+
+/// What kind of socket (if any) a file descriptor refers to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FdKind {
+    /// AF_UNIX stream socket
+    Unix { listening: bool },
+    /// AF_INET or AF_INET6 stream socket
+    Tcp { listening: bool },
+    /// Some other kind of socket (datagram, netlink, etc.)
+    OtherSocket,
+    /// An open fd that isn't a socket (pipe, regular file, etc.)
+    NotSocket,
+    /// Not an open file descriptor at all
+    Closed,
+}
+
+fn getsockopt_int(fd: RawFd, opt: c_int) -> std::io::Result<c_int> {
+    let mut val: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as socklen_t;
+    let r = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            opt,
+            &mut val as *mut c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if r < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(val)
+}
+
+fn getsockname_family(fd: RawFd) -> std::io::Result<c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+    let r = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if r < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(addr.ss_family as c_int)
+}
+
+impl FdKind {
+    /// Inspect the given fd and figure out what it is.
+    pub fn of(fd: RawFd) -> Self {
+        let socktype = match getsockopt_int(fd, libc::SO_TYPE) {
+            Ok(t) => t,
+            Err(e) => {
+                return match e.raw_os_error() {
+                    Some(libc::EBADF) => FdKind::Closed,
+                    _ => FdKind::NotSocket,
+                }
+            }
+        };
+        if socktype != libc::SOCK_STREAM {
+            return FdKind::OtherSocket;
+        }
+        let listening = getsockopt_int(fd, libc::SO_ACCEPTCONN).is_ok_and(|v| v != 0);
+        match getsockname_family(fd) {
+            Ok(libc::AF_UNIX) => FdKind::Unix { listening },
+            Ok(libc::AF_INET) | Ok(libc::AF_INET6) => FdKind::Tcp { listening },
+            _ => FdKind::OtherSocket,
+        }
+    }
+
+    pub fn is_listening(&self) -> bool {
+        matches!(
+            self,
+            FdKind::Unix { listening: true } | FdKind::Tcp { listening: true }
+        )
+    }
+}
+
+impl std::fmt::Display for FdKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FdKind::Unix { listening: true } => write!(f, "listening unix socket"),
+            FdKind::Unix { listening: false } => write!(f, "connected unix socket"),
+            FdKind::Tcp { listening: true } => write!(f, "listening tcp socket"),
+            FdKind::Tcp { listening: false } => write!(f, "connected tcp socket"),
+            FdKind::OtherSocket => write!(f, "non-stream socket"),
+            FdKind::NotSocket => write!(f, "not a socket"),
+            FdKind::Closed => write!(f, "closed fd"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -216,6 +333,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn huge_count_saturates() {
+        let lfd = ListenFds {
+            fds: u32::MAX as usize + 4,
+            fdnames: None,
+        };
+        assert_eq!(lfd.iter().next(), Some(LISTEN_FDS_START));
+        assert_eq!(lfd.iter().len(), (RawFd::MAX - LISTEN_FDS_START) as usize);
+    }
+
+    #[test]
+    fn short_names() {
+        let lfd = ListenFds {
+            fds: 2,
+            fdnames: Some(vec!["one".into()]),
+        };
+        assert_eq!(
+            lfd.iter_names().collect::<Vec<&str>>(),
+            vec!["one", "unknown"]
+        );
+    }
+
     #[test]
     #[serial]
     fn with_names() {
@@ -305,4 +444,38 @@ mod tests {
         set_var("LISTEN_FDNAMES", "connection:other");
         assert_eq!(ListenFds::from_env().unwrap().get_connection_fd(), Some(3));
     }
+
+    #[test]
+    fn fdkind_of() {
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+
+        let dir = tempfile::tempdir().unwrap();
+        let unix_listener = UnixListener::bind(dir.path().join("sock")).unwrap();
+        let (unix_stream, _peer) = UnixStream::pair().unwrap();
+        let tcp_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_stream = std::net::TcpStream::connect(tcp_listener.local_addr().unwrap()).unwrap();
+        let (dgram, _peer) = UnixDatagram::pair().unwrap();
+        let file = std::fs::File::open("/dev/null").unwrap();
+
+        assert_eq!(
+            FdKind::of(unix_listener.as_raw_fd()),
+            FdKind::Unix { listening: true }
+        );
+        assert_eq!(
+            FdKind::of(unix_stream.as_raw_fd()),
+            FdKind::Unix { listening: false }
+        );
+        assert_eq!(
+            FdKind::of(tcp_listener.as_raw_fd()),
+            FdKind::Tcp { listening: true }
+        );
+        assert_eq!(
+            FdKind::of(tcp_stream.as_raw_fd()),
+            FdKind::Tcp { listening: false }
+        );
+        assert_eq!(FdKind::of(dgram.as_raw_fd()), FdKind::OtherSocket);
+        assert_eq!(FdKind::of(file.as_raw_fd()), FdKind::NotSocket);
+        assert_eq!(FdKind::of(RawFd::MAX), FdKind::Closed);
+    }
 }
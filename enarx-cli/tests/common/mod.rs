@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for running the real `enarx-cli` binary the way systemd would:
+//! with sockets passed in as fds 3 and up, described by LISTEN_FDS,
+//! LISTEN_PID and LISTEN_FDNAMES. See sd_listen_fds(3).
+
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// The first fd systemd passes to a service
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A set of fds to hand to a child process, and what to call them
+#[derive(Default)]
+pub struct SocketActivation {
+    fds: Vec<(OwnedFd, String)>,
+}
+
+impl SocketActivation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass `fd` to the child with the given name. The fds end up in the
+    /// child in the order they're added, starting at fd 3.
+    pub fn fd(mut self, fd: impl Into<OwnedFd>, name: &str) -> Self {
+        self.fds.push((fd.into(), name.into()));
+        self
+    }
+
+    /// Start `enarx-cli` with the given args and the fds in place.
+    pub fn spawn(self, args: &[&str]) -> ServeChild {
+        let names: Vec<&str> = self.fds.iter().map(|(_, name)| name.as_str()).collect();
+        let mut cmd = self.command(args);
+        cmd.env("LISTEN_FDNAMES", names.join(":"));
+        let fds = self.fds.into_iter().map(|(fd, _)| fd).collect();
+        ServeChild::spawn(cmd, fds)
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        // LISTEN_PID has to be the pid of the process that reads it, which
+        // we can't know before forking, so let the shell fill it in and
+        // then exec the real binary in its place.
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg(r#"LISTEN_PID=$$ exec "$0" "$@""#)
+            .arg(env!("CARGO_BIN_EXE_enarx-cli"))
+            .args(args)
+            .env("LISTEN_FDS", self.fds.len().to_string())
+            .env_remove("NOTIFY_SOCKET")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        cmd
+    }
+}
+
+/// A running `enarx-cli`, which gets killed if it's dropped while running
+pub struct ServeChild {
+    child: Child,
+}
+
+impl ServeChild {
+    fn spawn(mut cmd: Command, fds: Vec<OwnedFd>) -> Self {
+        // Move the fds well out of the way first, so putting one at 3 can't
+        // clobber another that happens to be sitting there already.
+        let high: Vec<OwnedFd> = fds
+            .iter()
+            .map(|fd| unsafe {
+                let new = libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 100);
+                assert!(new >= 0, "F_DUPFD failed");
+                OwnedFd::from_raw_fd(new)
+            })
+            .collect();
+        let raw: Vec<RawFd> = high.iter().map(AsRawFd::as_raw_fd).collect();
+        // SAFETY: dup2() is async-signal-safe, and the new fds don't have
+        // FD_CLOEXEC set, so they survive the exec.
+        unsafe {
+            cmd.pre_exec(move || {
+                for (i, fd) in raw.iter().enumerate() {
+                    if libc::dup2(*fd, LISTEN_FDS_START + i as RawFd) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let child = cmd.spawn().expect("failed to start enarx-cli");
+        drop(high);
+        Self { child }
+    }
+
+    /// Wait up to `timeout` for the child to exit, returning its status and
+    /// whatever it wrote to stderr.
+    pub fn wait_timeout(mut self, timeout: Duration) -> Option<(ExitStatus, String)> {
+        use std::io::Read;
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait().expect("wait failed") {
+                let mut stderr = String::new();
+                if let Some(mut pipe) = self.child.stderr.take() {
+                    pipe.read_to_string(&mut stderr).ok();
+                }
+                return Some((status, stderr));
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        None
+    }
+}
+
+impl Drop for ServeChild {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            self.child.kill().ok();
+            self.child.wait().ok();
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Run `enarx-cli serve` under emulated systemd socket activation and talk
+//! to it over the sockets we handed it.
+
+mod common;
+
+use common::SocketActivation;
+use enarx_proto::v0::keepldr_client::KeepldrClient;
+use enarx_proto::v0::InfoRequest;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tonic::transport::{Endpoint, Uri};
+use tower::service_fn;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn info_over_unix(path: std::path::PathBuf) -> String {
+    let channel = Endpoint::from_static("http://enarx.dev")
+        .connect_with_connector(service_fn(move |_: Uri| {
+            tokio::net::UnixStream::connect(path.clone())
+        }))
+        .await
+        .unwrap();
+    let info = KeepldrClient::new(channel)
+        .info(InfoRequest {})
+        .await
+        .unwrap();
+    info.into_inner().name
+}
+
+#[tokio::test]
+async fn accept_no() {
+    let dir = tempfile::tempdir().unwrap();
+    let sock_path = dir.path().join("enarx.sock");
+    let unix = UnixListener::bind(&sock_path).unwrap();
+    let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let tcp_addr = tcp.local_addr().unwrap();
+
+    let serve = SocketActivation::new()
+        .fd(unix, "enarx.socket")
+        .fd(tcp, "enarx-tcp.socket")
+        .spawn(&["serve", "--systemd-socket-listen"]);
+
+    // Every listener gets served, and keeps being served after the first
+    // connection goes away
+    for _ in 0..2 {
+        assert_eq!(info_over_unix(sock_path.clone()).await, "enarx serve");
+        let mut client = KeepldrClient::connect(format!("http://{}", tcp_addr))
+            .await
+            .unwrap();
+        let info = client.info(InfoRequest {}).await.unwrap();
+        assert_eq!(info.into_inner().name, "enarx serve");
+    }
+    drop(serve);
+}
+
+#[tokio::test]
+async fn accept_yes() {
+    let (ours, theirs) = UnixStream::pair().unwrap();
+    let serve = SocketActivation::new()
+        .fd(theirs, "connection")
+        .spawn(&["serve", "--systemd-socket-accept"]);
+
+    ours.set_nonblocking(true).unwrap();
+    let stream = Arc::new(Mutex::new(Some(ours)));
+    let channel = Endpoint::from_static("http://enarx.dev")
+        .connect_with_connector(service_fn(move |_: Uri| {
+            let stream = stream.lock().unwrap().take();
+            async move {
+                let stream = stream.ok_or_else(|| std::io::Error::other("already connected"))?;
+                tokio::net::UnixStream::from_std(stream)
+            }
+        }))
+        .await
+        .unwrap();
+    let mut client = KeepldrClient::new(channel);
+    let info = client.info(InfoRequest {}).await.unwrap();
+    assert_eq!(info.into_inner().name, "enarx serve");
+
+    // Once the one connection is closed, the service instance is done
+    drop(client);
+    // Wait on another thread so this runtime can actually close the connection
+    let (status, stderr) = tokio::task::spawn_blocking(move || serve.wait_timeout(TIMEOUT))
+        .await
+        .unwrap()
+        .expect("serve didn't exit");
+    assert!(status.success(), "serve failed: {}", stderr);
+}
+
+#[test]
+fn accept_yes_given_listener() {
+    let dir = tempfile::tempdir().unwrap();
+    let unix = UnixListener::bind(dir.path().join("enarx.sock")).unwrap();
+    let serve = SocketActivation::new()
+        .fd(unix, "connection")
+        .spawn(&["serve", "--systemd-socket-accept"]);
+    let (status, stderr) = serve.wait_timeout(TIMEOUT).expect("serve didn't exit");
+    assert!(!status.success());
+    assert!(
+        stderr.contains("Accept=yes"),
+        "unexpected error: {}",
+        stderr
+    );
+}
+
+#[test]
+fn accept_no_given_connection() {
+    let (_ours, theirs) = UnixStream::pair().unwrap();
+    let serve = SocketActivation::new()
+        .fd(theirs, "enarx.socket")
+        .spawn(&["serve", "--systemd-socket-listen"]);
+    let (status, stderr) = serve.wait_timeout(TIMEOUT).expect("serve didn't exit");
+    assert!(!status.success());
+    assert!(
+        stderr.contains("not a listening socket"),
+        "unexpected error: {}",
+        stderr
+    );
+}
+
+#[test]
+fn config_check() {
+    let dir = tempfile::tempdir().unwrap();
+    let unix = UnixListener::bind(dir.path().join("enarx.sock")).unwrap();
+    let serve = SocketActivation::new().fd(unix, "enarx.socket").spawn(&[
+        "serve",
+        "--systemd-socket-listen",
+        "--config-check",
+    ]);
+    let (status, stderr) = serve.wait_timeout(TIMEOUT).expect("serve didn't exit");
+    assert!(status.success(), "config check failed: {}", stderr);
+}
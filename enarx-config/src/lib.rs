@@ -1,18 +1,30 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use log::{debug, warn};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use structopt::StructOpt;
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::os::unix::io::{RawFd, AsRawFd};
-use wasmparser::WasmFeatures;
+use wasmparser::{Validator, WasmFeatures};
+use zeroize::Zeroizing;
 
-/// Options for setting up TLS connections
-#[derive(StructOpt, Debug)]
+/// Options for setting up TLS connections. Only the paths are serialized;
+/// the in-memory `cert_pem` and `key_pem` never are, and serializing fails
+/// if one of them is set without the matching path, since the other side
+/// would end up with no certificate or key at all.
+#[derive(StructOpt, Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct TLSOptions {
     /// PEM-encoded certificate chain
     #[structopt(long)]
     pub cert: Option<PathBuf>,
-    
+
     /// PEM-encoded private key
     #[structopt(long)]
     pub key: Option<PathBuf>,
@@ -24,29 +36,477 @@ pub struct TLSOptions {
     /// Directory containing trusted CA certificates
     #[structopt(long)]
     pub capath: Option<PathBuf>,
+
+    /// Whether a server asks clients for a certificate signed by one of the
+    /// trusted CAs: off, optional or required
+    #[structopt(long, value_name = "MODE", default_value = "off")]
+    pub client_auth: ClientAuthMode,
+
+    /// PEM-encoded certificate chain, used instead of `cert` if it's set
+    #[structopt(skip)]
+    #[serde(skip)]
+    pub cert_pem: Option<Vec<u8>>,
+
+    /// PEM-encoded private key, used instead of `key` if it's set. It's
+    /// zeroed when it's dropped.
+    #[structopt(skip)]
+    #[serde(skip)]
+    pub key_pem: Option<Secret<Zeroizing<Vec<u8>>>>,
 }
 
+impl Serialize for TLSOptions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{Error, SerializeStruct};
 
-/// Settings for the workload's runtime environment
+        for (pem, path, name) in [
+            (self.cert_pem.is_some(), &self.cert, "cert"),
+            (self.key_pem.is_some(), &self.key, "key"),
+        ] {
+            if pem && path.is_none() {
+                return Err(S::Error::custom(format!(
+                    "can't serialize an in-memory {}_pem without a {} path",
+                    name, name
+                )));
+            }
+        }
+        let mut state = serializer.serialize_struct("TLSOptions", 5)?;
+        for (name, path) in [
+            ("cert", &self.cert),
+            ("key", &self.key),
+            ("cacert", &self.cacert),
+            ("capath", &self.capath),
+        ] {
+            match path {
+                Some(path) => state.serialize_field(name, path)?,
+                None => state.skip_field(name)?,
+            }
+        }
+        if self.client_auth.is_off() {
+            state.skip_field("client_auth")?;
+        } else {
+            state.serialize_field("client_auth", &self.client_auth)?;
+        }
+        state.end()
+    }
+}
+
+/// Whether a TLS server authenticates its clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMode {
+    /// Don't ask for a client certificate
+    #[default]
+    Off,
+    /// Ask for one, and verify it if the client sends one, but let clients
+    /// without one connect too
+    Optional,
+    /// Refuse any client without a valid certificate
+    Required,
+}
+
+impl std::str::FromStr for ClientAuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(ClientAuthMode::Off),
+            "optional" => Ok(ClientAuthMode::Optional),
+            "required" => Ok(ClientAuthMode::Required),
+            _ => Err(format!(
+                "unknown client auth mode {:?} (expected off, optional or required)",
+                s
+            )),
+        }
+    }
+}
+
+impl ClientAuthMode {
+    fn is_off(&self) -> bool {
+        *self == ClientAuthMode::Off
+    }
+}
+
+impl std::fmt::Display for ClientAuthMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ClientAuthMode::Off => "off",
+            ClientAuthMode::Optional => "optional",
+            ClientAuthMode::Required => "required",
+        })
+    }
+}
+
+/// Problems loading the files named in `TLSOptions`
 #[derive(Debug)]
+pub enum TlsError {
+    /// A required option wasn't given
+    Missing(&'static str),
+    /// The file couldn't be read at all
+    Read { path: PathBuf, source: io::Error },
+    /// The file didn't contain the PEM items we expected
+    Parse { path: PathBuf, reason: String },
+    /// The file parsed OK, but its contents aren't usable
+    Invalid { path: PathBuf, reason: String },
+}
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Missing(opt) => write!(f, "--{} is required", opt),
+            TlsError::Read { path, source } => write!(f, "{}: {}", path.display(), source),
+            TlsError::Parse { path, reason } => {
+                write!(f, "{}: failed to parse: {}", path.display(), reason)
+            }
+            TlsError::Invalid { path, reason } => {
+                write!(f, "{}: failed validation: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TlsError::Read { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl TLSOptions {
+    /// Options that use an in-memory certificate chain and key, rather than
+    /// reading them from files.
+    pub fn from_pem_bytes(cert: impl Into<Vec<u8>>, key: impl Into<Vec<u8>>) -> Self {
+        TLSOptions {
+            cert_pem: Some(cert.into()),
+            key_pem: Some(Secret::new(Zeroizing::new(key.into()))),
+            ..Default::default()
+        }
+    }
+
+    /// The certificate chain to use: `cert_pem` if it's set, else `cert`
+    fn cert_source(&self) -> Option<PemSource<'_>> {
+        match (&self.cert_pem, &self.cert) {
+            (Some(pem), _) => Some(PemSource::Memory("<cert_pem>", pem)),
+            (None, path) => path.as_deref().map(PemSource::File),
+        }
+    }
+
+    /// The private key to use: `key_pem` if it's set, else `key`
+    fn key_source(&self) -> Option<PemSource<'_>> {
+        match (&self.key_pem, &self.key) {
+            (Some(pem), _) => Some(PemSource::Memory("<key_pem>", pem.expose())),
+            (None, path) => path.as_deref().map(PemSource::File),
+        }
+    }
+
+    /// Build a server config from `cert` and `key` (or their in-memory
+    /// equivalents), which are both required. The key must match the leaf
+    /// (first) certificate in `cert`.
+    ///
+    /// Unless `client_auth` is `Off`, client certificates are verified
+    /// against the CAs in `cacert` and `capath`, and at least one of those
+    /// is required.
+    pub fn server_config(&self) -> Result<rustls::ServerConfig, TlsError> {
+        let (cert, key) = match (self.cert_source(), self.key_source()) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, _) => return Err(TlsError::Missing("cert")),
+            (_, None) => return Err(TlsError::Missing("key")),
+        };
+        let chain = load_certs(&cert)?;
+        let der = load_key(&key)?;
+        let builder = rustls::ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .expect("default protocol versions are supported");
+        let builder = match self.client_auth {
+            ClientAuthMode::Off => builder.with_no_client_auth(),
+            mode => builder.with_client_cert_verifier(self.client_verifier(mode)?),
+        };
+        builder
+            .with_single_cert(chain, der)
+            .map_err(|e| key_error(cert.name(), key.name(), e))
+    }
+
+    /// Build a client config that trusts the CAs in `cacert` and `capath`.
+    /// If `cert` and `key` are set they're used for client authentication;
+    /// if neither is set the client is anonymous. Setting only one of them
+    /// is an error.
+    pub fn client_config(&self) -> Result<rustls::ClientConfig, TlsError> {
+        let builder = rustls::ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .expect("default protocol versions are supported")
+            .with_root_certificates(self.root_store()?);
+        match (self.cert_source(), self.key_source()) {
+            (None, None) => Ok(builder.with_no_client_auth()),
+            (Some(cert), Some(key)) => {
+                let chain = load_certs(&cert)?;
+                let der = load_key(&key)?;
+                builder
+                    .with_client_auth_cert(chain, der)
+                    .map_err(|e| key_error(cert.name(), key.name(), e))
+            }
+            (Some(_), None) => Err(TlsError::Missing("key")),
+            (None, Some(_)) => Err(TlsError::Missing("cert")),
+        }
+    }
+
+    /// A verifier that accepts client certificates issued by our CAs
+    fn client_verifier(
+        &self,
+        mode: ClientAuthMode,
+    ) -> Result<std::sync::Arc<dyn rustls::server::danger::ClientCertVerifier>, TlsError> {
+        let roots = self.root_store()?;
+        if roots.is_empty() {
+            return Err(TlsError::Missing("cacert"));
+        }
+        let builder = rustls::server::WebPkiClientVerifier::builder_with_provider(
+            roots.into(),
+            crypto_provider(),
+        );
+        let builder = match mode {
+            ClientAuthMode::Optional => builder.allow_unauthenticated(),
+            _ => builder,
+        };
+        builder.build().map_err(|e| TlsError::Invalid {
+            path: (self.cacert.clone().or_else(|| self.capath.clone())).unwrap_or_default(),
+            reason: e.to_string(),
+        })
+    }
+
+    /// Load every CA certificate from `cacert` and from the `*.pem` files
+    /// in `capath`.
+    fn root_store(&self) -> Result<rustls::RootCertStore, TlsError> {
+        let mut files: Vec<PathBuf> = self.cacert.iter().cloned().collect();
+        if let Some(dir) = &self.capath {
+            let read_err = |source| TlsError::Read {
+                path: dir.clone(),
+                source,
+            };
+            let mut pems = Vec::new();
+            for entry in std::fs::read_dir(dir).map_err(read_err)? {
+                let path = entry.map_err(read_err)?.path();
+                if path.extension() == Some("pem".as_ref()) && path.is_file() {
+                    pems.push(path);
+                }
+            }
+            pems.sort();
+            files.extend(pems);
+        }
+        let mut roots = rustls::RootCertStore::empty();
+        for path in files {
+            for cert in load_certs(&PemSource::File(&path))? {
+                roots.add(cert).map_err(|e| TlsError::Invalid {
+                    path: path.clone(),
+                    reason: e.to_string(),
+                })?;
+            }
+        }
+        Ok(roots)
+    }
+}
+
+fn crypto_provider() -> std::sync::Arc<rustls::crypto::CryptoProvider> {
+    std::sync::Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Somewhere to read PEM data from
+enum PemSource<'a> {
+    File(&'a Path),
+    /// PEM data that's already in memory, and a name to use for it in errors
+    Memory(&'static str, &'a [u8]),
+}
+
+impl PemSource<'_> {
+    /// The path to blame in errors
+    fn name(&self) -> &Path {
+        match self {
+            PemSource::File(path) => path,
+            PemSource::Memory(name, _) => Path::new(name),
+        }
+    }
+}
+
+fn read_pem(source: &PemSource<'_>) -> Result<Vec<rustls_pemfile::Item>, TlsError> {
+    let path = source.name();
+    let items: io::Result<Vec<_>> = match source {
+        PemSource::File(path) => {
+            let file = File::open(path).map_err(|source| TlsError::Read {
+                path: path.into(),
+                source,
+            })?;
+            rustls_pemfile::read_all(&mut io::BufReader::new(file)).collect()
+        }
+        PemSource::Memory(_, mut bytes) => rustls_pemfile::read_all(&mut bytes).collect(),
+    };
+    items.map_err(|e| TlsError::Parse {
+        path: path.into(),
+        reason: e.to_string(),
+    })
+}
+
+/// Read all the PEM certificates in `source`; there must be at least one.
+fn load_certs(source: &PemSource<'_>) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let path = source.name();
+    let certs: Vec<_> = read_pem(source)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(cert) => Some(cert),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(TlsError::Parse {
+            path: path.into(),
+            reason: "no PEM certificates found".into(),
+        });
+    }
+    Ok(certs)
+}
+
+/// Read the first PEM private key in `source`.
+fn load_key(source: &PemSource<'_>) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let path = source.name();
+    read_pem(source)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::Pkcs1Key(key) => Some(key.into()),
+            rustls_pemfile::Item::Pkcs8Key(key) => Some(key.into()),
+            rustls_pemfile::Item::Sec1Key(key) => Some(key.into()),
+            _ => None,
+        })
+        .ok_or_else(|| TlsError::Parse {
+            path: path.into(),
+            reason: "no PEM private key found".into(),
+        })
+}
+
+/// Work out which file to blame when rustls rejects a cert/key pair.
+fn key_error(cert: &Path, key: &Path, err: rustls::Error) -> TlsError {
+    match err {
+        rustls::Error::InconsistentKeys(_) => TlsError::Invalid {
+            path: key.into(),
+            reason: format!("private key does not match certificate {}", cert.display()),
+        },
+        rustls::Error::InvalidCertificate(_) | rustls::Error::NoCertificatesPresented => {
+            TlsError::Invalid {
+                path: cert.into(),
+                reason: err.to_string(),
+            }
+        }
+        _ => TlsError::Invalid {
+            path: key.into(),
+            reason: err.to_string(),
+        },
+    }
+}
+
+/// Version of the serialized `LoaderConfig` format. This needs to change
+/// whenever a config could be misread by a wasmldr that expects the old one.
+pub const CONFIG_VERSION: u32 = 0;
+
+/// Everything wasmldr needs to set up the workload, in the form that's sent
+/// over the keep socket. It serializes as
+/// `{"version": 0, "env": {...}, "wasm": {...}}`, and deserializing fails if
+/// `version` isn't `CONFIG_VERSION`.
+///
+/// `Inherit` handles only carry the fd number; the fd itself has to be
+/// passed to wasmldr alongside the config.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoaderConfig {
+    version: ConfigVersion,
+    #[serde(default)]
+    pub env: EnvConfig,
+    #[serde(default)]
+    pub wasm: WasmConfig,
+}
+
+impl LoaderConfig {
+    pub fn new(env: EnvConfig, wasm: WasmConfig) -> Self {
+        Self {
+            version: ConfigVersion,
+            env,
+            wasm,
+        }
+    }
+}
+
+/// Always serializes as `CONFIG_VERSION` and refuses to deserialize anything else
+#[derive(Debug, Default)]
+struct ConfigVersion;
+
+impl Serialize for ConfigVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(CONFIG_VERSION)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match u32::deserialize(deserializer)? {
+            CONFIG_VERSION => Ok(ConfigVersion),
+            v => Err(serde::de::Error::custom(format!(
+                "unsupported config version {} (expected {})",
+                v, CONFIG_VERSION
+            ))),
+        }
+    }
+}
+
+/// Settings for the workload's runtime environment
+///
+/// The `Debug` output leaves out the values of sensitive variables; see
+/// `is_sensitive()`.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct EnvConfig {
     pub envs: Vec<(String, String)>,
+    /// Names of variables in `envs` whose values shouldn't be logged
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sensitive: Vec<String>,
+    /// Variables whose values are looked up by `resolve_secrets()` just
+    /// before the workload starts, so the values themselves never appear
+    /// in the config
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub secrets: Vec<(String, SecretSource)>,
+    /// What the workload sees as `argv[0]`. If it's not set, the module's
+    /// file stem is used; see `argv()`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arg0: Option<String>,
     pub args: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub stdin: Option<ReadHandle>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub stdout: Option<WriteHandle>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub stderr: Option<WriteHandle>,
-}
-
-impl Default for EnvConfig {
-    fn default() -> Self {
-        Self {
-            envs: Default::default(),
-            args: Default::default(),
-            stdin: None,
-            stdout: None,
-            stderr: None,
-        }
-    }
+    /// Host directories to make available to the workload, and the
+    /// (absolute) guest paths to put them at
+    pub preopens: Vec<(PathBuf, String)>,
+    /// The workload's working directory, as an absolute guest path
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Whether `envs` still has `$NAME` references for `expand()` to fill
+    /// in from the host environment
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub expand_env: bool,
+    /// `Some(true)` after `clear_env()`, and while it is, `inherit_env()`
+    /// and `inherit_env_filtered()` do nothing. `allow_inherit_env()` sets
+    /// it to `Some(false)`, which `merge()` carries over like `Some(true)`;
+    /// `None` leaves the other side's setting alone.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_cleared: Option<bool>,
+    /// OS resource limits for the process that runs the workload
+    #[serde(skip_serializing_if = "ResourceLimits::is_empty")]
+    pub limits: ResourceLimits,
+    /// Which outbound TCP connections the workload may make
+    #[serde(skip_serializing_if = "NetworkPolicy::is_default")]
+    pub network: NetworkPolicy,
+    /// For each variable set with `env()` or one of the `push_*()` methods,
+    /// whether its latest setting went into `secrets` rather than `envs`.
+    /// `dedup_envs()` uses this to let the last setting win.
+    #[serde(skip)]
+    latest_is_secret: std::collections::HashMap<String, bool>,
 }
 
 impl EnvConfig {
@@ -67,24 +527,4764 @@ impl EnvConfig {
         self.inherit_stdin().inherit_stdout().inherit_stderr()
     }
 
+    /// Read stdin from the file at `path`. Like every handle, the file isn't
+    /// opened until the keep is built, so it needn't exist yet.
+    pub fn stdin_from_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.stdin = Some(ReadHandle::File(path.into()));
+        self
+    }
+
+    /// Read stdin from `fd`, which the config takes ownership of.
+    pub fn stdin_from_fd(mut self, fd: impl Into<OwnedFd>) -> Self {
+        self.stdin = Some(ReadHandle::Pipe(fd.into().into()));
+        self
+    }
+
+    /// Write stdout to `fd`, which the config takes ownership of.
+    pub fn stdout_to_fd(mut self, fd: impl Into<OwnedFd>) -> Self {
+        self.stdout = Some(WriteHandle::Pipe(fd.into().into()));
+        self
+    }
+
+    /// Write stderr to `fd`, which the config takes ownership of.
+    pub fn stderr_to_fd(mut self, fd: impl Into<OwnedFd>) -> Self {
+        self.stderr = Some(WriteHandle::Pipe(fd.into().into()));
+        self
+    }
+
+    /// Write stdout to the file at `path` when the keep is built, appending
+    /// to it if `append` is set and truncating it otherwise.
+    pub fn stdout_to_file(mut self, path: impl Into<PathBuf>, append: bool) -> Self {
+        self.stdout = Some(WriteHandle::file(path, append));
+        self
+    }
+
+    /// Write stderr to the file at `path` when the keep is built, appending
+    /// to it if `append` is set and truncating it otherwise.
+    pub fn stderr_to_file(mut self, path: impl Into<PathBuf>, append: bool) -> Self {
+        self.stderr = Some(WriteHandle::file(path, append));
+        self
+    }
+
+    /// Make the host directory `host` available to the workload at `guest`.
+    pub fn preopen(mut self, host: impl Into<PathBuf>, guest: impl Into<String>) -> Self {
+        self.preopens.push((host.into(), guest.into()));
+        self
+    }
+
+    /// Set what the workload sees as `argv[0]`.
+    pub fn arg0(mut self, name: impl Into<String>) -> Self {
+        self.arg0 = Some(name.into());
+        self
+    }
+
+    /// The workload's full argument list: `arg0` (or `default_arg0(module)`
+    /// if it's not set), then `args`.
+    pub fn argv(&self, module: &Path) -> Vec<String> {
+        let arg0 = (self.arg0.clone()).unwrap_or_else(|| default_arg0(module));
+        std::iter::once(arg0)
+            .chain(self.args.iter().cloned())
+            .collect()
+    }
+
+    /// Limit the workload process's address space to `bytes`
+    pub fn limit_address_space(mut self, bytes: u64) -> Self {
+        self.limits.address_space = Some(bytes);
+        self
+    }
+
+    /// Limit the workload process to `seconds` of CPU time
+    pub fn limit_cpu_seconds(mut self, seconds: u64) -> Self {
+        self.limits.cpu_seconds = Some(seconds);
+        self
+    }
+
+    /// Limit how many files the workload process can have open at once
+    pub fn limit_open_files(mut self, count: u64) -> Self {
+        self.limits.open_files = Some(count);
+        self
+    }
+
+    /// Let the workload connect to destinations that match `rule`
+    pub fn allow_net(mut self, rule: NetRule) -> Self {
+        self.network.allow.push(rule);
+        self
+    }
+
+    /// Set the workload's working directory, as a guest path.
+    pub fn cwd(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cwd = Some(path.into());
+        self
+    }
+
+    /// The same as `cwd()`.
+    pub fn workdir(self, path: impl Into<PathBuf>) -> Self {
+        self.cwd(path)
+    }
+
+    /// Set an environment variable, replacing any previous value for `name`.
+    pub fn env(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let name = name.into();
+        self.envs.retain(|(k, _)| *k != name);
+        self.latest_is_secret.insert(name.clone(), false);
+        self.envs.push((name, value.into()));
+        self
+    }
+
+    /// Set variables from a dotenv-style file of `NAME=VALUE` lines, as if
+    /// with `env()`, so a name that's set twice gets the last value. Blank
+    /// lines and lines starting with `#` are skipped. Whitespace around the
+    /// name is trimmed, but the value is used exactly as it is: quotes and
+    /// all, with no escapes or `@` secrets.
+    pub fn envs_from_file(mut self, path: impl AsRef<Path>) -> Result<Self, EnvFileError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|source| EnvFileError::Read {
+            path: path.into(),
+            source,
+        })?;
+        for (i, line) in text.lines().enumerate() {
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let syntax = |reason: String| EnvFileError::Syntax {
+                path: path.into(),
+                line: i + 1,
+                reason,
+            };
+            let (name, value) =
+                (line.split_once('=')).ok_or_else(|| syntax("expected NAME=VALUE".into()))?;
+            let name = name.trim();
+            validate_env_name(name).map_err(|e| syntax(e.to_string()))?;
+            self = self.env(name, value);
+        }
+        Ok(self)
+    }
+
+    /// Add a variable without replacing any earlier setting, after checking
+    /// that its name and value are usable; see `validate_env_name()`.
+    pub fn push_env(
+        &mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), EnvConfigError> {
+        let (name, value) = (name.into(), value.into());
+        validate_env_name(&name)?;
+        if value.contains('\0') {
+            return Err(EnvConfigError::InvalidValue(name));
+        }
+        self.latest_is_secret.insert(name.clone(), false);
+        self.envs.push((name, value));
+        Ok(())
+    }
+
+    /// Add a variable whose value shouldn't be logged, without replacing
+    /// any earlier setting.
+    pub fn push_secret(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.sensitive.push(name.clone());
+        self.latest_is_secret.insert(name.clone(), false);
+        self.envs.push((name, value.into()));
+    }
+
+    /// Whether the value of the variable `name` should be kept out of logs,
+    /// either because it was added with `push_secret()` or because its name
+    /// matches one of `SENSITIVE_PATTERNS`.
+    pub fn is_sensitive(&self, name: &str) -> bool {
+        self.sensitive.iter().any(|s| s == name) || is_sensitive_name(name)
+    }
+
+    /// Add a variable from a `NAME=VALUE`-style spec, without replacing any
+    /// earlier setting. A value like `@file:PATH`, `@env:NAME` or
+    /// `@cmd:COMMAND` goes into `secrets` to be looked up later (see
+    /// `SecretSource`); any other value starting with `@` needs to be
+    /// escaped as `@@`.
+    pub fn push_value(
+        &mut self,
+        name: impl Into<String>,
+        value: &str,
+    ) -> Result<(), SecretParseError> {
+        let name = name.into();
+        let setting = match value.strip_prefix('@') {
+            Some(escaped) if escaped.starts_with('@') => Err(escaped.into()),
+            Some(spec) => Ok(spec.parse()?),
+            None => Err(value.into()),
+        };
+        self.latest_is_secret.insert(name.clone(), setting.is_ok());
+        match setting {
+            Ok(source) => self.secrets.push((name, source)),
+            Err(value) => self.envs.push((name, value)),
+        }
+        Ok(())
+    }
+
+    /// Look up the value of everything in `secrets`. `@cmd:` secrets are
+    /// an error unless `allow_commands` is set.
+    pub fn resolve_secrets(
+        &self,
+        allow_commands: bool,
+    ) -> Result<Vec<(String, Secret<String>)>, SecretError> {
+        self.secrets
+            .iter()
+            .map(|(name, source)| Ok((name.clone(), source.resolve(allow_commands)?)))
+            .collect()
+    }
+
+    /// If `expand_env` is set, substitute host environment variables into
+    /// the values in `envs`, then clear it so nothing gets expanded twice.
+    /// See `expand_with()` for the syntax.
+    pub fn expand(self) -> Result<Self, ExpandError> {
+        self.expand_with(|name| std::env::var(name).ok())
+    }
+
+    /// Like `expand()`, but look variables up with `lookup`.
+    ///
+    /// `${NAME}` and `$NAME` are replaced by the variable's value, and it's
+    /// an error if it isn't set. `${NAME:-DEFAULT}` gives DEFAULT if NAME is
+    /// unset or empty. `$$` is a literal `$`, and so is a `$` that isn't
+    /// followed by a name or `{`. Values of sensitive variables (see
+    /// `is_sensitive()`) are left exactly as they are, since a `$` in a
+    /// password is much more likely than a reference.
+    pub fn expand_with(
+        mut self,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ExpandError> {
+        if !self.expand_env {
+            return Ok(self);
+        }
+        let mut envs = std::mem::take(&mut self.envs);
+        for (name, value) in envs.iter_mut() {
+            if !self.is_sensitive(name) {
+                *value = expand_value(value, &lookup).map_err(|kind| ExpandError {
+                    var: name.clone(),
+                    kind,
+                })?;
+            }
+        }
+        self.envs = envs;
+        self.expand_env = false;
+        Ok(self)
+    }
+
+    /// Start from an empty environment: drop every variable set so far
+    /// (including secrets), and stop `inherit_env()` from copying anything
+    /// from the host. Variables set afterwards with `env()` are kept as
+    /// usual. To allow inheriting again, use `allow_inherit_env()`.
+    pub fn clear_env(mut self) -> Self {
+        self.envs.clear();
+        self.secrets.clear();
+        self.sensitive.clear();
+        self.latest_is_secret.clear();
+        self.env_cleared = Some(true);
+        self
+    }
+
+    /// Undo the inheritance block set by `clear_env()`, so `inherit_env()`
+    /// copies from the host again. Variables `clear_env()` dropped stay
+    /// dropped.
+    pub fn allow_inherit_env(mut self) -> Self {
+        self.env_cleared = Some(false);
+        self
+    }
+
+    /// Keep only the variables (and secrets) named in `names`, e.g. to trim
+    /// an inherited environment down to what the workload needs.
+    pub fn only_env(mut self, names: &[&str]) -> Self {
+        let keep = |name: &String| names.contains(&name.as_str());
+        self.envs.retain(|(name, _)| keep(name));
+        self.secrets.retain(|(name, _)| keep(name));
+        self.sensitive.retain(keep);
+        self.latest_is_secret.retain(|name, _| keep(name));
+        self
+    }
+
+    /// Copy all of the host's environment variables into `envs`.
+    /// See `inherit_env_filtered()` for the details.
+    pub fn inherit_env(self) -> Self {
+        self.inherit_env_filtered(&[], &[])
+    }
+
+    /// Copy the host's environment variables into `envs` if their names
+    /// match any of the `allow` patterns (or if `allow` is empty) and none of
+    /// the `deny` patterns. Patterns are globs: `*` matches any run of
+    /// characters and `?` matches any one character, so `RUST_*` or
+    /// `*_SECRET` work as you'd expect.
+    ///
+    /// The host environment is read at the time this is called. Inherited
+    /// variables are added in order of name, ahead of the existing entries,
+    /// and never replace a variable that's already in `envs` - so anything
+    /// set explicitly (with `env()`, before or after this) always wins.
+    ///
+    /// Variables whose name or value isn't valid UTF-8 are skipped with a
+    /// warning. Nothing is inherited after `clear_env()`.
+    pub fn inherit_env_filtered(mut self, allow: &[&str], deny: &[&str]) -> Self {
+        if self.env_cleared == Some(true) {
+            return self;
+        }
+        let mut inherited: Vec<(String, String)> = std::env::vars_os()
+            .filter_map(|(k, v)| match (k.into_string(), v.into_string()) {
+                (Ok(k), Ok(v)) => Some((k, v)),
+                (k, _) => {
+                    warn!("not inheriting non-UTF-8 environment variable {:?}", k);
+                    None
+                }
+            })
+            .filter(|(k, _)| allow.is_empty() || allow.iter().any(|p| glob_match(p, k)))
+            .filter(|(k, _)| !deny.iter().any(|p| glob_match(p, k)))
+            .filter(|(k, _)| !self.envs.iter().any(|(name, _)| name == k))
+            .filter(|(k, _)| !self.secrets.iter().any(|(name, _)| name == k))
+            .collect();
+        inherited.sort();
+        inherited.append(&mut self.envs);
+        self.envs = inherited;
+        self
+    }
+
+    /// Check that every variable name is non-empty and free of `=` and NUL,
+    /// that no values or args contain NUL, and that no name is set twice.
+    ///
+    /// Also check that every preopened host path is an existing directory,
+    /// and that the guest paths (and `cwd`) are absolute, with no guest path
+    /// used twice.
+    pub fn validate(&self) -> Result<(), EnvConfigError> {
+        let mut seen = std::collections::HashSet::new();
+        for (name, value) in &self.envs {
+            validate_env_name(name)?;
+            if value.contains('\0') {
+                return Err(EnvConfigError::InvalidValue(name.clone()));
+            }
+            if !seen.insert(name) {
+                return Err(EnvConfigError::DuplicateKey(name.clone()));
+            }
+        }
+        for (name, _) in &self.secrets {
+            validate_env_name(name)?;
+            if !seen.insert(name) {
+                return Err(EnvConfigError::DuplicateKey(name.clone()));
+            }
+        }
+        if let Some(arg) = (self.arg0.iter().chain(&self.args)).find(|arg| arg.contains('\0')) {
+            return Err(EnvConfigError::InvalidArg(arg.clone()));
+        }
+        let mut guests = std::collections::HashSet::new();
+        for (host, guest) in &self.preopens {
+            if !host.is_dir() {
+                return Err(EnvConfigError::NotADirectory(host.clone()));
+            }
+            if !guest.starts_with('/') {
+                return Err(EnvConfigError::RelativeGuestPath(guest.clone()));
+            }
+            if !guests.insert(guest.trim_end_matches('/')) {
+                return Err(EnvConfigError::DuplicateGuestPath(guest.clone()));
+            }
+        }
+        match &self.cwd {
+            Some(cwd) if !cwd.is_absolute() => {
+                Err(EnvConfigError::RelativeGuestPath(cwd.display().to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Layer `other` on top of this config, so that:
+    ///
+    /// - Any variable `other` sets (in `envs` or `secrets`) replaces every
+    ///   setting of that variable here. Its entries go after the ones left
+    ///   here, in their original order, and duplicates within `other` are
+    ///   kept as they are.
+    /// - `sensitive` names are handled the same way.
+    /// - `args` are replaced wholesale, if `other.args` isn't empty. Use
+    ///   `merge_args()` to add them to ours instead.
+    /// - `arg0`, `stdin`, `stdout`, `stderr` and `cwd` are taken from
+    ///   `other` if it sets them.
+    /// - A preopen in `other` replaces any preopen here at the same guest
+    ///   path; the rest are added to the end.
+    /// - `expand_env` is set if it's set on either side.
+    /// - `env_cleared` is taken from `other` if it sets it. (`other`'s
+    ///   variables are still added after ours, even if `other` was
+    ///   cleared; only the flag carries over.)
+    /// - Each of the `limits` is taken from `other` if it sets it.
+    /// - `network` rules are concatenated, and `network.default_allow` is
+    ///   taken from `other` if it sets it, so a later layer can close a
+    ///   policy an earlier one opened.
+    ///
+    /// So merging an empty config changes nothing, and since each list is
+    /// "what's left of ours, then theirs", `a.merge(b).merge(c)` and
+    /// `a.merge(b.merge(c))` come out the same.
+    pub fn merge(mut self, other: EnvConfig) -> EnvConfig {
+        let names: std::collections::HashSet<&String> = (other.envs.iter())
+            .map(|(name, _)| name)
+            .chain(other.secrets.iter().map(|(name, _)| name))
+            .collect();
+        self.envs.retain(|(name, _)| !names.contains(name));
+        self.secrets.retain(|(name, _)| !names.contains(name));
+        self.sensitive.retain(|name| !names.contains(name));
+        (self.latest_is_secret).retain(|name, _| !names.contains(name));
+        let guests: std::collections::HashSet<&str> = (other.preopens.iter())
+            .map(|(_, guest)| guest.trim_end_matches('/'))
+            .collect();
+        (self.preopens).retain(|(_, guest)| !guests.contains(guest.trim_end_matches('/')));
+
+        self.envs.extend(other.envs);
+        self.secrets.extend(other.secrets);
+        self.sensitive.extend(other.sensitive);
+        self.latest_is_secret.extend(other.latest_is_secret);
+        self.preopens.extend(other.preopens);
+        if !other.args.is_empty() {
+            self.args = other.args;
+        }
+        self.arg0 = other.arg0.or(self.arg0);
+        self.stdin = other.stdin.or(self.stdin);
+        self.stdout = other.stdout.or(self.stdout);
+        self.stderr = other.stderr.or(self.stderr);
+        self.cwd = other.cwd.or(self.cwd);
+        self.expand_env |= other.expand_env;
+        self.env_cleared = other.env_cleared.or(self.env_cleared);
+        self.limits = self.limits.merge(other.limits);
+        self.network.allow.extend(other.network.allow);
+        self.network.default_allow = (other.network.default_allow).or(self.network.default_allow);
+        self
+    }
+
+    /// Like `merge()`, except that `other`'s args are added after ours
+    /// rather than replacing them.
+    pub fn merge_args(mut self, mut other: EnvConfig) -> EnvConfig {
+        let mut args = std::mem::take(&mut self.args);
+        args.append(&mut other.args);
+        let mut merged = self.merge(other);
+        merged.args = args;
+        merged
+    }
+
+    /// Drop all but the last setting of each variable, keeping the order of
+    /// the ones that are left. If a variable is in both `envs` and
+    /// `secrets`, whichever was set last (with `env()` or a `push_*()`
+    /// method) wins; if that isn't known, the secret does.
+    pub fn dedup_envs(mut self) -> Self {
+        let latest = &self.latest_is_secret;
+        let secrets: std::collections::HashSet<&String> =
+            self.secrets.iter().map(|(name, _)| name).collect();
+        (self.envs).retain(|(name, _)| !secrets.contains(name) || latest.get(name) == Some(&false));
+        let plain: std::collections::HashSet<&String> =
+            self.envs.iter().map(|(name, _)| name).collect();
+        self.secrets.retain(|(name, _)| !plain.contains(name));
+
+        let mut seen = std::collections::HashSet::new();
+        self.secrets.reverse();
+        self.secrets.retain(|(name, _)| seen.insert(name.clone()));
+        self.secrets.reverse();
+        self.envs.reverse();
+        self.envs.retain(|(name, _)| seen.insert(name.clone()));
+        self.envs.reverse();
+        self
+    }
 }
 
-/// Options for 
-#[derive(Debug)]
-pub enum ReadHandle {
-    Null,
-    Inherit(RawFd),
-    PlaintextSocket(SocketAddr),
+impl std::fmt::Debug for EnvConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let envs: Vec<(&str, &dyn std::fmt::Debug)> = (self.envs.iter())
+            .map(|(name, value)| match self.is_sensitive(name) {
+                true => (name.as_str(), &REDACTED as &dyn std::fmt::Debug),
+                false => (name.as_str(), value as &dyn std::fmt::Debug),
+            })
+            .collect();
+        f.debug_struct("EnvConfig")
+            .field("envs", &envs)
+            .field("sensitive", &self.sensitive)
+            .field("secrets", &self.secrets)
+            .field("arg0", &self.arg0)
+            .field("args", &self.args)
+            .field("stdin", &self.stdin)
+            .field("stdout", &self.stdout)
+            .field("stderr", &self.stderr)
+            .field("preopens", &self.preopens)
+            .field("cwd", &self.cwd)
+            .field("expand_env", &self.expand_env)
+            .field("env_cleared", &self.env_cleared)
+            .field("limits", &self.limits)
+            .field("network", &self.network)
+            .finish()
+    }
 }
 
-#[derive(Debug)]
-pub enum WriteHandle {
-    Null,
-    Inherit(RawFd),
-    PlaintextSocket(SocketAddr),
+/// Resource limits for the process that runs a workload. Unset limits are
+/// left as they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// Maximum size of the address space, in bytes (`RLIMIT_AS`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address_space: Option<u64>,
+    /// CPU time, in seconds (`RLIMIT_CPU`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<u64>,
+    /// One more than the highest fd number that can be opened
+    /// (`RLIMIT_NOFILE`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_files: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Take each limit from `other` if it's set there
+    pub fn merge(self, other: ResourceLimits) -> Self {
+        ResourceLimits {
+            address_space: other.address_space.or(self.address_space),
+            cpu_seconds: other.cpu_seconds.or(self.cpu_seconds),
+            open_files: other.open_files.or(self.open_files),
+        }
+    }
+
+    /// Apply the limits that are set to the current process, with
+    /// `setrlimit()`. Both the soft and hard limits are set, so they can't
+    /// be raised again afterwards, and trying to go above the current hard
+    /// limit fails with `EPERM`.
+    ///
+    /// This only makes calls that are async-signal-safe, so it can be used
+    /// from `CommandExt::pre_exec()`.
+    pub fn apply(&self) -> io::Result<()> {
+        let limits = [
+            (libc::RLIMIT_AS, self.address_space),
+            (libc::RLIMIT_CPU, self.cpu_seconds),
+            (libc::RLIMIT_NOFILE, self.open_files),
+        ];
+        for (resource, limit) in limits.iter() {
+            if let Some(limit) = limit {
+                let rlim = libc::rlimit {
+                    rlim_cur: *limit as libc::rlim_t,
+                    rlim_max: *limit as libc::rlim_t,
+                };
+                // SAFETY: rlim is a valid rlimit that outlives the call
+                if unsafe { libc::setrlimit(*resource, &rlim) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
+/// Which outbound TCP connections a workload may make. A connection is
+/// allowed if any rule in `allow` matches it; otherwise it's allowed only
+/// if `default_allow` is `Some(true)`. So the default policy denies
+/// everything.
+///
+/// `Enarx.toml` has no `network` table, so rules come from `enarx run
+/// --allow-net` or from a serialized `EnvConfig`, where `network` is read
+/// like any other field.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkPolicy {
+    /// Whether to allow connections that no rule matches. `None` means
+    /// nothing has said either way, which denies them, but unlike
+    /// `Some(false)` it lets `EnvConfig::merge()` keep the other side's
+    /// setting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_allow: Option<bool>,
+    /// Destinations the workload may connect to, like `api.example.com:443`
+    /// or `10.0.0.0/8:5432`; see `NetRule::from_str()`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<NetRule>,
+}
 
-pub struct WasmConfig {
-    pub features: WasmFeatures,
-}
\ No newline at end of file
+impl NetworkPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether the workload may connect to `addr`. Rules with host names
+    /// only match if `hostname` is given, since an address alone doesn't
+    /// say which name it was looked up from.
+    pub fn check(&self, addr: &SocketAddr, hostname: Option<&str>) -> bool {
+        self.allow.iter().any(|rule| rule.matches(addr, hostname))
+            || self.default_allow.unwrap_or(false)
+    }
+}
+
+/// One `HOST:PORT` rule in a `NetworkPolicy`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetRule {
+    pub host: HostPattern,
+    /// The lowest and highest ports allowed
+    pub ports: (u16, u16),
+}
+
+/// The host half of a `NetRule`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    /// Any host at all: `*`
+    Any,
+    /// Addresses in a network, like `10.0.0.0/8`. A single address is a
+    /// network with a full-length prefix.
+    Cidr(std::net::IpAddr, u8),
+    /// A host name, compared case-insensitively
+    Name(String),
+    /// Any name under a domain: `*.example.com` (but not `example.com`)
+    Subdomain(String),
+}
+
+impl NetRule {
+    pub fn matches(&self, addr: &SocketAddr, hostname: Option<&str>) -> bool {
+        let (low, high) = self.ports;
+        if addr.port() < low || addr.port() > high {
+            return false;
+        }
+        let hostname = hostname.map(|h| h.trim_end_matches('.').to_ascii_lowercase());
+        match &self.host {
+            HostPattern::Any => true,
+            HostPattern::Cidr(net, len) => in_network(addr.ip().to_canonical(), *net, *len),
+            HostPattern::Name(name) => hostname.is_some_and(|h| h == *name),
+            HostPattern::Subdomain(domain) => hostname.is_some_and(|h| {
+                h.strip_suffix(domain.as_str())
+                    .is_some_and(|rest| rest.len() > 1 && rest.ends_with('.'))
+            }),
+        }
+    }
+}
+
+/// Whether `ip` is in the network `net`/`len`
+fn in_network(ip: std::net::IpAddr, net: std::net::IpAddr, len: u8) -> bool {
+    network_of(ip, len) == net
+}
+
+/// A `NetRule` that couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetRuleParseError(String);
+
+impl std::fmt::Display for NetRuleParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NetRuleParseError {}
+
+impl std::str::FromStr for NetRule {
+    type Err = NetRuleParseError;
+
+    /// Parse `HOST:PORT`. HOST is `*`, a host name, `*.DOMAIN`, an IP
+    /// address or a network like `10.0.0.0/8`; IPv6 addresses and networks
+    /// go in brackets, like `[fd00::/8]`. PORT is a number, a range like
+    /// `8000-8080`, or `*` for any port.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |msg: String| Err(NetRuleParseError(msg));
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && !port.is_empty() => (host, port),
+            _ => return err(format!("network rule requires HOST:PORT, not {:?}", s)),
+        };
+
+        let parse_port = |p: &str| {
+            p.parse::<u16>().map_err(|_| {
+                NetRuleParseError(format!("invalid port {:?} in network rule {:?}", p, s))
+            })
+        };
+        let ports = match port.split_once('-') {
+            _ if port == "*" => (0, u16::MAX),
+            Some((low, high)) => (parse_port(low)?, parse_port(high)?),
+            None => (parse_port(port)?, parse_port(port)?),
+        };
+        if ports.0 > ports.1 {
+            return err(format!("port range {:?} is backwards", port));
+        }
+
+        let bracketed = host.strip_prefix('[').and_then(|h| h.strip_suffix(']'));
+        let (addr, len) = match bracketed.unwrap_or(host).split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (bracketed.unwrap_or(host), None),
+        };
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Ok(ip) = addr.parse::<std::net::IpAddr>() {
+            if ip.is_ipv6() && bracketed.is_none() {
+                return err(format!(
+                    "IPv6 addresses need brackets, like [{}]:{}",
+                    host, port
+                ));
+            }
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let len = match len.map(str::parse::<u8>) {
+                None => max,
+                Some(Ok(len)) if len <= max => len,
+                Some(_) => {
+                    return err(format!(
+                        "invalid prefix length in {:?} (must be 0-{})",
+                        host, max
+                    ))
+                }
+            };
+            if network_of(ip, len) != ip {
+                return err(format!(
+                    "{:?} has bits set past the /{} prefix; did you mean {}/{}?",
+                    host,
+                    len,
+                    network_of(ip, len),
+                    len
+                ));
+            }
+            HostPattern::Cidr(ip, len)
+        } else if len.is_some() || bracketed.is_some() {
+            return err(format!("invalid network {:?}", host));
+        } else {
+            let name = host.trim_end_matches('.').to_ascii_lowercase();
+            let (name, subdomains) = match name.strip_prefix("*.") {
+                Some(domain) => (domain.to_string(), true),
+                None => (name, false),
+            };
+            let label_ok = |l: &str| {
+                !l.is_empty()
+                    && (l.bytes()).all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            };
+            if !name.split('.').all(label_ok) {
+                return err(format!("invalid host name {:?} in network rule", host));
+            }
+            match subdomains {
+                true => HostPattern::Subdomain(name),
+                false => HostPattern::Name(name),
+            }
+        };
+        Ok(NetRule { host, ports })
+    }
+}
+
+/// `ip` with everything past the first `len` bits cleared
+fn network_of(ip: std::net::IpAddr, len: u8) -> std::net::IpAddr {
+    use std::net::IpAddr;
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+            IpAddr::V4((u32::from(ip) & mask).into())
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & mask).into())
+        }
+    }
+}
+
+impl std::fmt::Display for NetRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.host {
+            HostPattern::Any => write!(f, "*")?,
+            HostPattern::Cidr(ip, len) => {
+                let full = if ip.is_ipv4() { 32 } else { 128 };
+                let net = match len {
+                    len if *len == full => ip.to_string(),
+                    len => format!("{}/{}", ip, len),
+                };
+                match ip.is_ipv6() {
+                    true => write!(f, "[{}]", net)?,
+                    false => write!(f, "{}", net)?,
+                }
+            }
+            HostPattern::Name(name) => write!(f, "{}", name)?,
+            HostPattern::Subdomain(domain) => write!(f, "*.{}", domain)?,
+        }
+        match self.ports {
+            (0, u16::MAX) => write!(f, ":*"),
+            (low, high) if low == high => write!(f, ":{}", low),
+            (low, high) => write!(f, ":{}-{}", low, high),
+        }
+    }
+}
+
+impl Serialize for NetRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for NetRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Check that `name` can be used as an environment variable name: it has to
+/// be non-empty and can't contain `=` or NUL. Names that aren't portable
+/// (anything other than `[A-Za-z_][A-Za-z0-9_]*`) are allowed, since some
+/// programs use them, but they get a warning.
+pub fn validate_env_name(name: &str) -> Result<(), EnvConfigError> {
+    if name.is_empty() || name.contains(&['=', '\0'][..]) {
+        return Err(EnvConfigError::InvalidName(name.into()));
+    }
+    let portable = !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !portable {
+        warn!("environment variable name {:?} is not portable", name);
+    }
+    Ok(())
+}
+
+/// The `argv[0]` a workload gets if its config doesn't set one: the file
+/// stem of `module`, so `./target/foo.wasm` runs as `foo`. A path with no
+/// file name at all is used as it is.
+pub fn default_arg0(module: &Path) -> String {
+    match module.file_stem() {
+        Some(stem) => stem.to_string_lossy().into_owned(),
+        None => module.to_string_lossy().into_owned(),
+    }
+}
+
+/// Expand the `$` references in `value`; see `EnvConfig::expand_with()`.
+fn expand_value(
+    value: &str,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<String, ExpandErrorKind> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after.find('}').ok_or(ExpandErrorKind::Unterminated)?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            if name.is_empty()
+                || name.starts_with(|c: char| c.is_ascii_digit())
+                || !name.chars().all(is_name_char)
+            {
+                return Err(ExpandErrorKind::BadName(name.into()));
+            }
+            match (lookup(name), default) {
+                (Some(v), Some(_)) if !v.is_empty() => out.push_str(&v),
+                (_, Some(default)) => out.push_str(default),
+                (Some(v), None) => out.push_str(&v),
+                (None, None) => return Err(ExpandErrorKind::Undefined(name.into())),
+            }
+            rest = &after[end + 1..];
+        } else if rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            let end = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+            let name = &rest[..end];
+            let v = lookup(name).ok_or_else(|| ExpandErrorKind::Undefined(name.into()))?;
+            out.push_str(&v);
+            rest = &rest[end..];
+        } else {
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Variable names that probably hold something secret. Matching ignores
+/// case, so `*TOKEN*` catches `github_token` too.
+pub const SENSITIVE_PATTERNS: &[&str] = &["*TOKEN*", "*SECRET*", "*PASSWORD*", "*KEY*"];
+
+/// Whether `name` matches any of `SENSITIVE_PATTERNS`
+pub fn is_sensitive_name(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    SENSITIVE_PATTERNS.iter().any(|p| glob_match(p, &name))
+}
+
+/// Stands in for a value in `Debug` output
+struct Redacted;
+
+const REDACTED: Redacted = Redacted;
+
+impl std::fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// A workload's settings as read from an `Enarx.toml` file, like:
+///
+/// ```toml
+/// invoke = "main"
+/// arg0 = "busybox"
+/// args = ["--verbose", "input.txt"]
+///
+/// [env]
+/// RUST_LOG = "debug"
+/// DB_PASS = "@file:/run/secrets/dbpass"
+///
+/// [stdio]
+/// stdin = "null"
+/// stdout = { file = "/tmp/out.log" }
+/// ```
+///
+/// Every key is optional, but unknown keys are an error.
+#[derive(Debug, Default)]
+pub struct WorkloadConfig {
+    pub env: EnvConfig,
+    pub invoke: Option<String>,
+}
+
+/// The on-disk layout of `Enarx.toml`
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct WorkloadFile {
+    invoke: Option<String>,
+    arg0: Option<String>,
+    args: Vec<String>,
+    env: std::collections::BTreeMap<String, String>,
+    stdio: StdioFile,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct StdioFile {
+    stdin: Option<ReadHandle>,
+    stdout: Option<WriteHandle>,
+    stderr: Option<WriteHandle>,
+}
+
+/// Problems reading an `Enarx.toml` file
+#[derive(Debug)]
+pub enum WorkloadConfigError {
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    /// An `[env]` value that looks like a secret, but isn't a valid one
+    Secret {
+        path: PathBuf,
+        name: String,
+        source: SecretParseError,
+    },
+}
+
+impl std::fmt::Display for WorkloadConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorkloadConfigError::Read { path, source } => {
+                write!(f, "could not read {}: {}", path.display(), source)
+            }
+            WorkloadConfigError::Parse { path, source } => {
+                write!(f, "invalid config {}: {}", path.display(), source.message())
+            }
+            WorkloadConfigError::Secret { path, name, source } => {
+                write!(
+                    f,
+                    "invalid config {}: env.{}: {}",
+                    path.display(),
+                    name,
+                    source
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for WorkloadConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WorkloadConfigError::Read { source, .. } => Some(source),
+            WorkloadConfigError::Parse { source, .. } => Some(source),
+            WorkloadConfigError::Secret { source, .. } => Some(source),
+        }
+    }
+}
+
+/// Problems reading a file for `EnvConfig::envs_from_file()`
+#[derive(Debug)]
+pub enum EnvFileError {
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// A line (counting from 1) that isn't a valid `NAME=VALUE`
+    Syntax {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for EnvFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvFileError::Read { path, source } => {
+                write!(f, "could not read {}: {}", path.display(), source)
+            }
+            EnvFileError::Syntax { path, line, reason } => {
+                write!(f, "{}:{}: {}", path.display(), line, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EnvFileError::Read { source, .. } => Some(source),
+            EnvFileError::Syntax { .. } => None,
+        }
+    }
+}
+
+/// Read a workload config file. See `WorkloadConfig` for the format.
+/// The `[env]` variables come out in order of name.
+pub fn load_workload_config(path: &Path) -> Result<WorkloadConfig, WorkloadConfigError> {
+    let text = std::fs::read_to_string(path).map_err(|source| WorkloadConfigError::Read {
+        path: path.into(),
+        source,
+    })?;
+    parse_workload_config(path, &text)
+}
+
+/// Parse the contents of the workload config file at `path`
+fn parse_workload_config(path: &Path, text: &str) -> Result<WorkloadConfig, WorkloadConfigError> {
+    let file: WorkloadFile = toml::from_str(text).map_err(|source| WorkloadConfigError::Parse {
+        path: path.into(),
+        source,
+    })?;
+    let mut env = EnvConfig {
+        arg0: file.arg0,
+        args: file.args,
+        stdin: file.stdio.stdin,
+        stdout: file.stdio.stdout,
+        stderr: file.stdio.stderr,
+        ..Default::default()
+    };
+    for (name, value) in file.env {
+        if let Err(source) = env.push_value(name.clone(), &value) {
+            return Err(WorkloadConfigError::Secret {
+                path: path.into(),
+                name,
+                source,
+            });
+        }
+    }
+    Ok(WorkloadConfig {
+        env,
+        invoke: file.invoke,
+    })
+}
+
+/// Problems found by `EnvConfig::validate()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvConfigError {
+    /// A variable name that's empty or contains `=` or NUL
+    InvalidName(String),
+    /// The name of a variable whose value contains NUL
+    InvalidValue(String),
+    /// An argument that contains NUL
+    InvalidArg(String),
+    /// A variable name that's set more than once
+    DuplicateKey(String),
+    /// A preopened host path that isn't an existing directory
+    NotADirectory(PathBuf),
+    /// A guest path (for a preopen or `cwd`) that isn't absolute
+    RelativeGuestPath(String),
+    /// A guest path that more than one preopen is using
+    DuplicateGuestPath(String),
+}
+
+impl std::fmt::Display for EnvConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvConfigError::InvalidName(name) => {
+                write!(f, "invalid environment variable name {:?}", name)
+            }
+            EnvConfigError::InvalidValue(name) => {
+                write!(
+                    f,
+                    "value of environment variable {} contains a NUL byte",
+                    name
+                )
+            }
+            EnvConfigError::InvalidArg(arg) => write!(f, "argument {:?} contains a NUL byte", arg),
+            EnvConfigError::DuplicateKey(name) => {
+                write!(f, "environment variable {} is set more than once", name)
+            }
+            EnvConfigError::NotADirectory(path) => {
+                write!(f, "{} is not a directory", path.display())
+            }
+            EnvConfigError::RelativeGuestPath(path) => {
+                write!(f, "guest path {:?} is not absolute", path)
+            }
+            EnvConfigError::DuplicateGuestPath(path) => {
+                write!(f, "guest path {:?} is used more than once", path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvConfigError {}
+
+/// Match `name` against a glob `pattern`, where `*` matches any run of
+/// characters (including none) and `?` matches any single character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume if we need to backtrack to the last '*'
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    p = sp + 1;
+                    n = sn + 1;
+                    star = Some((sp, sn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The `HOST:PORT` a socket handle connects to or listens on. HOST can be
+/// an address or a name; names aren't looked up until the handle is
+/// resolved, so parsing a handle never waits on DNS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPort {
+    pub host: String,
+    pub port: u16,
+}
+
+impl From<SocketAddr> for HostPort {
+    fn from(addr: SocketAddr) -> Self {
+        HostPort {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+        }
+    }
+}
+
+impl std::str::FromStr for HostPort {
+    type Err = HandleParseError;
+
+    /// Parse `HOST:PORT`, with an IPv6 HOST in brackets, like `[::1]:443`.
+    /// A name can only use letters, digits, `.`, `-` and `_`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |what: &str| Err(HandleParseError(format!("invalid {} in {:?}", what, s)));
+        let (host, port) = match s.rsplit_once(':') {
+            Some(parts) => parts,
+            None => return err("address"),
+        };
+        let host = match host.strip_prefix('[') {
+            Some(host) => match host.strip_suffix(']') {
+                Some(host) if host.parse::<std::net::Ipv6Addr>().is_ok() => host,
+                _ => return err("IPv6 address"),
+            },
+            None if host.contains(':') => return err("IPv6 address"),
+            None => {
+                let name_char = |c| char::is_ascii_alphanumeric(&c) || matches!(c, '.' | '-' | '_');
+                if host.is_empty() || !host.chars().all(name_char) {
+                    return err("host");
+                }
+                host
+            }
+        };
+        match port.parse::<u16>() {
+            Ok(port) => Ok(HostPort {
+                host: host.into(),
+                port,
+            }),
+            Err(_) => err("port"),
+        }
+    }
+}
+
+impl std::fmt::Display for HostPort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl std::net::ToSocketAddrs for HostPort {
+    type Iter = std::vec::IntoIter<SocketAddr>;
+
+    /// Look up the host if it's a name. Errors say which name it was.
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|e| io::Error::new(e.kind(), format!("couldn't look up {}: {}", self, e)))
+    }
+}
+
+impl Serialize for HostPort {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HostPort {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Where a workload's input comes from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadHandle {
+    Null,
+    Inherit(RawFd),
+    /// Connect to a TCP address
+    #[serde(alias = "plaintext_socket")]
+    Connect(HostPort),
+    /// Listen on a TCP address and take the first connection made to it,
+    /// waiting up to `timeout` seconds (or `DEFAULT_ACCEPT_TIMEOUT`) for it
+    Listen {
+        addr: HostPort,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+    },
+    /// Connect to a TCP address and talk TLS over it, as a client set up
+    /// by `TLSOptions::client_config()`: the server has to have a
+    /// certificate from one of the CAs in `tls.cacert` or one of the
+    /// `*.pem` files in the `tls.capath` directory, and `tls.cert` and
+    /// `tls.key` are sent if they're set. The certificate is checked
+    /// against `name`, or against the host in `addr` if there's no name.
+    TlsSocket {
+        addr: HostPort,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        tls: Box<TLSOptions>,
+    },
+    File(PathBuf),
+    /// An fd the caller opened for this, like one end of a pipe. Unlike
+    /// `Inherit`, the handle owns it, and it's closed when the last clone
+    /// of the handle goes away. It can't be serialized.
+    #[serde(skip)]
+    Pipe(std::sync::Arc<OwnedFd>),
+}
+
+impl ReadHandle {
+    /// Open the handle, giving a new fd that's ready to read from.
+    /// `File` is opened read-only and `Null` reads from `/dev/null`.
+    /// `Listen` blocks until something connects or the timeout runs out.
+    ///
+    /// For `TlsSocket`, the TLS handshake is done before this returns, and
+    /// the fd is the read end of a pipe. A background thread decrypts
+    /// whatever the server sends into the pipe until the server closes the
+    /// connection.
+    pub fn resolve(&self) -> io::Result<OwnedFd> {
+        match self {
+            ReadHandle::Null => Ok(File::open("/dev/null")?.into()),
+            ReadHandle::Inherit(fd) => dup_fd(*fd),
+            ReadHandle::Connect(addr) => Ok(TcpStream::connect(addr)?.into()),
+            ReadHandle::Listen { addr, timeout } => accept_one(addr, *timeout),
+            ReadHandle::TlsSocket { addr, name, tls } => {
+                let mut stream = tls_connect(addr, name.as_deref(), tls)?;
+                let (reader, writer) = pipe()?;
+                let what = self.to_string();
+                std::thread::Builder::new()
+                    .name("tls-recv".into())
+                    .spawn(move || {
+                        if let Err(e) = io::copy(&mut stream, &mut File::from(writer)) {
+                            warn!("reading from {} failed: {}", what, e);
+                        }
+                    })?;
+                Ok(reader)
+            }
+            ReadHandle::File(_) => Ok(self.open()?.into()),
+            ReadHandle::Pipe(fd) => fd.try_clone(),
+        }
+    }
+
+    /// The fd an `Inherit` or `Pipe` handle refers to
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        match self {
+            ReadHandle::Inherit(fd) => Some(*fd),
+            ReadHandle::Pipe(fd) => Some(fd.as_raw_fd()),
+            _ => None,
+        }
+    }
+
+    /// Open a `File` handle read-only. Other handles aren't files, so this
+    /// fails with `InvalidInput` for them.
+    pub fn open(&self) -> io::Result<File> {
+        match self {
+            ReadHandle::File(path) => File::open(path),
+            _ => Err(not_a_file(self)),
+        }
+    }
+}
+
+/// Where a workload's output goes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteHandle {
+    Null,
+    Inherit(RawFd),
+    /// Connect to a TCP address
+    #[serde(alias = "plaintext_socket")]
+    Connect(HostPort),
+    /// Listen on a TCP address; see `ReadHandle::Listen`
+    Listen {
+        addr: HostPort,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+    },
+    /// Connect to a TCP address and talk TLS over it; see
+    /// `ReadHandle::TlsSocket`
+    TlsSocket {
+        addr: HostPort,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        tls: Box<TLSOptions>,
+    },
+    File(PathBuf),
+    /// Like `File`, but append to the file rather than truncating it
+    Append(PathBuf),
+    /// An fd the caller opened for this; see `ReadHandle::Pipe`
+    #[serde(skip)]
+    Pipe(std::sync::Arc<OwnedFd>),
+    /// Copy everything written to both handles
+    Tee(Box<WriteHandle>, Box<WriteHandle>),
+}
+
+impl WriteHandle {
+    /// Open the handle, giving a new fd that's ready to write to.
+    /// `File` is created (or truncated) and `Null` writes to `/dev/null`.
+    /// `Listen` blocks until something connects or the timeout runs out.
+    ///
+    /// For `Tee`, both branches are resolved up front and the fd returned is
+    /// the write end of a pipe. A background thread copies whatever comes
+    /// out of the pipe to both branches until every copy of the fd is closed.
+    /// If writing to one branch fails, that's logged and the copy carries on
+    /// with the other one.
+    ///
+    /// `TlsSocket` works the same way: the handshake is done up front, and a
+    /// background thread encrypts whatever comes out of the pipe and sends
+    /// it to the server, then closes the TLS session cleanly at EOF.
+    ///
+    /// The background threads are left to finish on their own; use
+    /// `resolve_joinable()` to wait for them.
+    pub fn resolve(&self) -> io::Result<OwnedFd> {
+        self.resolve_joinable().map(|(fd, _threads)| fd)
+    }
+
+    /// Like `resolve()`, but also return the background threads that copy
+    /// to `Tee` and `TlsSocket` handles (including ones nested in a `Tee`),
+    /// so a caller that's shutting down can wait until everything written
+    /// has reached its destination.
+    pub fn resolve_joinable(&self) -> io::Result<(OwnedFd, CopyThreads)> {
+        let mut threads = CopyThreads::default();
+        let fd = match self {
+            WriteHandle::Null => OpenOptions::new().write(true).open("/dev/null")?.into(),
+            WriteHandle::Inherit(fd) => dup_fd(*fd)?,
+            WriteHandle::Connect(addr) => TcpStream::connect(addr)?.into(),
+            WriteHandle::Listen { addr, timeout } => accept_one(addr, *timeout)?,
+            WriteHandle::TlsSocket { addr, name, tls } => {
+                let stream = tls_connect(addr, name.as_deref(), tls)?;
+                let (reader, writer) = pipe()?;
+                let what = self.to_string();
+                let thread =
+                    std::thread::Builder::new()
+                        .name("tls-send".into())
+                        .spawn(move || {
+                            if let Err(e) = tls_send(reader, stream) {
+                                warn!("writing to {} failed: {}", what, e);
+                            }
+                        })?;
+                threads.0.push(thread);
+                writer
+            }
+            WriteHandle::File(_) | WriteHandle::Append(_) => self.open()?.into(),
+            WriteHandle::Pipe(fd) => fd.try_clone()?,
+            WriteHandle::Tee(a, b) => {
+                let (a_fd, a_threads) = a.resolve_joinable()?;
+                let (b_fd, b_threads) = b.resolve_joinable()?;
+                let branches = vec![(a.to_string(), a_fd), (b.to_string(), b_fd)];
+                let (reader, writer) = pipe()?;
+                let thread = std::thread::Builder::new()
+                    .name("tee".into())
+                    .spawn(move || tee(reader, branches))?;
+                threads.0.push(thread);
+                threads.0.extend(a_threads.0);
+                threads.0.extend(b_threads.0);
+                writer
+            }
+        };
+        Ok((fd, threads))
+    }
+}
+
+/// The background threads behind a resolved `WriteHandle`; see
+/// `WriteHandle::resolve_joinable()`. Dropping this leaves them running.
+#[derive(Debug, Default)]
+pub struct CopyThreads(Vec<std::thread::JoinHandle<()>>);
+
+impl CopyThreads {
+    /// Wait for every thread to finish copying. Each one runs until it
+    /// reads EOF, so every copy of the resolved fd has to be closed first,
+    /// or this waits forever.
+    pub fn join(self) {
+        for thread in self.0 {
+            let name = thread.thread().name().unwrap_or("copy").to_string();
+            if thread.join().is_err() {
+                warn!("{} thread panicked", name);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl WriteHandle {
+    /// The fd an `Inherit` or `Pipe` handle refers to
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        match self {
+            WriteHandle::Inherit(fd) => Some(*fd),
+            WriteHandle::Pipe(fd) => Some(fd.as_raw_fd()),
+            _ => None,
+        }
+    }
+
+    /// A `File` handle for `path`, or an `Append` one if `append` is set.
+    pub fn file(path: impl Into<PathBuf>, append: bool) -> Self {
+        match append {
+            true => WriteHandle::Append(path.into()),
+            false => WriteHandle::File(path.into()),
+        }
+    }
+
+    /// Open a `File` or `Append` handle for writing, creating the file if
+    /// it doesn't exist. Other handles aren't files, so this fails with
+    /// `InvalidInput` for them.
+    pub fn open(&self) -> io::Result<File> {
+        match self {
+            WriteHandle::File(path) => File::create(path),
+            WriteHandle::Append(path) => OpenOptions::new().append(true).create(true).open(path),
+            _ => Err(not_a_file(self)),
+        }
+    }
+}
+
+fn not_a_file(handle: &dyn std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} is not a file handle", handle),
+    )
+}
+
+/// Copy everything from `reader` to each of `branches` until EOF, dropping
+/// any branch that fails.
+fn tee(reader: OwnedFd, branches: Vec<(String, OwnedFd)>) {
+    use std::io::{Read, Write};
+
+    let mut reader = File::from(reader);
+    let mut branches: Vec<(String, File)> = branches
+        .into_iter()
+        .map(|(name, fd)| (name, fd.into()))
+        .collect();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("tee: read failed: {}", e);
+                return;
+            }
+        };
+        branches.retain_mut(|(name, out)| match out.write_all(&buf[..len]) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("tee: writing to {} failed, dropping it: {}", name, e);
+                false
+            }
+        });
+        if branches.is_empty() {
+            return;
+        }
+    }
+}
+
+type TlsStream = rustls::StreamOwned<rustls::ClientConnection, TcpStream>;
+
+/// Connect to `addr` and finish a TLS handshake with it, checking its
+/// certificate against `name` (or the host, if there's no name).
+/// Errors say which address they're about, and keep the original kind.
+fn tls_connect(addr: &HostPort, name: Option<&str>, tls: &TLSOptions) -> io::Result<TlsStream> {
+    let err = |kind, e: &dyn std::fmt::Display| {
+        io::Error::new(kind, format!("couldn't set up TLS to {}: {}", addr, e))
+    };
+    let config = tls
+        .client_config()
+        .map_err(|e| err(io::ErrorKind::InvalidInput, &e))?;
+    let name = name.unwrap_or(&addr.host);
+    let server_name = rustls::pki_types::ServerName::try_from(name.to_string())
+        .map_err(|e| err(io::ErrorKind::InvalidInput, &e))?;
+    let mut conn = rustls::ClientConnection::new(config.into(), server_name)
+        .map_err(|e| err(io::ErrorKind::Other, &e))?;
+    let mut sock = TcpStream::connect(addr)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock).map_err(|e| err(e.kind(), &e))?;
+    }
+    Ok(rustls::StreamOwned::new(conn, sock))
+}
+
+/// Send everything from `reader` over `stream` until EOF, then tell the
+/// server we're done.
+fn tls_send(reader: OwnedFd, mut stream: TlsStream) -> io::Result<()> {
+    use std::io::Write;
+
+    io::copy(&mut File::from(reader), &mut stream)?;
+    stream.conn.send_close_notify();
+    stream.flush()?;
+    // Closing a socket with unread data in it (like the session tickets
+    // TLS 1.3 servers send) resets the connection, and the server can lose
+    // the end of what we sent. So wait a little for the server to close
+    // its side first.
+    stream.sock.shutdown(std::net::Shutdown::Write)?;
+    stream.sock.set_read_timeout(Some(Duration::from_secs(5)))?;
+    io::copy(&mut stream.sock, &mut io::sink()).ok();
+    Ok(())
+}
+
+/// How long a `Listen` handle waits for a connection if it doesn't say
+pub const DEFAULT_ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Listen on `addr` and return the first connection made to it. Failing to
+/// bind and timing out get their own messages, since they mean very
+/// different things to whoever's on the other end.
+fn accept_one(addr: &HostPort, timeout: Option<u64>) -> io::Result<OwnedFd> {
+    let timeout = timeout.map_or(DEFAULT_ACCEPT_TIMEOUT, Duration::from_secs);
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| io::Error::new(e.kind(), format!("couldn't listen on {}: {}", addr, e)))?;
+    let mut pfd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    loop {
+        // SAFETY: pfd is a single valid pollfd and outlives the call
+        match unsafe { libc::poll(&mut pfd, 1, millis) } {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no connection to {} within {}s", addr, timeout.as_secs()),
+                ))
+            }
+            n if n > 0 => break,
+            _ => match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => continue,
+                e => return Err(e),
+            },
+        }
+    }
+    let (stream, peer) = listener.accept()?;
+    debug!("accepted connection from {} on {}", peer, addr);
+    Ok(stream.into())
+}
+
+/// Make a pipe, returning the (read, write) ends.
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: pipe2() fills in fds, which is the right size, and on success
+    // both fds are new and owned by nobody else.
+    unsafe {
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])))
+    }
+}
+
+/// A value that shouldn't end up in logs. Its `Debug` output is just
+/// `<redacted>`; use `expose()` to get at the real thing.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Secret(value)
+    }
+}
+
+impl<T> std::fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Redacted.fmt(f)
+    }
+}
+
+/// Where the value of a secret environment variable comes from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretSource {
+    /// The contents of a file, minus one trailing newline
+    File(PathBuf),
+    /// One of the host's environment variables
+    Env(String),
+    /// The output of a shell command, minus one trailing newline
+    Command(String),
+}
+
+impl SecretSource {
+    /// Look up the secret's value. Running a `Command` is an error unless
+    /// `allow_commands` is set.
+    pub fn resolve(&self, allow_commands: bool) -> Result<Secret<String>, SecretError> {
+        let mut value = match self {
+            SecretSource::File(path) => {
+                std::fs::read_to_string(path).map_err(|source| SecretError::Read {
+                    path: path.clone(),
+                    source,
+                })?
+            }
+            SecretSource::Env(name) => match std::env::var(name) {
+                Ok(value) => value,
+                Err(_) => return Err(SecretError::MissingEnv(name.clone())),
+            },
+            SecretSource::Command(command) if !allow_commands => {
+                return Err(SecretError::CommandsNotAllowed(command.clone()))
+            }
+            SecretSource::Command(command) => run_secret_command(command)?,
+        };
+        if value.ends_with('\n') {
+            value.pop();
+        }
+        Ok(Secret(value))
+    }
+}
+
+/// Run `command` with `sh -c`, giving back its stdout. stderr is left
+/// alone, so a password manager can still prompt or complain.
+fn run_secret_command(command: &str) -> Result<String, SecretError> {
+    let err = |reason: String| SecretError::Command {
+        command: command.into(),
+        reason,
+    };
+    let output = std::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|e| err(e.to_string()))?;
+    if !output.status.success() {
+        return Err(err(output.status.to_string()));
+    }
+    String::from_utf8(output.stdout).map_err(|_| err("output is not UTF-8".into()))
+}
+
+impl std::str::FromStr for SecretSource {
+    type Err = SecretParseError;
+
+    /// Parse `file:PATH`, `env:NAME` or `cmd:COMMAND` - that is, a secret
+    /// value with the leading `@` already taken off.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |msg: String| Err(SecretParseError(msg));
+        let (scheme, rest) = match s.split_once(':') {
+            Some(parts) => parts,
+            None => return err(format!("unknown secret {:?} (use @@ for a literal @)", s)),
+        };
+        if rest.is_empty() {
+            return err(format!("{} secret is empty", scheme));
+        }
+        match scheme {
+            "file" => Ok(SecretSource::File(rest.into())),
+            "env" => Ok(SecretSource::Env(rest.into())),
+            "cmd" => Ok(SecretSource::Command(rest.into())),
+            _ => err(format!(
+                "unknown secret scheme {:?} (use @@ for a literal @)",
+                scheme
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretSource::File(path) => write!(f, "@file:{}", path.display()),
+            SecretSource::Env(name) => write!(f, "@env:{}", name),
+            SecretSource::Command(command) => write!(f, "@cmd:{}", command),
+        }
+    }
+}
+
+/// A secret spec that couldn't be parsed; see `SecretSource::from_str()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretParseError(String);
+
+impl std::fmt::Display for SecretParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SecretParseError {}
+
+/// A variable whose value couldn't be expanded; see `EnvConfig::expand()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpandError {
+    /// The variable being set
+    pub var: String,
+    pub kind: ExpandErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExpandErrorKind {
+    /// A reference to a host variable that isn't set (or isn't UTF-8)
+    Undefined(String),
+    /// A `${` with no `}`
+    Unterminated,
+    /// Something between `${` and `}` that isn't a variable name
+    BadName(String),
+}
+
+impl std::fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "can't expand {}: ", self.var)?;
+        match &self.kind {
+            ExpandErrorKind::Undefined(name) => write!(
+                f,
+                "{} is not set (use ${{{}:-DEFAULT}} to allow that)",
+                name, name
+            ),
+            ExpandErrorKind::Unterminated => write!(f, "\"${{\" is missing its \"}}\""),
+            ExpandErrorKind::BadName(name) => write!(f, "{:?} is not a variable name", name),
+        }
+    }
+}
+
+impl std::error::Error for ExpandError {}
+
+/// Problems looking up a secret. None of these include the secret's value.
+#[derive(Debug)]
+pub enum SecretError {
+    /// The secret file couldn't be read
+    Read { path: PathBuf, source: io::Error },
+    /// The host environment variable isn't set, or isn't UTF-8
+    MissingEnv(String),
+    /// The secret is a command, and commands weren't allowed
+    CommandsNotAllowed(String),
+    /// The command couldn't be run, or failed
+    Command { command: String, reason: String },
+}
+
+impl std::fmt::Display for SecretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretError::Read { path, source } => {
+                write!(f, "could not read secret {}: {}", path.display(), source)
+            }
+            SecretError::MissingEnv(name) => {
+                write!(f, "secret environment variable {} is not set", name)
+            }
+            SecretError::CommandsNotAllowed(command) => {
+                write!(f, "secret command {:?} is not allowed", command)
+            }
+            SecretError::Command { command, reason } => {
+                write!(f, "secret command {:?} failed: {}", command, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SecretError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SecretError::Read { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// A handle spec that couldn't be parsed; see `ReadHandle::from_str()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandleParseError(String);
+
+impl std::fmt::Display for HandleParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for HandleParseError {}
+
+/// The parts of the handle syntax that ReadHandle and WriteHandle share
+enum HandleSpec<'a> {
+    Null,
+    Inherit(RawFd),
+    Connect(HostPort),
+    Listen(HostPort, Option<u64>),
+    Tls(HostPort, Option<String>, Box<TLSOptions>),
+    File(PathBuf),
+    Append(PathBuf),
+    Tee(&'a str),
+}
+
+impl<'a> HandleSpec<'a> {
+    fn parse(s: &'a str, default_fd: RawFd) -> Result<Self, HandleParseError> {
+        let err = |msg: String| Err(HandleParseError(msg));
+        if s == "null" {
+            return Ok(HandleSpec::Null);
+        }
+        if s == "inherit" {
+            return Ok(HandleSpec::Inherit(default_fd));
+        }
+        if let Some(rest) = s.strip_prefix("tcp-listen://") {
+            let (addr, timeout) = match rest.split_once("?timeout=") {
+                Some((addr, secs)) => match secs.parse::<u64>() {
+                    Ok(secs) => (addr, Some(secs)),
+                    Err(_) => {
+                        return err(format!(
+                            "tcp-listen timeout must be a number of seconds, not {:?}",
+                            secs
+                        ))
+                    }
+                },
+                None => (rest, None),
+            };
+            return Ok(HandleSpec::Listen(host_port("tcp-listen", addr)?, timeout));
+        }
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            return Ok(HandleSpec::Connect(host_port("tcp", addr)?));
+        }
+        if let Some(rest) = s.strip_prefix("tls://") {
+            return parse_tls(rest);
+        }
+        let (scheme, rest) = match s.split_once(':') {
+            Some(parts) => parts,
+            None => return err(format!("unknown handle {:?}", s)),
+        };
+        match scheme {
+            "inherit" => match rest.parse::<RawFd>() {
+                Ok(fd) if fd >= 0 => Ok(HandleSpec::Inherit(fd)),
+                _ => err(format!(
+                    "inherit handle requires a file descriptor, not {:?}",
+                    rest
+                )),
+            },
+            "file" if rest.is_empty() => err("file handle requires a path".into()),
+            "file" => Ok(HandleSpec::File(rest.into())),
+            "append" if rest.is_empty() => err("append handle requires a path".into()),
+            "append" => Ok(HandleSpec::Append(rest.into())),
+            "tee" => Ok(HandleSpec::Tee(rest)),
+            _ => err(format!("unknown handle scheme {:?}", scheme)),
+        }
+    }
+}
+
+/// Parse the part of a TLS handle after `tls://`: `HOST:PORT`, then
+/// optionally `?` and `&`-separated `name=`, `cacert=`, `capath=`, `cert=`
+/// and `key=` settings. Unless there's a `name=`, the server's certificate
+/// is checked against HOST.
+/// Values can use `%XX` escapes, so `%`, `&`, `=` and `?` in a path are
+/// written `%25`, `%26`, `%3D` and `%3F`.
+fn parse_tls(rest: &str) -> Result<HandleSpec<'_>, HandleParseError> {
+    let (addr, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let addr = host_port("tls", addr)?;
+    let mut name = None;
+    let mut tls = TLSOptions::default();
+    for setting in query.split('&').filter(|s| !s.is_empty()) {
+        let (key, value) = match setting.split_once('=') {
+            Some((key, value)) if !value.is_empty() => (key, value),
+            _ => {
+                return Err(HandleParseError(format!(
+                    "tls handle setting {:?} needs a value, like cacert=FILE",
+                    setting
+                )))
+            }
+        };
+        let value = match unescape_setting(value) {
+            Some(value) => value,
+            None => {
+                return Err(HandleParseError(format!(
+                    "invalid %-escape in tls handle setting {:?}",
+                    setting
+                )))
+            }
+        };
+        match key {
+            "name" => name = Some(value),
+            "cacert" => tls.cacert = Some(value.into()),
+            "capath" => tls.capath = Some(value.into()),
+            "cert" => tls.cert = Some(value.into()),
+            "key" => tls.key = Some(value.into()),
+            _ => {
+                return Err(HandleParseError(format!(
+                    "unknown tls handle setting {:?} (expected name, cacert, capath, cert or key)",
+                    key
+                )))
+            }
+        }
+    }
+    Ok(HandleSpec::Tls(addr, name, Box::new(tls)))
+}
+
+/// Parse the `host:port` part of a `kind` handle. Names are looked up
+/// later, by `resolve()`.
+fn host_port(kind: &str, addr: &str) -> Result<HostPort, HandleParseError> {
+    if !matches!(addr.rsplit_once(':'), Some((host, _)) if !host.is_empty()) {
+        return Err(HandleParseError(format!(
+            "{} handle requires host:port, not {:?}",
+            kind, addr
+        )));
+    }
+    addr.parse()
+        .map_err(|e| HandleParseError(format!("{} handle: {}", kind, e)))
+}
+
+impl ReadHandle {
+    /// Parse a handle spec, using `default_fd` for a bare `inherit`.
+    /// See `from_str()` for the syntax.
+    pub fn parse_with_fd(s: &str, default_fd: RawFd) -> Result<Self, HandleParseError> {
+        match HandleSpec::parse(s, default_fd)? {
+            HandleSpec::Null => Ok(ReadHandle::Null),
+            HandleSpec::Inherit(fd) => Ok(ReadHandle::Inherit(fd)),
+            HandleSpec::Connect(addr) => Ok(ReadHandle::Connect(addr)),
+            HandleSpec::Listen(addr, timeout) => Ok(ReadHandle::Listen { addr, timeout }),
+            HandleSpec::Tls(addr, name, tls) => Ok(ReadHandle::TlsSocket { addr, name, tls }),
+            HandleSpec::File(path) => Ok(ReadHandle::File(path)),
+            HandleSpec::Append(_) => Err(HandleParseError(
+                "can't read from an append handle; use file:PATH".into(),
+            )),
+            HandleSpec::Tee(_) => Err(HandleParseError("can't read from a tee handle".into())),
+        }
+    }
+}
+
+impl std::str::FromStr for ReadHandle {
+    type Err = HandleParseError;
+
+    /// Parse `null`, `inherit` (stdin), `inherit:FD`, `file:PATH`,
+    /// `tcp://HOST:PORT`, `tcp-listen://HOST:PORT[?timeout=SECS]` or
+    /// `tls://HOST:PORT[?cacert=FILE&capath=DIR&cert=FILE&key=FILE&name=NAME]`,
+    /// where the values can use `%XX` escapes. Host names are looked up when
+    /// the handle is resolved, not here.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with_fd(s, 0)
+    }
+}
+
+impl WriteHandle {
+    /// Parse a handle spec, using `default_fd` for a bare `inherit`.
+    /// See `from_str()` for the syntax.
+    pub fn parse_with_fd(s: &str, default_fd: RawFd) -> Result<Self, HandleParseError> {
+        match HandleSpec::parse(s, default_fd)? {
+            HandleSpec::Null => Ok(WriteHandle::Null),
+            HandleSpec::Inherit(fd) => Ok(WriteHandle::Inherit(fd)),
+            HandleSpec::Connect(addr) => Ok(WriteHandle::Connect(addr)),
+            HandleSpec::Listen(addr, timeout) => Ok(WriteHandle::Listen { addr, timeout }),
+            HandleSpec::Tls(addr, name, tls) => Ok(WriteHandle::TlsSocket { addr, name, tls }),
+            HandleSpec::File(path) => Ok(WriteHandle::File(path)),
+            HandleSpec::Append(path) => Ok(WriteHandle::Append(path)),
+            HandleSpec::Tee(rest) => {
+                let (a, b) = split_tee(rest)?;
+                Ok(WriteHandle::Tee(
+                    Box::new(Self::parse_with_fd(a, default_fd)?),
+                    Box::new(Self::parse_with_fd(b, default_fd)?),
+                ))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for WriteHandle {
+    type Err = HandleParseError;
+
+    /// Parse `null`, `inherit` (stdout), `inherit:FD`, `file:PATH`,
+    /// `append:PATH`, `tcp://HOST:PORT`, `tcp-listen://HOST:PORT[?timeout=SECS]`,
+    /// `tls://HOST:PORT[?SETTINGS]` (see `ReadHandle::from_str()`) or
+    /// `tee:HANDLE,HANDLE`. The first half of a tee ends at the first comma
+    /// unless it's in braces, so `tee:inherit,file:a,b.log` works as it is,
+    /// but a first half with a comma in it (including another tee) needs
+    /// them: `tee:{file:a,b.log},inherit` or `tee:{tee:null,inherit},null`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with_fd(s, 1)
+    }
+}
+
+/// Split the part of a tee handle after `tee:` into its two halves; see
+/// `WriteHandle::from_str()`. Braces nest, so a braced first half can
+/// contain braced tees of its own, but not unmatched braces.
+fn split_tee(rest: &str) -> Result<(&str, &str), HandleParseError> {
+    let err = |msg: String| Err(HandleParseError(msg));
+    let inner = match rest.strip_prefix('{') {
+        Some(inner) => inner,
+        None => {
+            return match rest.split_once(',') {
+                Some((a, _)) if a.starts_with("tee:") => err(format!(
+                    "a tee in the first half of a tee has to be in braces, like tee:{{{}}},...",
+                    a
+                )),
+                Some(parts) => Ok(parts),
+                None => err(format!("tee handle requires two handles, not {:?}", rest)),
+            }
+        }
+    };
+    let mut depth = 1;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 1 => {
+                return match inner[i + 1..].strip_prefix(',') {
+                    Some(b) => Ok((&inner[..i], b)),
+                    None => err(format!(
+                        "tee handle requires a comma after {{{}}}",
+                        &inner[..i]
+                    )),
+                }
+            }
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    err(format!("unmatched {{ in tee handle {:?}", rest))
+}
+
+/// Shows the handle as a spec that `from_str()` parses back to the same
+/// handle. `Pipe` is the exception: an fd the caller opened can't be named
+/// in a spec, so it's shown as `<pipe fd N>`, which no spec matches.
+impl std::fmt::Display for ReadHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadHandle::Null => write!(f, "null"),
+            ReadHandle::Inherit(fd) => write!(f, "inherit:{}", fd),
+            ReadHandle::Connect(addr) => write!(f, "tcp://{}", addr),
+            ReadHandle::Listen { addr, timeout } => fmt_listen(f, addr, *timeout),
+            ReadHandle::TlsSocket { addr, name, tls } => fmt_tls(f, addr, name, tls),
+            ReadHandle::File(path) => write!(f, "file:{}", path.display()),
+            ReadHandle::Pipe(fd) => write!(f, "<pipe fd {}>", fd.as_raw_fd()),
+        }
+    }
+}
+
+/// Shows the handle as a spec, like `ReadHandle`, apart from `Pipe`,
+/// which is shown as `<pipe fd N>`.
+impl std::fmt::Display for WriteHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteHandle::Null => write!(f, "null"),
+            WriteHandle::Inherit(fd) => write!(f, "inherit:{}", fd),
+            WriteHandle::Connect(addr) => write!(f, "tcp://{}", addr),
+            WriteHandle::Listen { addr, timeout } => fmt_listen(f, addr, *timeout),
+            WriteHandle::TlsSocket { addr, name, tls } => fmt_tls(f, addr, name, tls),
+            WriteHandle::File(path) => write!(f, "file:{}", path.display()),
+            WriteHandle::Append(path) => write!(f, "append:{}", path.display()),
+            WriteHandle::Pipe(fd) => write!(f, "<pipe fd {}>", fd.as_raw_fd()),
+            WriteHandle::Tee(a, b) => {
+                let a = a.to_string();
+                if a.contains(',') || a.starts_with('{') {
+                    write!(f, "tee:{{{}}},{}", a, b)
+                } else {
+                    write!(f, "tee:{},{}", a, b)
+                }
+            }
+        }
+    }
+}
+
+fn fmt_listen(
+    f: &mut std::fmt::Formatter<'_>,
+    addr: &HostPort,
+    timeout: Option<u64>,
+) -> std::fmt::Result {
+    write!(f, "tcp-listen://{}", addr)?;
+    match timeout {
+        Some(secs) => write!(f, "?timeout={}", secs),
+        None => Ok(()),
+    }
+}
+
+fn fmt_tls(
+    f: &mut std::fmt::Formatter<'_>,
+    addr: &HostPort,
+    name: &Option<String>,
+    tls: &TLSOptions,
+) -> std::fmt::Result {
+    write!(f, "tls://{}", addr)?;
+    let paths = [
+        ("cacert", &tls.cacert),
+        ("capath", &tls.capath),
+        ("cert", &tls.cert),
+        ("key", &tls.key),
+    ];
+    let settings = (name.iter())
+        .map(|name| format!("name={}", escape_setting(name)))
+        .chain(paths.iter().filter_map(|(key, path)| {
+            let path = path.as_ref()?.display().to_string();
+            Some(format!("{}={}", key, escape_setting(&path)))
+        }));
+    for (i, setting) in settings.enumerate() {
+        write!(f, "{}{}", if i == 0 { '?' } else { '&' }, setting)?;
+    }
+    Ok(())
+}
+
+/// Escape the characters that mean something in a tls handle's settings
+fn escape_setting(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '%' | '&' | '=' | '?' => escaped.push_str(&format!("%{:02X}", c as u8)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undo `escape_setting()`, or any other `%XX` escapes. `None` if an
+/// escape isn't two hex digits or the result isn't UTF-8.
+fn unescape_setting(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            unescaped.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            unescaped.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(unescaped).ok()
+}
+
+/// dup() an inherited fd, so the caller gets one it owns.
+fn dup_fd(fd: RawFd) -> io::Result<OwnedFd> {
+    if fd < 0 {
+        return Err(io::Error::from_raw_os_error(libc::EBADF));
+    }
+    // SAFETY: we only borrow the fd long enough to duplicate it; if it's not
+    // open, the dup fails with EBADF and nothing else is touched.
+    unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()
+}
+
+/// Settings for the WebAssembly runtime
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasmConfig {
+    #[serde(with = "wasm_features")]
+    pub features: WasmFeatures,
+    /// The most linear memory the workload can use, in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_memory_bytes: Option<u64>,
+    /// The most elements any one table can grow to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_table_elements: Option<u32>,
+    /// How much fuel the workload gets; it's stopped when it runs out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fuel: Option<u64>,
+}
+
+/// The size of a WebAssembly memory page, which is the smallest memory
+/// limit that makes sense
+pub const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// A serializable mirror of `wasmparser::WasmFeatures`. Any feature that's
+/// left out gets its `WasmFeatures::default()` value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasmFeatureFlags {
+    pub reference_types: bool,
+    pub multi_value: bool,
+    pub bulk_memory: bool,
+    pub module_linking: bool,
+    pub simd: bool,
+    pub threads: bool,
+    pub tail_call: bool,
+    pub deterministic_only: bool,
+    pub multi_memory: bool,
+    pub exceptions: bool,
+    pub memory64: bool,
+}
+
+impl Default for WasmFeatureFlags {
+    fn default() -> Self {
+        WasmFeatures::default().into()
+    }
+}
+
+impl From<WasmFeatures> for WasmFeatureFlags {
+    fn from(f: WasmFeatures) -> Self {
+        Self {
+            reference_types: f.reference_types,
+            multi_value: f.multi_value,
+            bulk_memory: f.bulk_memory,
+            module_linking: f.module_linking,
+            simd: f.simd,
+            threads: f.threads,
+            tail_call: f.tail_call,
+            deterministic_only: f.deterministic_only,
+            multi_memory: f.multi_memory,
+            exceptions: f.exceptions,
+            memory64: f.memory64,
+        }
+    }
+}
+
+impl From<WasmFeatureFlags> for WasmFeatures {
+    fn from(f: WasmFeatureFlags) -> Self {
+        Self {
+            reference_types: f.reference_types,
+            multi_value: f.multi_value,
+            bulk_memory: f.bulk_memory,
+            module_linking: f.module_linking,
+            simd: f.simd,
+            threads: f.threads,
+            tail_call: f.tail_call,
+            deterministic_only: f.deterministic_only,
+            multi_memory: f.multi_memory,
+            exceptions: f.exceptions,
+            memory64: f.memory64,
+        }
+    }
+}
+
+impl WasmFeatureFlags {
+    /// The names accepted by `FromStr`, matching the proposal names that
+    /// wasmtime uses for its `--wasm-features` flag.
+    pub const NAMES: &'static [&'static str] = &[
+        "reference-types",
+        "multi-value",
+        "bulk-memory",
+        "module-linking",
+        "simd",
+        "threads",
+        "tail-call",
+        "deterministic-only",
+        "multi-memory",
+        "exceptions",
+        "memory64",
+    ];
+
+    /// The features `preset` turns on
+    pub fn preset(preset: WasmPreset) -> Self {
+        match preset {
+            WasmPreset::Strict => Self {
+                reference_types: false,
+                multi_value: false,
+                bulk_memory: false,
+                module_linking: false,
+                simd: false,
+                threads: false,
+                tail_call: false,
+                deterministic_only: false,
+                multi_memory: false,
+                exceptions: false,
+                memory64: false,
+            },
+            WasmPreset::Default => Self::default(),
+            // deterministic-only restricts what's allowed rather than
+            // adding anything, so it's not part of "everything"
+            WasmPreset::Experimental => Self {
+                reference_types: true,
+                multi_value: true,
+                bulk_memory: true,
+                module_linking: true,
+                simd: true,
+                threads: true,
+                tail_call: true,
+                deterministic_only: false,
+                multi_memory: true,
+                exceptions: true,
+                memory64: true,
+            },
+        }
+    }
+
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "reference-types" => Some(&mut self.reference_types),
+            "multi-value" => Some(&mut self.multi_value),
+            "bulk-memory" => Some(&mut self.bulk_memory),
+            "module-linking" => Some(&mut self.module_linking),
+            "simd" => Some(&mut self.simd),
+            "threads" => Some(&mut self.threads),
+            "tail-call" => Some(&mut self.tail_call),
+            "deterministic-only" => Some(&mut self.deterministic_only),
+            "multi-memory" => Some(&mut self.multi_memory),
+            "exceptions" => Some(&mut self.exceptions),
+            "memory64" => Some(&mut self.memory64),
+            _ => None,
+        }
+    }
+}
+
+/// A feature name that `WasmFeatureFlags` doesn't know about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownFeature(pub String);
+
+impl std::fmt::Display for UnknownFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unknown WebAssembly feature {:?} (valid features: {})",
+            self.0,
+            WasmFeatureFlags::NAMES.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownFeature {}
+
+impl std::str::FromStr for WasmFeatureFlags {
+    type Err = UnknownFeature;
+
+    /// Parse a comma-separated list of features like `simd,-bulk-memory`,
+    /// applied to the defaults (`reference-types`, `multi-value` and
+    /// `bulk-memory`, the same set wasmtime enables by default). See
+    /// `WasmFeatureChanges` for the syntax.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.parse::<WasmFeatureChanges>()?
+            .apply_to(WasmFeatureFlags::default()))
+    }
+}
+
+/// A named set of WebAssembly features, so nobody has to remember the
+/// proposal names to get a sensible configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WasmPreset {
+    /// The MVP: no post-MVP proposals at all
+    Strict,
+    /// The proposals wasmtime enables by default
+    #[default]
+    Default,
+    /// Every proposal this version of wasmparser knows about
+    Experimental,
+}
+
+impl std::str::FromStr for WasmPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(WasmPreset::Strict),
+            "default" => Ok(WasmPreset::Default),
+            "experimental" => Ok(WasmPreset::Experimental),
+            _ => Err(format!(
+                "unknown WebAssembly profile {:?} (expected strict, default or experimental)",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for WasmPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WasmPreset::Strict => "strict",
+            WasmPreset::Default => "default",
+            WasmPreset::Experimental => "experimental",
+        })
+    }
+}
+
+/// Features to turn on or off, parsed from a comma-separated list like
+/// `simd,-bulk-memory`. `name` or `+name` turns a feature on and `-name`
+/// turns it off; later items win.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WasmFeatureChanges(Vec<(String, bool)>);
+
+impl WasmFeatureChanges {
+    /// Apply the changes, in order, to `flags`
+    pub fn apply_to(&self, mut flags: WasmFeatureFlags) -> WasmFeatureFlags {
+        for (name, enable) in &self.0 {
+            // Names were checked when the changes were parsed
+            if let Some(flag) = flags.flag_mut(name) {
+                *flag = *enable;
+            }
+        }
+        flags
+    }
+}
+
+impl std::str::FromStr for WasmFeatureChanges {
+    type Err = UnknownFeature;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut changes = vec![];
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (name, enable) = match item.strip_prefix('-') {
+                Some(name) => (name, false),
+                None => (item.strip_prefix('+').unwrap_or(item), true),
+            };
+            if !WasmFeatureFlags::NAMES.contains(&name) {
+                return Err(UnknownFeature(name.into()));
+            }
+            changes.push((name.to_string(), enable));
+        }
+        Ok(Self(changes))
+    }
+}
+
+/// Parse a size like `4096`, `64KiB`, `1.5GiB` or `100MB`.
+///
+/// Units are case-insensitive. `KiB`, `MiB`, `GiB` and `TiB` are powers of
+/// 1024, and so are the bare `K`, `M`, `G` and `T`; `KB`, `MB`, `GB` and
+/// `TB` are powers of 1000. `B` or no unit at all means bytes. Whitespace
+/// between the number and the unit is fine, so a quoted `"1 GiB"` works.
+///
+/// Fractions need a unit, and are rounded down to a whole number of bytes.
+/// Thousands separators (`1,024` or `1_024`) are rejected rather than
+/// guessed at, since `1,5GiB` means 1.5GiB in much of the world.
+pub fn parse_size(s: &str) -> Result<u64, SizeParseError> {
+    let err = |kind| SizeParseError {
+        input: s.into(),
+        kind,
+    };
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    if number.is_empty() {
+        return Err(err(SizeErrorKind::NoNumber));
+    }
+    if let Some(sep) = unit.chars().next().filter(|c| *c == ',' || *c == '_') {
+        return Err(err(SizeErrorKind::Separator(sep)));
+    }
+
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    let digits = |d: &str| !d.is_empty() && d.bytes().all(|b| b.is_ascii_digit());
+    if !digits(whole) || !(fraction.is_empty() || digits(fraction)) || number.ends_with('.') {
+        return Err(err(SizeErrorKind::BadNumber(number.into())));
+    }
+
+    let unit = unit.trim_start();
+    let multiplier: u128 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => return Err(err(SizeErrorKind::UnknownUnit(unit.into()))),
+    };
+    if multiplier == 1 && !fraction.is_empty() {
+        return Err(err(SizeErrorKind::FractionalBytes));
+    }
+
+    let whole: u128 = whole.parse().map_err(|_| err(SizeErrorKind::TooBig))?;
+    // Digits past the 18th can't change the result by a whole byte
+    let fraction = &fraction[..fraction.len().min(18)];
+    let scale = 10u128.pow(fraction.len() as u32);
+    let fraction: u128 = fraction.parse().unwrap_or(0);
+    whole
+        .checked_mul(multiplier)
+        .and_then(|bytes| bytes.checked_add(fraction * multiplier / scale))
+        .and_then(|bytes| u64::try_from(bytes).ok())
+        .ok_or_else(|| err(SizeErrorKind::TooBig))
+}
+
+/// A size that `parse_size()` couldn't make sense of
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeParseError {
+    /// The whole string that was being parsed
+    pub input: String,
+    pub kind: SizeErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SizeErrorKind {
+    /// Nothing, or something other than a number, at the start
+    NoNumber,
+    /// Digits and dots that don't make a number, like `1.2.3`
+    BadNumber(String),
+    /// A thousands separator, like the `,` in `1,024`
+    Separator(char),
+    /// A suffix that isn't one of the known units
+    UnknownUnit(String),
+    /// A fraction without a unit, like `1.5`
+    FractionalBytes,
+    /// More than `u64::MAX` bytes
+    TooBig,
+}
+
+impl std::fmt::Display for SizeParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid size {:?}: ", self.input)?;
+        match &self.kind {
+            SizeErrorKind::NoNumber => write!(f, "it should start with a number, like 64KiB"),
+            SizeErrorKind::BadNumber(number) => write!(f, "{:?} is not a number", number),
+            SizeErrorKind::Separator(sep) => match separator_fix(&self.input, *sep) {
+                Some(fixed) => write!(
+                    f,
+                    "leave out the {:?} separators (did you mean {}?)",
+                    sep, fixed
+                ),
+                None => write!(
+                    f,
+                    "leave out any {:?} separators, and use '.' for a fraction",
+                    sep
+                ),
+            },
+            SizeErrorKind::UnknownUnit(unit) => write!(
+                f,
+                "unknown unit {:?} (try B, KiB, MiB, GiB, TiB, KB, MB, GB or TB)",
+                unit
+            ),
+            SizeErrorKind::FractionalBytes => write!(f, "can't have a fraction of a byte"),
+            SizeErrorKind::TooBig => write!(f, "that's more than {} bytes", u64::MAX),
+        }
+    }
+}
+
+impl std::error::Error for SizeParseError {}
+
+/// `input` without its `sep` separators, if that's certainly what was meant:
+/// every separator has to be followed by exactly three digits, and a `,`
+/// can't be followed by a unit, since `1,500MB` could mean 1.5MB.
+fn separator_fix(input: &str, sep: char) -> Option<String> {
+    let input = input.trim();
+    let end = (input.find(|c: char| !(c.is_ascii_digit() || c == sep))).unwrap_or(input.len());
+    let (number, rest) = input.split_at(end);
+    let mut groups = number.split(sep);
+    let first = groups.next()?;
+    let grouped = (1..=3).contains(&first.len()) && groups.all(|g| g.len() == 3);
+    let mixed = rest.starts_with([',', '_']);
+    if !grouped || mixed || (sep == ',' && !rest.is_empty()) {
+        return None;
+    }
+    Some(input.replace(sep, ""))
+}
+
+/// (De)serialize `WasmFeatures` by way of `WasmFeatureFlags`
+mod wasm_features {
+    use super::WasmFeatureFlags;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use wasmparser::WasmFeatures;
+
+    pub fn serialize<S: Serializer>(f: &WasmFeatures, serializer: S) -> Result<S::Ok, S::Error> {
+        WasmFeatureFlags::from(*f).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<WasmFeatures, D::Error> {
+        WasmFeatureFlags::deserialize(deserializer).map(Into::into)
+    }
+}
+
+impl WasmConfig {
+    /// Make a config with the features of `preset`
+    pub fn preset(preset: WasmPreset) -> Self {
+        Self {
+            features: WasmFeatureFlags::preset(preset).into(),
+            ..Default::default()
+        }
+    }
+
+    /// Make a config with the features given by a string like
+    /// `simd,-bulk-memory`; see `WasmFeatureFlags::from_str()`.
+    pub fn from_feature_str(features: &str) -> Result<Self, UnknownFeature> {
+        Ok(Self {
+            features: features.parse::<WasmFeatureFlags>()?.into(),
+            ..Default::default()
+        })
+    }
+
+    pub fn max_memory_bytes(mut self, bytes: u64) -> Self {
+        self.max_memory_bytes = Some(bytes);
+        self
+    }
+
+    pub fn max_table_elements(mut self, elements: u32) -> Self {
+        self.max_table_elements = Some(elements);
+        self
+    }
+
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Check that `module` is a valid WebAssembly module using these features.
+    pub fn validate(&self, module: &[u8]) -> Result<(), wasmparser::BinaryReaderError> {
+        Validator::new()
+            .wasm_features(self.features)
+            .validate_all(module)
+    }
+}
+
+/// Entry points for the fuzz targets in `fuzz/`, for things that aren't
+/// otherwise public
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzzing {
+    use super::*;
+
+    pub fn parse_workload_config(text: &str) -> Result<WorkloadConfig, WorkloadConfigError> {
+        super::parse_workload_config(Path::new("Enarx.toml"), text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use std::env::{remove_var, set_var};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("RUST_*", "RUST_LOG"));
+        assert!(glob_match("RUST_*", "RUST_"));
+        assert!(!glob_match("RUST_*", "RUSTY"));
+        assert!(glob_match("*_SECRET", "DB_SECRET"));
+        assert!(!glob_match("*_SECRET", "DB_SECRETS"));
+        assert!(glob_match("*_*_*", "A_B_C"));
+        assert!(glob_match("?OME", "HOME"));
+        assert!(!glob_match("?OME", "OME"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("PATH", "PATH"));
+        assert!(!glob_match("PATH", "PATHS"));
+    }
+
+    #[test]
+    #[serial]
+    fn inherit_env_filtered() {
+        set_var("ENARX_TEST_INHERIT_B", "b");
+        set_var("ENARX_TEST_INHERIT_A", "a");
+        set_var("ENARX_TEST_INHERIT_SECRET", "hunter2");
+        set_var("ENARX_TEST_INHERIT_EXPLICIT", "host");
+        let config = EnvConfig::default()
+            .env("ENARX_TEST_INHERIT_EXPLICIT", "before")
+            .inherit_env_filtered(&["ENARX_TEST_INHERIT_*"], &["*_SECRET"])
+            .env("ENARX_TEST_INHERIT_A", "after");
+        assert_eq!(
+            config.envs,
+            vec![
+                ("ENARX_TEST_INHERIT_B".to_string(), "b".to_string()),
+                (
+                    "ENARX_TEST_INHERIT_EXPLICIT".to_string(),
+                    "before".to_string()
+                ),
+                ("ENARX_TEST_INHERIT_A".to_string(), "after".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn inherit_env_then_override() {
+        set_var("ENARX_TEST_INHERIT_ALL", "host");
+        let config = EnvConfig::default()
+            .inherit_env()
+            .env("ENARX_TEST_INHERIT_ALL", "explicit");
+        let values: Vec<&str> = (config.envs.iter())
+            .filter(|(k, _)| k == "ENARX_TEST_INHERIT_ALL")
+            .map(|(_, v)| v.as_str())
+            .collect();
+        assert_eq!(values, vec!["explicit"]);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn clear_env() {
+        set_var("ENARX_TEST_CLEAR", "host");
+        let mut config = EnvConfig::default().env("BEFORE", "1");
+        config.push_value("TOKEN", "@env:X").unwrap();
+        config.push_secret("PASSWORD", "hunter2");
+        let config = config.clear_env();
+        assert!(config.envs.is_empty());
+        assert!(config.secrets.is_empty());
+        assert!(config.sensitive.is_empty());
+        assert_eq!(config.env_cleared, Some(true));
+
+        let config = config.inherit_env().env("AFTER", "2").inherit_env();
+        assert_eq!(config.envs, vec![("AFTER".to_string(), "2".to_string())]);
+
+        let config = config
+            .allow_inherit_env()
+            .inherit_env_filtered(&["ENARX_TEST_CLEAR"], &[]);
+        assert_eq!(config.envs.len(), 2);
+
+        // The flag survives a merge from either side, and a later layer
+        // can turn it off again
+        let cleared = || EnvConfig::default().clear_env();
+        let merged = cleared().merge(EnvConfig::default());
+        assert_eq!(merged.env_cleared, Some(true));
+        let merged = EnvConfig::default().merge(cleared());
+        assert_eq!(merged.env_cleared, Some(true));
+        let merged = cleared().merge(EnvConfig::default().allow_inherit_env());
+        assert_eq!(merged.env_cleared, Some(false));
+    }
+
+    #[test]
+    #[serial]
+    fn only_env() {
+        set_var("ENARX_TEST_ONLY_A", "a");
+        set_var("ENARX_TEST_ONLY_B", "b");
+        let mut config = EnvConfig::default().env("KEEP", "1").env("DROP", "2");
+        config.push_value("TOKEN", "@env:X").unwrap();
+        let config = config
+            .inherit_env_filtered(&["ENARX_TEST_ONLY_*"], &[])
+            .only_env(&["KEEP", "ENARX_TEST_ONLY_B", "NOT_SET"]);
+        assert_eq!(
+            config.envs,
+            vec![
+                ("ENARX_TEST_ONLY_B".to_string(), "b".to_string()),
+                ("KEEP".to_string(), "1".to_string()),
+            ]
+        );
+        assert!(config.secrets.is_empty());
+        assert!(EnvConfig::default()
+            .env("X", "1")
+            .only_env(&[])
+            .envs
+            .is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn inherit_env_skips_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        set_var("ENARX_TEST_UTF8_OK", "ok");
+        set_var("ENARX_TEST_UTF8_BAD", OsStr::from_bytes(b"\xff\xfe"));
+        let config = EnvConfig::default().inherit_env_filtered(&["ENARX_TEST_UTF8_*"], &[]);
+        remove_var("ENARX_TEST_UTF8_BAD");
+        assert_eq!(
+            config.envs,
+            vec![("ENARX_TEST_UTF8_OK".to_string(), "ok".to_string())]
+        );
+    }
+
+    #[test]
+    fn resolve_null_reads_eof() {
+        let mut null = File::from(ReadHandle::Null.resolve().unwrap());
+        let mut buf = Vec::new();
+        assert_eq!(null.read_to_end(&mut buf).unwrap(), 0);
+
+        let mut null = File::from(WriteHandle::Null.resolve().unwrap());
+        null.write_all(b"discarded").unwrap();
+    }
+
+    #[test]
+    fn resolve_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        std::fs::write(&path, "old contents that should be truncated").unwrap();
+
+        let mut out = File::from(WriteHandle::File(path.clone()).resolve().unwrap());
+        out.write_all(b"hello").unwrap();
+        drop(out);
+
+        let mut input = File::from(ReadHandle::File(path).resolve().unwrap());
+        let mut buf = String::new();
+        input.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "hello");
+    }
+
+    #[test]
+    fn open_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let env = EnvConfig::default()
+            .stdin_from_file(&path)
+            .stdout_to_file(&path, true)
+            .stderr_to_file(&path, false);
+        let (stdin, stdout, stderr) =
+            (env.stdin.unwrap(), env.stdout.unwrap(), env.stderr.unwrap());
+        // Nothing's opened until asked for
+        assert!(!path.exists());
+
+        stderr.open().unwrap().write_all(b"one ").unwrap();
+        File::from(stdout.resolve().unwrap())
+            .write_all(b"two")
+            .unwrap();
+        let mut buf = String::new();
+        stdin.open().unwrap().read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "one two");
+
+        let err = WriteHandle::Null.open().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = ReadHandle::Inherit(0).open().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn resolve_file_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let err = ReadHandle::File(missing.clone()).resolve().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = WriteHandle::File(missing.join("out"))
+            .resolve()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let locked = dir.path().join("locked");
+        std::fs::write(&locked, "").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // root (and CAP_DAC_OVERRIDE) ignores file modes, so there's nothing to check
+        if File::open(&locked).is_ok() {
+            return;
+        }
+        let err = ReadHandle::File(locked.clone()).resolve().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = WriteHandle::File(locked).resolve().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn resolve_inherit() {
+        let file = tempfile::tempfile().unwrap();
+        let fd = ReadHandle::Inherit(file.as_raw_fd()).resolve().unwrap();
+        assert_ne!(fd.as_raw_fd(), file.as_raw_fd());
+
+        let err = WriteHandle::Inherit(-1).resolve().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn resolve_socket() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut sock = File::from(WriteHandle::Connect(addr.into()).resolve().unwrap());
+        let (mut conn, _) = listener.accept().unwrap();
+        sock.write_all(b"ping").unwrap();
+        drop(sock);
+        let mut buf = String::new();
+        conn.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "ping");
+
+        // Names are looked up here, not when the handle is parsed
+        let handle: WriteHandle = "tcp://no-such-host.invalid:80".parse().unwrap();
+        let err = handle.resolve().unwrap_err();
+        assert!(err.to_string().starts_with("couldn't look up"), "{}", err);
+    }
+
+    #[test]
+    fn resolve_listen() {
+        // Find a free port, then let the handle have it
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let handle = ReadHandle::Listen {
+            addr: addr.into(),
+            timeout: Some(10),
+        };
+        let accepted = std::thread::spawn(move || handle.resolve());
+        let mut sock = loop {
+            match TcpStream::connect(addr) {
+                Ok(sock) => break sock,
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        sock.write_all(b"ping").unwrap();
+        drop(sock);
+        let mut buf = String::new();
+        File::from(accepted.join().unwrap().unwrap())
+            .read_to_string(&mut buf)
+            .unwrap();
+        assert_eq!(buf, "ping");
+    }
+
+    #[test]
+    fn resolve_listen_errors() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        let handle = WriteHandle::Listen {
+            addr: addr.into(),
+            timeout: Some(0),
+        };
+        let err = handle.resolve().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(err.to_string().starts_with("couldn't listen on"));
+
+        drop(taken);
+        let err = handle.resolve().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.to_string().starts_with("no connection to"));
+    }
+
+    /// Write a fresh self-signed cert and its key into `dir` as
+    /// `{name}.pem` and `{name}.key`.
+    fn write_cert(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let names = vec!["localhost".into(), "127.0.0.1".into()];
+        let ck = rcgen::generate_simple_self_signed(names).unwrap();
+        let cert = dir.join(format!("{}.pem", name));
+        let key = dir.join(format!("{}.key", name));
+        std::fs::write(&cert, ck.cert.pem()).unwrap();
+        std::fs::write(&key, ck.key_pair.serialize_pem()).unwrap();
+        (cert, key)
+    }
+
+    fn tls(cert: Option<&Path>, key: Option<&Path>) -> TLSOptions {
+        TLSOptions {
+            cert: cert.map(Into::into),
+            key: key.map(Into::into),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tls_server_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_cert(dir.path(), "server");
+        tls(Some(&cert), Some(&key)).server_config().unwrap();
+
+        let err = tls(Some(&cert), None).server_config().unwrap_err();
+        assert!(matches!(err, TlsError::Missing("key")), "{}", err);
+        let err = tls(None, None).server_config().unwrap_err();
+        assert!(matches!(err, TlsError::Missing("cert")), "{}", err);
+    }
+
+    #[test]
+    fn tls_key_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = write_cert(dir.path(), "one");
+        let (_, key) = write_cert(dir.path(), "two");
+        let err = tls(Some(&cert), Some(&key)).server_config().unwrap_err();
+        match &err {
+            TlsError::Invalid { path, .. } => assert_eq!(path, &key),
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert!(err.to_string().contains("does not match"), "{}", err);
+    }
+
+    #[test]
+    fn tls_bad_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_cert(dir.path(), "server");
+
+        // A key where the cert should be, and vice versa
+        let err = tls(Some(&key), Some(&key)).server_config().unwrap_err();
+        assert!(
+            matches!(&err, TlsError::Parse { path, .. } if path == &key),
+            "{}",
+            err
+        );
+        let err = tls(Some(&cert), Some(&cert)).server_config().unwrap_err();
+        assert!(
+            matches!(&err, TlsError::Parse { path, .. } if path == &cert),
+            "{}",
+            err
+        );
+
+        // Broken base64 inside the PEM armor
+        let broken = dir.path().join("broken.pem");
+        std::fs::write(
+            &broken,
+            "-----BEGIN CERTIFICATE-----\n!!!!\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        let err = tls(Some(&broken), Some(&key)).server_config().unwrap_err();
+        assert!(
+            matches!(&err, TlsError::Parse { path, .. } if path == &broken),
+            "{}",
+            err
+        );
+
+        // Valid PEM, but not a valid certificate
+        let garbage = dir.path().join("garbage.pem");
+        std::fs::write(
+            &garbage,
+            "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        let mut opts = tls(None, None);
+        opts.cacert = Some(garbage.clone());
+        let err = opts.client_config().unwrap_err();
+        assert!(
+            matches!(&err, TlsError::Invalid { path, .. } if path == &garbage),
+            "{}",
+            err
+        );
+
+        // A typo'd path
+        let missing = dir.path().join("missing.pem");
+        let err = tls(Some(&missing), Some(&key)).server_config().unwrap_err();
+        assert!(
+            matches!(&err, TlsError::Read { path, .. } if path == &missing),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn tls_client_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_cert(dir.path(), "client");
+
+        // Anonymous client
+        tls(None, None).client_config().unwrap();
+        // Client auth
+        tls(Some(&cert), Some(&key)).client_config().unwrap();
+        // Half of a client identity is a hard error
+        let err = tls(Some(&cert), None).client_config().unwrap_err();
+        assert!(matches!(err, TlsError::Missing("key")), "{}", err);
+        let err = tls(None, Some(&key)).client_config().unwrap_err();
+        assert!(matches!(err, TlsError::Missing("cert")), "{}", err);
+    }
+
+    #[test]
+    fn tls_ca_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let capath = dir.path().join("ca");
+        std::fs::create_dir(&capath).unwrap();
+        write_cert(&capath, "a");
+        write_cert(&capath, "b");
+        let (cacert, _) = write_cert(dir.path(), "c");
+
+        let opts = TLSOptions {
+            cacert: Some(cacert),
+            capath: Some(capath),
+            ..Default::default()
+        };
+        // Only the *.pem files in capath are loaded, not the *.key files
+        assert_eq!(opts.root_store().unwrap().len(), 3);
+        opts.client_config().unwrap();
+    }
+
+    /// Shuttle TLS records between `client` and `server` until both are
+    /// done handshaking, or one of them fails
+    fn handshake(
+        client: rustls::ClientConfig,
+        server: rustls::ServerConfig,
+    ) -> Result<rustls::ServerConnection, rustls::Error> {
+        use std::convert::TryFrom;
+        let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        let mut client = rustls::ClientConnection::new(client.into(), name).unwrap();
+        let mut server = rustls::ServerConnection::new(server.into()).unwrap();
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut &buf[..]).unwrap();
+            server.process_new_packets()?;
+            buf.clear();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut &buf[..]).unwrap();
+            client.process_new_packets()?;
+        }
+        Ok(server)
+    }
+
+    #[test]
+    fn tls_client_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server_cert, server_key) = write_cert(dir.path(), "server");
+        let (client_cert, client_key) = write_cert(dir.path(), "client");
+        let (other_cert, other_key) = write_cert(dir.path(), "other");
+
+        let server = |mode: ClientAuthMode| {
+            TLSOptions {
+                cacert: Some(client_cert.clone()),
+                client_auth: mode,
+                ..tls(Some(&server_cert), Some(&server_key))
+            }
+            .server_config()
+            .unwrap()
+        };
+        let client = |identity: Option<(&PathBuf, &PathBuf)>| {
+            TLSOptions {
+                cacert: Some(server_cert.clone()),
+                ..tls(
+                    identity.map(|i| i.0.as_path()),
+                    identity.map(|i| i.1.as_path()),
+                )
+            }
+            .client_config()
+            .unwrap()
+        };
+        let trusted = Some((&client_cert, &client_key));
+        let untrusted = Some((&other_cert, &other_key));
+
+        // Required: only a trusted cert gets in, and the server can see it
+        let conn = handshake(client(trusted), server(ClientAuthMode::Required)).unwrap();
+        let peer = conn.peer_certificates().unwrap();
+        assert_eq!(
+            peer[0].as_ref(),
+            load_certs(&PemSource::File(&client_cert)).unwrap()[0].as_ref()
+        );
+        let err = handshake(client(untrusted), server(ClientAuthMode::Required)).unwrap_err();
+        assert!(
+            matches!(err, rustls::Error::InvalidCertificate(_)),
+            "{}",
+            err
+        );
+        let err = handshake(client(None), server(ClientAuthMode::Required)).unwrap_err();
+        assert!(
+            matches!(err, rustls::Error::NoCertificatesPresented),
+            "{}",
+            err
+        );
+
+        // Optional: no cert is fine, but a bad one still isn't
+        let conn = handshake(client(None), server(ClientAuthMode::Optional)).unwrap();
+        assert!(conn.peer_certificates().is_none());
+        handshake(client(trusted), server(ClientAuthMode::Optional)).unwrap();
+        handshake(client(untrusted), server(ClientAuthMode::Optional)).unwrap_err();
+
+        // Off: the client's cert isn't even asked for
+        let conn = handshake(client(trusted), server(ClientAuthMode::Off)).unwrap();
+        assert!(conn.peer_certificates().is_none());
+
+        // Client auth needs something to check client certs against
+        let opts = TLSOptions {
+            client_auth: ClientAuthMode::Required,
+            ..tls(Some(&server_cert), Some(&server_key))
+        };
+        let err = opts.server_config().unwrap_err();
+        assert!(matches!(err, TlsError::Missing("cacert")), "{}", err);
+
+        assert_eq!("required".parse(), Ok(ClientAuthMode::Required));
+        assert!("yes".parse::<ClientAuthMode>().is_err());
+        assert_eq!(ClientAuthMode::Optional.to_string(), "optional");
+    }
+
+    /// Accept one connection on a local TLS server, and hand it to `serve`
+    fn tls_server<T: Send + 'static>(
+        config: rustls::ServerConfig,
+        serve: impl FnOnce(&mut rustls::StreamOwned<rustls::ServerConnection, TcpStream>) -> T
+            + Send
+            + 'static,
+    ) -> (SocketAddr, std::thread::JoinHandle<T>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let conn = rustls::ServerConnection::new(config.into()).unwrap();
+            serve(&mut rustls::StreamOwned::new(conn, sock))
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn resolve_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_cert(dir.path(), "server");
+        let server_config = || tls(Some(&cert), Some(&key)).server_config().unwrap();
+
+        // Write: checked against the address, with the CA from `cacert`
+        let (addr, server) = tls_server(server_config(), |stream| {
+            let mut buf = String::new();
+            stream.read_to_string(&mut buf).map(|_| buf)
+        });
+        let handle = WriteHandle::TlsSocket {
+            addr: addr.into(),
+            name: None,
+            tls: Box::new(TLSOptions {
+                cacert: Some(cert.clone()),
+                ..Default::default()
+            }),
+        };
+        let mut out = File::from(handle.resolve().unwrap());
+        out.write_all(b"ping").unwrap();
+        drop(out);
+        // read_to_string only succeeds if the client sent close_notify
+        assert_eq!(server.join().unwrap().unwrap(), "ping");
+
+        // Read: checked against a name, with the CA found in `capath`
+        let capath = dir.path().join("ca");
+        std::fs::create_dir(&capath).unwrap();
+        std::fs::copy(&cert, capath.join("server.pem")).unwrap();
+        let (addr, server) = tls_server(server_config(), |stream| {
+            stream.write_all(b"pong").unwrap();
+            stream.conn.send_close_notify();
+            stream.flush().unwrap();
+        });
+        let handle = ReadHandle::TlsSocket {
+            addr: addr.into(),
+            name: Some("localhost".into()),
+            tls: Box::new(TLSOptions {
+                capath: Some(capath),
+                ..Default::default()
+            }),
+        };
+        let mut buf = String::new();
+        File::from(handle.resolve().unwrap())
+            .read_to_string(&mut buf)
+            .unwrap();
+        assert_eq!(buf, "pong");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn resolve_tls_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_cert(dir.path(), "server");
+        let (other, _) = write_cert(dir.path(), "other");
+        let handle = |addr: SocketAddr, name: Option<&str>, cacert: &Path| WriteHandle::TlsSocket {
+            addr: addr.into(),
+            name: name.map(Into::into),
+            tls: Box::new(TLSOptions {
+                cacert: Some(cacert.into()),
+                ..Default::default()
+            }),
+        };
+        let server = || {
+            let config = tls(Some(&cert), Some(&key)).server_config().unwrap();
+            tls_server(config, |stream| stream.read(&mut [0; 1]))
+        };
+
+        // A server we don't trust, or that isn't who we asked for
+        for (name, cacert) in [(None, &other), (Some("example.com"), &cert)] {
+            let (addr, server) = server();
+            let err = handle(addr, name, cacert).resolve().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+            let msg = err.to_string();
+            assert!(
+                msg.starts_with(&format!("couldn't set up TLS to {}", addr)),
+                "{}",
+                msg
+            );
+            server.join().unwrap().unwrap_err();
+        }
+
+        // Nothing listening
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let err = handle(addr, None, &cert).resolve().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        // Bad options fail before connecting at all
+        let opts = TLSOptions {
+            cert: Some(cert.clone()),
+            ..Default::default()
+        };
+        let handle = ReadHandle::TlsSocket {
+            addr: addr.into(),
+            name: None,
+            tls: Box::new(opts),
+        };
+        let err = handle.resolve().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("key"), "{}", err);
+    }
+
+    #[test]
+    fn tls_pem_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let ck = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cacert = dir.path().join("ca.pem");
+        std::fs::write(&cacert, ck.cert.pem()).unwrap();
+
+        let server = TLSOptions::from_pem_bytes(ck.cert.pem(), ck.key_pair.serialize_pem());
+        assert!(!format!("{:?}", server).contains("PRIVATE KEY"));
+        let server = server.server_config().unwrap();
+        let client = TLSOptions {
+            cacert: Some(cacert),
+            ..Default::default()
+        }
+        .client_config()
+        .unwrap();
+
+        handshake(client, server).unwrap();
+
+        // In-memory material wins over paths, and is named in errors
+        let mut opts = TLSOptions::from_pem_bytes(ck.cert.pem(), "not a key");
+        opts.key = Some(dir.path().join("unused.key"));
+        let err = opts.server_config().unwrap_err();
+        assert!(
+            matches!(&err, TlsError::Parse { path, .. } if path == Path::new("<key_pem>")),
+            "{}",
+            err
+        );
+    }
+
+    fn sample_config() -> LoaderConfig {
+        let mut env = EnvConfig::default()
+            .env("RUST_LOG", "debug")
+            .env("HOME", "/");
+        env.args = vec!["--verbose".into(), "input.txt".into()];
+        env.stdin = Some(ReadHandle::Inherit(0));
+        env.stdout = Some(WriteHandle::File("/tmp/out.log".into()));
+        env.stderr = Some(WriteHandle::Connect("127.0.0.1:9000".parse().unwrap()));
+        let mut wasm = WasmConfig::default();
+        wasm.features.simd = true;
+        wasm.features.reference_types = false;
+        LoaderConfig::new(env, wasm)
+    }
+
+    #[test]
+    fn config_json() {
+        let json = serde_json::to_value(sample_config()).unwrap();
+        assert_eq!(json["version"], 0);
+        assert_eq!(
+            json["env"]["envs"],
+            serde_json::json!([["RUST_LOG", "debug"], ["HOME", "/"]])
+        );
+        assert_eq!(json["env"]["stdin"], serde_json::json!({"inherit": 0}));
+        assert_eq!(
+            json["env"]["stdout"],
+            serde_json::json!({"file": "/tmp/out.log"})
+        );
+        assert_eq!(
+            json["env"]["stderr"],
+            serde_json::json!({"connect": "127.0.0.1:9000"})
+        );
+        assert_eq!(json["wasm"]["features"]["simd"], true);
+
+        let config: LoaderConfig = serde_json::from_value(json.clone()).unwrap();
+        assert!(matches!(config.env.stdin, Some(ReadHandle::Inherit(0))));
+        assert!(config.wasm.features.simd);
+        assert!(!config.wasm.features.reference_types);
+        assert_eq!(serde_json::to_value(&config).unwrap(), json);
+
+        // Configs from before Connect and Listen were split still load
+        let old: WriteHandle =
+            serde_json::from_value(serde_json::json!({"plaintext_socket": "127.0.0.1:9000"}))
+                .unwrap();
+        assert!(matches!(old, WriteHandle::Connect(_)));
+    }
+
+    #[test]
+    fn config_toml() {
+        let text = toml::to_string(&sample_config()).unwrap();
+        let config: LoaderConfig = toml::from_str(&text).unwrap();
+        assert_eq!(config.env.envs, sample_config().env.envs);
+        assert!(
+            matches!(config.env.stdout, Some(WriteHandle::File(ref p)) if p == Path::new("/tmp/out.log"))
+        );
+        assert_eq!(toml::to_string(&config).unwrap(), text);
+
+        let config: LoaderConfig = toml::from_str(
+            r#"
+            version = 0
+            [env]
+            args = ["hello"]
+            stdin = "null"
+            stdout = { inherit = 1 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.env.args, vec!["hello"]);
+        assert!(matches!(config.env.stdin, Some(ReadHandle::Null)));
+        assert!(matches!(config.env.stdout, Some(WriteHandle::Inherit(1))));
+        assert!(config.env.stderr.is_none());
+        assert_eq!(
+            WasmFeatureFlags::from(config.wasm.features),
+            WasmFeatureFlags::default()
+        );
+    }
+
+    #[test]
+    fn config_version() {
+        let err = serde_json::from_str::<LoaderConfig>(r#"{"version": 1}"#).unwrap_err();
+        assert!(
+            err.to_string().contains("unsupported config version 1"),
+            "{}",
+            err
+        );
+        serde_json::from_str::<LoaderConfig>(r#"{"env": {}}"#).unwrap_err();
+        serde_json::from_str::<LoaderConfig>(r#"{"version": 0}"#).unwrap();
+    }
+
+    #[test]
+    fn config_unknown_fields() {
+        let err = serde_json::from_str::<LoaderConfig>(
+            r#"{"version": 0, "wasm": {"features": {"simd": true, "gc": true}}}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("gc"), "{}", err);
+        let err =
+            serde_json::from_str::<LoaderConfig>(r#"{"version": 0, "env": {"stdn": "null"}}"#)
+                .unwrap_err();
+        assert!(err.to_string().contains("stdn"), "{}", err);
+    }
+
+    #[test]
+    fn feature_str() {
+        let flags: WasmFeatureFlags = "".parse().unwrap();
+        assert_eq!(flags, WasmFeatureFlags::default());
+        // The defaults are the proposals wasmtime has on by default
+        assert_eq!(
+            enabled(WasmFeatureFlags::default()),
+            ["reference-types", "multi-value", "bulk-memory"]
+        );
+
+        let flags: WasmFeatureFlags = "simd, +threads,-bulk-memory".parse().unwrap();
+        assert!(flags.simd && flags.threads && !flags.bulk_memory);
+        assert!(flags.reference_types && flags.multi_value);
+
+        // Later items win
+        assert!("-simd,simd".parse::<WasmFeatureFlags>().unwrap().simd);
+        assert!(!"simd,-simd".parse::<WasmFeatureFlags>().unwrap().simd);
+
+        let config = WasmConfig::from_feature_str("memory64").unwrap();
+        assert!(config.features.memory64);
+    }
+
+    fn enabled(flags: WasmFeatureFlags) -> Vec<&'static str> {
+        let mut flags = flags;
+        WasmFeatureFlags::NAMES
+            .iter()
+            .copied()
+            .filter(|name| *flags.flag_mut(name).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn presets() {
+        // If a wasmparser upgrade changes its defaults or adds a proposal,
+        // these should fail (or `WasmFeatureFlags` should stop compiling)
+        // so the presets get a deliberate look.
+        let cases: &[(WasmPreset, &[&str])] = &[
+            (WasmPreset::Strict, &[]),
+            (
+                WasmPreset::Default,
+                &["reference-types", "multi-value", "bulk-memory"],
+            ),
+            (
+                WasmPreset::Experimental,
+                &[
+                    "reference-types",
+                    "multi-value",
+                    "bulk-memory",
+                    "module-linking",
+                    "simd",
+                    "threads",
+                    "tail-call",
+                    "multi-memory",
+                    "exceptions",
+                    "memory64",
+                ],
+            ),
+        ];
+        for (preset, features) in cases {
+            let flags = WasmFeatureFlags::preset(*preset);
+            assert_eq!(enabled(flags), *features, "{}", preset);
+            let config = WasmConfig::preset(*preset);
+            assert_eq!(WasmFeatureFlags::from(config.features), flags);
+            assert_eq!(preset.to_string().parse(), Ok(*preset));
+        }
+        assert_eq!(
+            WasmFeatureFlags::preset(WasmPreset::Default),
+            WasmFeatureFlags::from(WasmFeatures::default())
+        );
+        assert!("mvp".parse::<WasmPreset>().unwrap_err().contains("strict"));
+    }
+
+    #[test]
+    fn feature_changes() {
+        let changes: WasmFeatureChanges = "simd,-bulk-memory".parse().unwrap();
+        let strict = changes.apply_to(WasmFeatureFlags::preset(WasmPreset::Strict));
+        assert_eq!(enabled(strict), ["simd"]);
+        let experimental = changes.apply_to(WasmFeatureFlags::preset(WasmPreset::Experimental));
+        assert!(experimental.simd && !experimental.bulk_memory && experimental.threads);
+        assert_eq!(
+            "".parse::<WasmFeatureChanges>(),
+            Ok(WasmFeatureChanges::default())
+        );
+        assert_eq!(
+            "simd,-gc".parse::<WasmFeatureChanges>(),
+            Err(UnknownFeature("gc".into()))
+        );
+    }
+
+    #[test]
+    fn feature_str_unknown() {
+        let err = WasmConfig::from_feature_str("simd,-gc").unwrap_err();
+        assert_eq!(err, UnknownFeature("gc".into()));
+        let msg = err.to_string();
+        assert!(msg.contains("\"gc\""), "{}", msg);
+        assert!(msg.contains("bulk-memory, module-linking"), "{}", msg);
+        // Underscores aren't accepted, so there's only one spelling to learn
+        "bulk_memory".parse::<WasmFeatureFlags>().unwrap_err();
+    }
+
+    #[test]
+    fn workload_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Enarx.toml");
+        std::fs::write(
+            &path,
+            r#"
+            invoke = "main"
+            arg0 = "busybox"
+            args = ["--verbose", "input.txt"]
+
+            [env]
+            RUST_LOG = "debug"
+            HOME = "/"
+            DB_PASS = "@file:/run/secrets/dbpass"
+
+            [stdio]
+            stdin = "null"
+            stdout = { file = "/tmp/out.log" }
+            "#,
+        )
+        .unwrap();
+        let config = load_workload_config(&path).unwrap();
+        assert_eq!(config.invoke.as_deref(), Some("main"));
+        assert_eq!(config.env.arg0.as_deref(), Some("busybox"));
+        assert_eq!(config.env.args, vec!["--verbose", "input.txt"]);
+        assert_eq!(
+            config.env.envs,
+            vec![
+                ("HOME".to_string(), "/".to_string()),
+                ("RUST_LOG".to_string(), "debug".to_string()),
+            ]
+        );
+        assert_eq!(
+            config.env.secrets,
+            vec![(
+                "DB_PASS".to_string(),
+                SecretSource::File("/run/secrets/dbpass".into())
+            )]
+        );
+        assert!(matches!(config.env.stdin, Some(ReadHandle::Null)));
+        assert!(matches!(config.env.stdout, Some(WriteHandle::File(_))));
+        assert!(config.env.stderr.is_none());
+
+        std::fs::write(&path, "").unwrap();
+        let config = load_workload_config(&path).unwrap();
+        assert!(config.invoke.is_none() && config.env.envs.is_empty());
+    }
+
+    #[test]
+    fn workload_config_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Enarx.toml");
+
+        let err = load_workload_config(&path).unwrap_err();
+        assert!(matches!(err, WorkloadConfigError::Read { .. }), "{}", err);
+
+        std::fs::write(&path, "invoke = \"main\"\nagrs = []\n").unwrap();
+        let err = load_workload_config(&path).unwrap_err();
+        assert!(err.to_string().contains("agrs"), "{}", err);
+
+        std::fs::write(&path, "[stdio]\nstdout = \"null\"\nstdrr = \"null\"\n").unwrap();
+        let err = load_workload_config(&path).unwrap_err();
+        assert!(err.to_string().contains("stdrr"), "{}", err);
+
+        std::fs::write(&path, "[env]\nCOUNT = 3\n").unwrap();
+        load_workload_config(&path).unwrap_err();
+
+        std::fs::write(&path, "[env]\nTOKEN = \"@vault:x\"\n").unwrap();
+        let err = load_workload_config(&path).unwrap_err();
+        assert!(err.to_string().contains("env.TOKEN"), "{}", err);
+
+        // Network rules can't be set here, only on the command line
+        std::fs::write(&path, "[network]\nallow = [\"*:443\"]\n").unwrap();
+        let err = load_workload_config(&path).unwrap_err();
+        assert!(err.to_string().contains("network"), "{}", err);
+    }
+
+    #[test]
+    fn env_validate() {
+        let env = |envs: &[(&str, &str)]| EnvConfig {
+            envs: envs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        env(&[("PATH", "/bin"), ("EMPTY", "")]).validate().unwrap();
+
+        for bad in &["", "FOO=BAR", "FOO\0", "=FOO"] {
+            assert_eq!(
+                env(&[(bad, "x")]).validate(),
+                Err(EnvConfigError::InvalidName(bad.to_string()))
+            );
+        }
+        assert_eq!(
+            env(&[("FOO", "a\0b")]).validate(),
+            Err(EnvConfigError::InvalidValue("FOO".into()))
+        );
+        assert_eq!(
+            env(&[("PATH", "/bin"), ("HOME", "/"), ("PATH", "/usr/bin")]).validate(),
+            Err(EnvConfigError::DuplicateKey("PATH".into()))
+        );
+
+        let mut config = env(&[]);
+        config.args = vec!["ok".into(), "not\0ok".into()];
+        assert_eq!(
+            config.validate(),
+            Err(EnvConfigError::InvalidArg("not\0ok".into()))
+        );
+    }
+
+    #[test]
+    fn env_dedup() {
+        let config = EnvConfig {
+            envs: vec![
+                ("PATH".into(), "/bin".into()),
+                ("HOME".into(), "/".into()),
+                ("PATH".into(), "/usr/bin".into()),
+            ],
+            ..Default::default()
+        }
+        .dedup_envs();
+        config.validate().unwrap();
+        assert_eq!(
+            config.envs,
+            vec![
+                ("HOME".to_string(), "/".to_string()),
+                ("PATH".to_string(), "/usr/bin".to_string()),
+            ]
+        );
+    }
+
+    /// Write `data` to a Tee of `first` and a local socket, and return
+    /// everything that arrives at the socket.
+    fn tee_to_socket(first: WriteHandle, data: &[u8]) -> Vec<u8> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let second = WriteHandle::Connect(listener.local_addr().unwrap().into());
+        let handle = WriteHandle::Tee(Box::new(first), Box::new(second));
+        let (out, threads) = handle.resolve_joinable().unwrap();
+        let mut out = File::from(out);
+        let (mut conn, _) = listener.accept().unwrap();
+        out.write_all(data).unwrap();
+        drop(out);
+        // The socket only hits EOF once the tee thread is done with both branches
+        let mut received = Vec::new();
+        conn.read_to_end(&mut received).unwrap();
+        threads.join();
+        received
+    }
+
+    #[test]
+    fn tee_join() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b, c) = (
+            dir.path().join("a"),
+            dir.path().join("b"),
+            dir.path().join("c"),
+        );
+        let handle: WriteHandle = format!(
+            "tee:{{tee:file:{},file:{}}},file:{}",
+            a.display(),
+            b.display(),
+            c.display()
+        )
+        .parse()
+        .unwrap();
+        let (out, threads) = handle.resolve_joinable().unwrap();
+        assert!(!threads.is_empty());
+        File::from(out).write_all(b"all of it").unwrap();
+        // Once the threads are joined, every branch has everything
+        threads.join();
+        for path in &[a, b, c] {
+            assert_eq!(std::fs::read(path).unwrap(), b"all of it");
+        }
+
+        let (_, threads) = WriteHandle::Null.resolve_joinable().unwrap();
+        assert!(threads.is_empty());
+    }
+
+    #[test]
+    fn tee() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.log");
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let received = tee_to_socket(WriteHandle::File(path.clone()), &data);
+        assert!(received == data);
+        assert!(std::fs::read(&path).unwrap() == data);
+    }
+
+    #[test]
+    fn tee_survives_failed_branch() {
+        // Every write to /dev/full fails with ENOSPC
+        let received = tee_to_socket(WriteHandle::File("/dev/full".into()), b"still here");
+        assert_eq!(received, b"still here");
+    }
+
+    #[test]
+    fn tee_resolve_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let handle = WriteHandle::Tee(
+            Box::new(WriteHandle::Null),
+            Box::new(WriteHandle::File(dir.path().join("missing/out.log"))),
+        );
+        assert_eq!(
+            handle.resolve().unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn tee_serde() {
+        let handle = WriteHandle::Tee(
+            Box::new(WriteHandle::Inherit(1)),
+            Box::new(WriteHandle::File("/tmp/out.log".into())),
+        );
+        let json = serde_json::to_value(&handle).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"tee": [{"inherit": 1}, {"file": "/tmp/out.log"}]})
+        );
+        let handle: WriteHandle = serde_json::from_value(json).unwrap();
+        assert!(matches!(handle, WriteHandle::Tee(..)));
+    }
+
+    #[test]
+    fn parse_handles() {
+        let read = |s: &str| s.parse::<ReadHandle>();
+        let write = |s: &str| s.parse::<WriteHandle>();
+
+        assert!(matches!(read("null"), Ok(ReadHandle::Null)));
+        assert!(matches!(read("inherit"), Ok(ReadHandle::Inherit(0))));
+        assert!(matches!(write("inherit"), Ok(WriteHandle::Inherit(1))));
+        assert!(matches!(
+            WriteHandle::parse_with_fd("inherit", 2),
+            Ok(WriteHandle::Inherit(2))
+        ));
+        assert!(matches!(read("inherit:4"), Ok(ReadHandle::Inherit(4))));
+        assert!(
+            matches!(read("file:/path/to/x"), Ok(ReadHandle::File(p)) if p == Path::new("/path/to/x"))
+        );
+        assert!(
+            matches!(write("tcp://127.0.0.1:9000"), Ok(WriteHandle::Connect(a)) if a.port == 9000)
+        );
+        // Names are kept as they are, and not looked up yet
+        assert!(matches!(
+            read("tcp://no-such-host.invalid:9000"),
+            Ok(ReadHandle::Connect(a)) if a.host == "no-such-host.invalid"
+        ));
+        assert!(matches!(
+            read("tcp-listen://0.0.0.0:9000"),
+            Ok(ReadHandle::Listen { addr, timeout: None }) if addr.port == 9000
+        ));
+        assert!(matches!(
+            write("tcp-listen://[::1]:9000?timeout=5"),
+            Ok(WriteHandle::Listen {
+                timeout: Some(5),
+                ..
+            })
+        ));
+
+        let tee = write("tee:inherit,tee:null,file:a,b.log").unwrap();
+        assert_eq!(tee.to_string(), "tee:inherit:1,tee:null,file:a,b.log");
+
+        match write("tls://localhost:9443?cacert=/ca.pem&capath=/etc/ssl/certs").unwrap() {
+            WriteHandle::TlsSocket { addr, name, tls } => {
+                assert_eq!(addr.to_string(), "localhost:9443");
+                assert_eq!(name, None);
+                assert_eq!(tls.cacert.as_deref(), Some(Path::new("/ca.pem")));
+                assert_eq!(tls.capath.as_deref(), Some(Path::new("/etc/ssl/certs")));
+                assert!(tls.cert.is_none() && tls.key.is_none());
+            }
+            other => panic!("unexpected handle {}", other),
+        }
+        match read("tls://[::1]:9443?cert=c.pem&key=k.pem").unwrap() {
+            ReadHandle::TlsSocket { name, tls, .. } => {
+                assert_eq!(name, None);
+                assert!(tls.cert.is_some() && tls.key.is_some());
+            }
+            other => panic!("unexpected handle {}", other),
+        }
+        assert!(matches!(
+            read("tls://127.0.0.1:9443?name=keep.example.com"),
+            Ok(ReadHandle::TlsSocket { name: Some(n), .. }) if n == "keep.example.com"
+        ));
+    }
+
+    #[test]
+    fn parse_handle_errors() {
+        let err = |s: &str| s.parse::<WriteHandle>().unwrap_err().to_string();
+        assert_eq!(err("foo:bar"), "unknown handle scheme \"foo\"");
+        assert_eq!(err("stdout"), "unknown handle \"stdout\"");
+        assert!(err("tcp://localhost").contains("requires host:port"));
+        assert!(err("tcp://:80").contains("requires host:port"));
+        assert!(err("tcp-listen://9000").contains("requires host:port"));
+        assert!(err("tcp-listen://[::1]:80?timeout=soon").contains("number of seconds"));
+        assert!(err("tls://localhost").contains("requires host:port"));
+        assert!(err("tcp://localhost:http").contains("invalid port"));
+        assert!(err("tcp://localhost:99999").contains("invalid port"));
+        assert!(err("tcp://::1:80").contains("invalid IPv6 address"));
+        assert!(err("tcp://[nope]:80").contains("invalid IPv6 address"));
+        assert!(err("tcp://a/b:80").contains("invalid host"));
+        assert!(err("tls://[::1]:443?cacert").contains("needs a value"));
+        assert!(err("tls://[::1]:443?cacert=").contains("needs a value"));
+        assert!(err("tls://[::1]:443?ca=x").contains("unknown tls handle setting \"ca\""));
+        for bad in &["%", "%2", "%zz", "%FF", "a%+1"] {
+            let spec = format!("tls://[::1]:443?cacert={}", bad);
+            assert!(err(&spec).contains("invalid %-escape"), "{}", spec);
+        }
+        assert!(err("inherit:-1").contains("requires a file descriptor"));
+        assert!(err("inherit:x").contains("requires a file descriptor"));
+        assert_eq!(err("file:"), "file handle requires a path");
+        assert!(err("tee:inherit").contains("requires two handles"));
+        assert!(err("tee:inherit,bogus").contains("unknown handle"));
+        assert!(err("tee:tee:null,null,null").contains("has to be in braces"));
+        assert!(err("tee:{null").contains("unmatched {"));
+        assert!(err("tee:{null}null").contains("requires a comma after {null}"));
+        assert!(err("tee:{null}").contains("requires a comma"));
+        let read_tee = "tee:null,null".parse::<ReadHandle>().unwrap_err();
+        assert!(read_tee.to_string().contains("tee"));
+        let read_append = "append:/a.log".parse::<ReadHandle>().unwrap_err();
+        assert!(read_append.to_string().contains("append"));
+    }
+
+    #[test]
+    fn display_handles() {
+        let addr: HostPort = "127.0.0.1:9000".parse().unwrap();
+        let file = tempfile::tempfile().unwrap();
+        let fd = file.as_raw_fd();
+        let pipe = std::sync::Arc::new(OwnedFd::from(file));
+
+        for (handle, shown) in [
+            (ReadHandle::Null, "null".to_string()),
+            (ReadHandle::Inherit(0), "inherit:0".into()),
+            (
+                ReadHandle::Connect(addr.clone()),
+                "tcp://127.0.0.1:9000".into(),
+            ),
+            (
+                ReadHandle::Listen {
+                    addr: addr.clone(),
+                    timeout: None,
+                },
+                "tcp-listen://127.0.0.1:9000".into(),
+            ),
+            (ReadHandle::File("/in".into()), "file:/in".into()),
+            (ReadHandle::Pipe(pipe.clone()), format!("<pipe fd {}>", fd)),
+        ] {
+            assert_eq!(handle.to_string(), shown);
+        }
+
+        for (handle, shown) in [
+            (WriteHandle::Null, "null".to_string()),
+            (WriteHandle::Inherit(1), "inherit:1".into()),
+            (
+                WriteHandle::Connect(addr.clone()),
+                "tcp://127.0.0.1:9000".into(),
+            ),
+            (
+                WriteHandle::Listen {
+                    addr,
+                    timeout: Some(5),
+                },
+                "tcp-listen://127.0.0.1:9000?timeout=5".into(),
+            ),
+            (WriteHandle::File("/out".into()), "file:/out".into()),
+            (WriteHandle::Append("/log".into()), "append:/log".into()),
+            (WriteHandle::Pipe(pipe), format!("<pipe fd {}>", fd)),
+            (
+                WriteHandle::Tee(
+                    Box::new(WriteHandle::Inherit(2)),
+                    Box::new(WriteHandle::Null),
+                ),
+                "tee:inherit:2,null".into(),
+            ),
+        ] {
+            assert_eq!(handle.to_string(), shown);
+        }
+    }
+
+    #[test]
+    fn display_handles_round_trip() {
+        for spec in &[
+            "null",
+            "inherit:3",
+            "file:/tmp/x y.log",
+            "tcp://127.0.0.1:80",
+            "tcp-listen://0.0.0.0:9000",
+            "tcp-listen://[::1]:80?timeout=30",
+            "tls://127.0.0.1:443",
+            "tls://[::1]:443?name=localhost&cacert=/ca.pem&capath=/ca&cert=/c.pem&key=/k.pem",
+        ] {
+            assert_eq!(&spec.parse::<ReadHandle>().unwrap().to_string(), spec);
+            assert_eq!(&spec.parse::<WriteHandle>().unwrap().to_string(), spec);
+        }
+        for spec in &[
+            "tee:inherit:1,tee:append:/a.log,tcp://[::1]:80",
+            "tee:{tee:inherit:1,append:/a.log},tcp://[::1]:80",
+            "tee:{tee:{tee:null,null},null},null",
+            "tee:{file:/a,b.log},file:/c,d.log",
+            "tee:{file:/{x},y},null",
+        ] {
+            assert_eq!(&spec.parse::<WriteHandle>().unwrap().to_string(), spec);
+        }
+        // Braces are only added where they're needed
+        let tee: WriteHandle = "tee:{null},null".parse().unwrap();
+        assert_eq!(tee.to_string(), "tee:null,null");
+        let tee = WriteHandle::Tee(
+            Box::new(WriteHandle::File("/a,b".into())),
+            Box::new(WriteHandle::Null),
+        );
+        assert_eq!(tee.to_string(), "tee:{file:/a,b},null");
+
+        // Characters that mean something in the settings are escaped
+        let spec = "tls://[::1]:443?cacert=/a%26b%3Dc%3Fd%25e.pem";
+        let handle: ReadHandle = spec.parse().unwrap();
+        match &handle {
+            ReadHandle::TlsSocket { tls, .. } => {
+                assert_eq!(tls.cacert.as_deref(), Some(Path::new("/a&b=c?d%e.pem")))
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(handle.to_string(), spec);
+        let lower: ReadHandle = "tls://[::1]:443?cacert=/a%3fb".parse().unwrap();
+        assert_eq!(lower.to_string(), "tls://[::1]:443?cacert=/a%3Fb");
+    }
+
+    #[test]
+    fn tls_handle_serde() {
+        let json = r#"{"tls_socket": {"addr": "127.0.0.1:443", "tls": {"cacert": "/ca.pem"}}}"#;
+        let handle: WriteHandle = serde_json::from_str(json).unwrap();
+        assert_eq!(handle.to_string(), "tls://127.0.0.1:443?cacert=/ca.pem");
+        assert_eq!(
+            serde_json::to_string(&handle).unwrap(),
+            json.replace(' ', "")
+        );
+
+        // In-memory keys never get written out, and can't be left out
+        // without a path to stand in for them
+        let mut opts = TLSOptions::from_pem_bytes("cert", "key");
+        opts.cacert = Some("/ca.pem".into());
+        let err = serde_json::to_string(&opts).unwrap_err();
+        assert!(err.to_string().contains("cert_pem"), "{}", err);
+        opts.cert = Some("/c.pem".into());
+        let err = serde_json::to_string(&opts).unwrap_err();
+        assert!(err.to_string().contains("key_pem"), "{}", err);
+        opts.key = Some("/k.pem".into());
+        assert_eq!(
+            serde_json::to_string(&opts).unwrap(),
+            r#"{"cert":"/c.pem","key":"/k.pem","cacert":"/ca.pem"}"#
+        );
+        opts.client_auth = ClientAuthMode::Required;
+        let json = serde_json::to_string(&opts).unwrap();
+        assert!(json.ends_with(r#""client_auth":"required"}"#), "{}", json);
+        let err = serde_json::from_str::<TLSOptions>(r#"{"key_pem": "x"}"#).unwrap_err();
+        assert!(err.to_string().contains("key_pem"), "{}", err);
+    }
+
+    #[test]
+    fn preopens() {
+        let dir = tempfile::tempdir().unwrap();
+        let host = dir.path();
+        let file = host.join("file");
+        std::fs::write(&file, "").unwrap();
+
+        let config = EnvConfig::default()
+            .preopen(host, "/data")
+            .preopen("/", "/host")
+            .cwd("/data");
+        config.validate().unwrap();
+
+        let check = |config: EnvConfig| config.validate().unwrap_err();
+        assert_eq!(
+            check(EnvConfig::default().preopen(&file, "/file")),
+            EnvConfigError::NotADirectory(file.clone())
+        );
+        assert_eq!(
+            check(EnvConfig::default().preopen(host.join("missing"), "/missing")),
+            EnvConfigError::NotADirectory(host.join("missing"))
+        );
+        assert_eq!(
+            check(EnvConfig::default().preopen(host, "data")),
+            EnvConfigError::RelativeGuestPath("data".into())
+        );
+        assert_eq!(
+            check(
+                EnvConfig::default()
+                    .preopen(host, "/data")
+                    .preopen("/", "/data/")
+            ),
+            EnvConfigError::DuplicateGuestPath("/data/".into())
+        );
+        assert_eq!(
+            check(EnvConfig::default().cwd("data")),
+            EnvConfigError::RelativeGuestPath("data".into())
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("dbpass");
+        std::fs::write(&file, "from-file\n").unwrap();
+        std::env::set_var("ENARX_TEST_SECRET", "from-env");
+
+        let mut env = EnvConfig::default();
+        let file_spec = format!("@file:{}", file.display());
+        env.push_value("FILE", &file_spec).unwrap();
+        env.push_value("ENV", "@env:ENARX_TEST_SECRET").unwrap();
+        env.push_value("CMD", "@cmd:echo from-cmd").unwrap();
+        env.push_value("PLAIN", "@@literal").unwrap();
+        assert_eq!(env.envs, vec![("PLAIN".into(), "@literal".into())]);
+        assert_eq!(env.secrets[0].1.to_string(), file_spec);
+        env.validate().unwrap();
+
+        let err = env.resolve_secrets(false).unwrap_err();
+        assert!(matches!(err, SecretError::CommandsNotAllowed(_)));
+        let secrets = env.resolve_secrets(true).unwrap();
+        let values: Vec<(&str, &str)> = secrets
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.expose().as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("FILE", "from-file"),
+                ("ENV", "from-env"),
+                ("CMD", "from-cmd")
+            ]
+        );
+        let debug = format!("{:?}", secrets);
+        assert!(debug.contains("<redacted>") && !debug.contains("from-"));
+
+        let err = SecretSource::Env("ENARX_TEST_UNSET".into())
+            .resolve(false)
+            .unwrap_err();
+        assert!(matches!(err, SecretError::MissingEnv(_)));
+        let err = SecretSource::Command("exit 3".into())
+            .resolve(true)
+            .unwrap_err();
+        assert!(err.to_string().contains("failed"), "{}", err);
+
+        let parse = |s: &str| EnvConfig::default().push_value("X", s).unwrap_err();
+        assert!(parse("@literal").to_string().contains("@@"));
+        assert!(parse("@http:x")
+            .to_string()
+            .contains("unknown secret scheme"));
+        assert_eq!(parse("@file:").to_string(), "file secret is empty");
+
+        // A secret and a plain value for the same name is a duplicate
+        let mut env = EnvConfig::default().env("ENV", "plain");
+        env.push_value("ENV", "@env:ENARX_TEST_SECRET").unwrap();
+        assert_eq!(
+            env.validate(),
+            Err(EnvConfigError::DuplicateKey("ENV".into()))
+        );
+        let env = env.dedup_envs();
+        assert!(env.envs.is_empty() && env.secrets.len() == 1);
+    }
+
+    #[test]
+    fn env_dedup_last_wins() {
+        let secret = vec![("A".to_string(), SecretSource::File("/x".into()))];
+        let plain = vec![("A".to_string(), "plain".to_string())];
+
+        let mut env = EnvConfig::default();
+        env.push_value("A", "@file:/x").unwrap();
+        env.push_value("A", "plain").unwrap();
+        let env = env.dedup_envs();
+        assert_eq!((&env.envs, &env.secrets), (&plain, &vec![]));
+
+        let mut env = EnvConfig::default();
+        env.push_value("A", "plain").unwrap();
+        env.push_value("A", "@file:/x").unwrap();
+        let env = env.dedup_envs();
+        assert_eq!((&env.envs, &env.secrets), (&vec![], &secret));
+
+        // env() and push_secret() count as settings too, and so do the
+        // settings merged in from another config
+        let mut env = EnvConfig::default();
+        env.push_value("A", "@file:/x").unwrap();
+        env.push_secret("A", "plain");
+        assert_eq!(env.dedup_envs().envs, plain);
+        let mut over = EnvConfig::default();
+        over.push_value("A", "@file:/x").unwrap();
+        let env = EnvConfig::default().env("A", "plain").merge(over);
+        assert_eq!(env.dedup_envs().secrets, secret);
+        let mut base = EnvConfig::default();
+        base.push_value("A", "@file:/x").unwrap();
+        let env = base.env("A", "plain").dedup_envs();
+        assert_eq!((&env.envs, &env.secrets), (&plain, &vec![]));
+    }
+
+    #[test]
+    fn sizes() {
+        let cases: &[(&str, u64)] = &[
+            ("4096", 4096),
+            ("0B", 0),
+            ("64KiB", 64 << 10),
+            ("256M", 256 << 20),
+            ("2GiB", 2 << 30),
+            ("1TiB", 1 << 40),
+            ("64kib", 64 << 10),
+            ("2gib", 2 << 30),
+            ("1g", 1 << 30),
+            ("100KB", 100_000),
+            ("100kb", 100_000),
+            ("5MB", 5_000_000),
+            ("2GB", 2_000_000_000),
+            ("3TB", 3_000_000_000_000),
+            ("1.5GiB", 3 << 29),
+            ("0.5K", 512),
+            ("1.25MB", 1_250_000),
+            // Rounded down to whole bytes
+            ("1.1KiB", 1126),
+            ("0.0000000000000000000001GiB", 0),
+            ("1 GiB", 1 << 30),
+            ("1\tMB", 1_000_000),
+            ("  512 ", 512),
+            ("18446744073709551615", u64::MAX),
+            ("16777215.99999999999TiB", u64::MAX - 10),
+        ];
+        for (input, bytes) in cases {
+            assert_eq!(parse_size(input), Ok(*bytes), "{:?}", input);
+        }
+
+        use SizeErrorKind::*;
+        let cases = [
+            ("", NoNumber),
+            ("MiB", NoNumber),
+            ("-1", NoNumber),
+            ("1,024", Separator(',')),
+            ("1_024KiB", Separator('_')),
+            ("1.2.3", BadNumber("1.2.3".into())),
+            ("1.", BadNumber("1.".into())),
+            (".5G", BadNumber(".5".into())),
+            ("12XB", UnknownUnit("XB".into())),
+            ("1 GiB extra", UnknownUnit("GiB extra".into())),
+            ("4 KiBs", UnknownUnit("KiBs".into())),
+            ("1.5", FractionalBytes),
+            ("1.5b", FractionalBytes),
+            ("99999999999999999999G", TooBig),
+            ("18446744073709551616", TooBig),
+            ("16777216TiB", TooBig),
+            ("999999999999999999999999999999999999999999", TooBig),
+            // The whole part fits in a u128, but adding the fraction doesn't
+            ("340282366920938463463374607.999TB", TooBig),
+        ];
+        for (input, kind) in cases.iter() {
+            let expected = SizeParseError {
+                input: input.to_string(),
+                kind: kind.clone(),
+            };
+            assert_eq!(parse_size(input), Err(expected), "{:?}", input);
+        }
+
+        let msg = |s| parse_size(s).unwrap_err().to_string();
+        assert_eq!(
+            msg("1,024"),
+            "invalid size \"1,024\": leave out the ',' separators (did you mean 1024?)"
+        );
+        assert!(msg("1,048,576").ends_with("(did you mean 1048576?)"));
+        assert!(msg("1_024KiB").ends_with("(did you mean 1024KiB?)"));
+        // Anything that could be a decimal comma (or that would still be
+        // wrong without the separators) gets no guess
+        for input in &[
+            "1,5GiB",
+            "1,500MB",
+            "1,5",
+            "1,0240",
+            "1024,000",
+            "1,024_000",
+            "1_024,000",
+            "1,",
+        ] {
+            let msg = msg(input);
+            assert!(!msg.contains("did you mean"), "{}", msg);
+            assert!(msg.contains("use '.' for a fraction"), "{}", msg);
+        }
+        assert!(msg("12XB").contains("unknown unit \"XB\""));
+    }
+
+    #[test]
+    fn wasm_limits() {
+        let config = WasmConfig::default()
+            .max_memory_bytes(256 << 20)
+            .max_table_elements(1000)
+            .fuel(1_000_000);
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["max_memory_bytes"], 256 << 20);
+        assert_eq!(json["max_table_elements"], 1000);
+        assert_eq!(json["fuel"], 1_000_000);
+        let config: WasmConfig = serde_json::from_value(json).unwrap();
+        assert_eq!(config.fuel, Some(1_000_000));
+
+        let json = serde_json::to_value(WasmConfig::default()).unwrap();
+        assert!(json.get("fuel").is_none());
+    }
+
+    #[test]
+    fn debug_redacts_sensitive_envs() {
+        let mut env = EnvConfig::default()
+            .env("RUST_LOG", "debug")
+            .env("GITHUB_TOKEN", "ghp_hunter2")
+            .env("db_password", "hunter3");
+        env.push_secret("MINE", "hunter4");
+        let debug = format!("{:?}", env);
+        assert!(!debug.contains("hunter"), "{}", debug);
+        assert!(debug.contains("\"RUST_LOG\", \"debug\""), "{}", debug);
+        assert!(debug.contains("(\"MINE\", <redacted>)"), "{}", debug);
+        let debug = format!("{:#?}", env);
+        assert!(!debug.contains("hunter"), "{}", debug);
+
+        assert!(is_sensitive_name("AWS_SECRET_ACCESS_KEY"));
+        assert!(is_sensitive_name("api_key"));
+        assert!(!is_sensitive_name("HOME"));
+        assert!(env.is_sensitive("MINE") && !env.is_sensitive("RUST_LOG"));
+    }
+
+    #[test]
+    fn push_env() {
+        let mut env = EnvConfig::default();
+        env.push_env("RUST_LOG", "debug").unwrap();
+        env.push_env("1 odd.name", "ok").unwrap();
+        assert_eq!(
+            env.push_env("", "x"),
+            Err(EnvConfigError::InvalidName("".into()))
+        );
+        assert_eq!(
+            env.push_env("A\0B", "x"),
+            Err(EnvConfigError::InvalidName("A\0B".into()))
+        );
+        assert_eq!(
+            env.push_env("A=B", "x"),
+            Err(EnvConfigError::InvalidName("A=B".into()))
+        );
+        assert_eq!(
+            env.push_env("A", "x\0"),
+            Err(EnvConfigError::InvalidValue("A".into()))
+        );
+        assert_eq!(env.envs.len(), 2);
+        env.validate().unwrap();
+    }
+
+    #[test]
+    fn cwd() {
+        assert_eq!(EnvConfig::default().cwd, None);
+        let env = EnvConfig::default().cwd("/srv/app");
+        assert_eq!(env.cwd, Some(PathBuf::from("/srv/app")));
+        env.validate().unwrap();
+        let env = EnvConfig::default().workdir(String::from("/srv/app"));
+        assert_eq!(env.cwd, Some(PathBuf::from("/srv/app")));
+    }
+
+    #[test]
+    fn arg0() {
+        assert_eq!(default_arg0(Path::new("./target/foo.wasm")), "foo");
+        assert_eq!(default_arg0(Path::new("/usr/bin/busybox")), "busybox");
+        assert_eq!(default_arg0(Path::new("app.tar.wasm")), "app.tar");
+        assert_eq!(default_arg0(Path::new(".hidden")), ".hidden");
+        assert_eq!(default_arg0(Path::new("/")), "/");
+
+        let env = EnvConfig {
+            args: vec!["-l".into()],
+            ..Default::default()
+        };
+        assert_eq!(env.argv(Path::new("./target/ls.wasm")), vec!["ls", "-l"]);
+        let env = env.arg0("busybox");
+        assert_eq!(
+            env.argv(Path::new("./target/ls.wasm")),
+            vec!["busybox", "-l"]
+        );
+
+        let merged = env.merge(EnvConfig::default());
+        assert_eq!(merged.arg0.as_deref(), Some("busybox"));
+        let merged = merged.merge(EnvConfig::default().arg0("sh"));
+        assert_eq!(merged.arg0.as_deref(), Some("sh"));
+
+        let err = EnvConfig::default().arg0("a\0b").validate().unwrap_err();
+        assert_eq!(err, EnvConfigError::InvalidArg("a\0b".into()));
+
+        let json = serde_json::to_value(EnvConfig::default().arg0("sh")).unwrap();
+        assert_eq!(json["arg0"], "sh");
+    }
+
+    #[test]
+    fn expand() {
+        let lookup = |name: &str| match name {
+            "HOME" => Some("/home/me".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let expand = |value: &str| {
+            let mut env = EnvConfig::default().env("V", value);
+            env.expand_env = true;
+            env.expand_with(lookup).map(|env| env.envs[0].1.clone())
+        };
+        assert_eq!(expand("${HOME}/.myapp").unwrap(), "/home/me/.myapp");
+        assert_eq!(expand("$HOME/.myapp").unwrap(), "/home/me/.myapp");
+        assert_eq!(
+            expand("$HOME-x:$HOMEx").unwrap_err().kind,
+            ExpandErrorKind::Undefined("HOMEx".into())
+        );
+        assert_eq!(expand("${NOPE:-/tmp}/x").unwrap(), "/tmp/x");
+        assert_eq!(expand("${EMPTY:-dflt}").unwrap(), "dflt");
+        assert_eq!(expand("${EMPTY}").unwrap(), "");
+        assert_eq!(expand("${HOME:-}").unwrap(), "/home/me");
+        assert_eq!(expand("${NOPE:-}").unwrap(), "");
+
+        // Escapes, and dollars that don't start a reference
+        assert_eq!(expand("$$HOME").unwrap(), "$HOME");
+        assert_eq!(expand("$$$HOME").unwrap(), "$/home/me");
+        assert_eq!(expand("cost: $5, $").unwrap(), "cost: $5, $");
+
+        let err = expand("${NOPE}").unwrap_err();
+        assert_eq!(err.kind, ExpandErrorKind::Undefined("NOPE".into()));
+        assert_eq!(
+            err.to_string(),
+            "can't expand V: NOPE is not set (use ${NOPE:-DEFAULT} to allow that)"
+        );
+        assert_eq!(
+            expand("${HOME").unwrap_err().kind,
+            ExpandErrorKind::Unterminated
+        );
+        assert_eq!(
+            expand("${}").unwrap_err().kind,
+            ExpandErrorKind::BadName("".into())
+        );
+        assert_eq!(
+            expand("${A B}").unwrap_err().kind,
+            ExpandErrorKind::BadName("A B".into())
+        );
+
+        // Nothing happens unless expand_env is set, and it's cleared after
+        let env = EnvConfig::default().env("V", "$HOME");
+        assert_eq!(env.expand_with(lookup).unwrap().envs[0].1, "$HOME");
+        let mut env = EnvConfig::default().env("V", "$$HOME");
+        env.expand_env = true;
+        let env = env.expand_with(lookup).unwrap();
+        assert!(!env.expand_env);
+        assert_eq!(env.expand_with(lookup).unwrap().envs[0].1, "$HOME");
+
+        // Secret values are never touched
+        let mut env = EnvConfig::default().env("DB_PASSWORD", "pa$$word$HOME");
+        env.push_secret("OTHER", "${NOPE}");
+        env.expand_env = true;
+        let env = env.expand_with(lookup).unwrap();
+        assert_eq!(env.envs[0].1, "pa$$word$HOME");
+        assert_eq!(env.envs[1].1, "${NOPE}");
+    }
+
+    #[test]
+    fn envs_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.env");
+        std::fs::write(
+            &path,
+            "# database settings\n\
+             DB_HOST=db.local\n\
+             \n\
+             \x20 DB_PORT =5432\n\
+             \x20 # indented comment\n\
+             GREETING=\"hello, world\" \n\
+             EMPTY=\n\
+             URL=http://x/?a=b\n\
+             DB_HOST=db.prod\r\n",
+        )
+        .unwrap();
+        let env = EnvConfig::default()
+            .env("DB_PORT", "1")
+            .envs_from_file(&path)
+            .unwrap();
+        let pairs = |v: &[(&str, &str)]| -> Vec<(String, String)> {
+            v.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            env.envs,
+            pairs(&[
+                ("DB_PORT", "5432"),
+                ("GREETING", "\"hello, world\" "),
+                ("EMPTY", ""),
+                ("URL", "http://x/?a=b"),
+                ("DB_HOST", "db.prod"),
+            ])
+        );
+
+        std::fs::write(&path, "A=1\n# ok\nB\n").unwrap();
+        let err = EnvConfig::default().envs_from_file(&path).unwrap_err();
+        assert!(
+            matches!(err, EnvFileError::Syntax { line: 3, .. }),
+            "{}",
+            err
+        );
+        assert!(err.to_string().ends_with("app.env:3: expected NAME=VALUE"));
+
+        std::fs::write(&path, " =1\n").unwrap();
+        let err = EnvConfig::default().envs_from_file(&path).unwrap_err();
+        assert!(
+            matches!(err, EnvFileError::Syntax { line: 1, .. }),
+            "{}",
+            err
+        );
+
+        let missing = dir.path().join("missing.env");
+        let err = EnvConfig::default().envs_from_file(missing).unwrap_err();
+        assert!(matches!(err, EnvFileError::Read { .. }));
+    }
+
+    #[test]
+    fn resource_limits() {
+        let env = EnvConfig::default()
+            .limit_address_space(1 << 30)
+            .limit_cpu_seconds(10)
+            .limit_open_files(64);
+        assert_eq!(
+            env.limits,
+            ResourceLimits {
+                address_space: Some(1 << 30),
+                cpu_seconds: Some(10),
+                open_files: Some(64),
+            }
+        );
+        let json = serde_json::to_value(&env).unwrap();
+        assert_eq!(
+            json["limits"],
+            serde_json::json!({"address_space": 1 << 30, "cpu_seconds": 10, "open_files": 64})
+        );
+        assert!(serde_json::to_value(EnvConfig::default())
+            .unwrap()
+            .get("limits")
+            .is_none());
+
+        let merged = env.merge(EnvConfig::default().limit_open_files(32));
+        assert_eq!(merged.limits.open_files, Some(32));
+        assert_eq!(merged.limits.cpu_seconds, Some(10));
+
+        // Apply them in a child, so the test process keeps its own limits
+        use std::os::unix::process::CommandExt;
+        let limits = EnvConfig::default().limit_open_files(64).limits;
+        let mut cmd = std::process::Command::new("/bin/sh");
+        cmd.args(["-c", "ulimit -n; ulimit -t"]);
+        // SAFETY: apply() only calls setrlimit(), which is async-signal-safe
+        unsafe { cmd.pre_exec(move || limits.apply()) };
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        let text = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "64");
+        assert_ne!(lines[1], "10");
+    }
+
+    #[test]
+    fn net_rules() {
+        use std::net::IpAddr;
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let cases = [
+            (
+                "api.example.com:443",
+                HostPattern::Name("api.example.com".into()),
+                (443, 443),
+            ),
+            (
+                "10.0.0.0/8:5432",
+                HostPattern::Cidr(ip("10.0.0.0"), 8),
+                (5432, 5432),
+            ),
+            (
+                "192.168.1.5:22",
+                HostPattern::Cidr(ip("192.168.1.5"), 32),
+                (22, 22),
+            ),
+            (
+                "[fd00::/8]:8000-8080",
+                HostPattern::Cidr(ip("fd00::"), 8),
+                (8000, 8080),
+            ),
+            ("[::1]:*", HostPattern::Cidr(ip("::1"), 128), (0, u16::MAX)),
+            (
+                "*.example.com:*",
+                HostPattern::Subdomain("example.com".into()),
+                (0, u16::MAX),
+            ),
+            ("*:80", HostPattern::Any, (80, 80)),
+            (
+                "0.0.0.0/0:443",
+                HostPattern::Cidr(ip("0.0.0.0"), 0),
+                (443, 443),
+            ),
+        ];
+        for (spec, host, ports) in cases.iter() {
+            let rule: NetRule = spec.parse().unwrap();
+            assert_eq!(
+                rule,
+                NetRule {
+                    host: host.clone(),
+                    ports: *ports
+                },
+                "{}",
+                spec
+            );
+            assert_eq!(&rule.to_string(), spec);
+        }
+        // Names are case-insensitive, and a trailing dot doesn't matter
+        let rule: NetRule = "API.Example.COM.:443".parse().unwrap();
+        assert_eq!(rule.to_string(), "api.example.com:443");
+
+        let err = |s: &str| s.parse::<NetRule>().unwrap_err().to_string();
+        assert!(err("example.com").contains("requires HOST:PORT"));
+        assert!(err(":443").contains("requires HOST:PORT"));
+        assert!(err("example.com:").contains("requires HOST:PORT"));
+        assert!(err("example.com:https").contains("invalid port \"https\""));
+        assert!(err("example.com:65536").contains("invalid port"));
+        assert!(err("example.com:90-80").contains("backwards"));
+        assert!(err("10.0.0.0/33:80").contains("must be 0-32"));
+        assert!(err("[fd00::/129]:80").contains("must be 0-128"));
+        assert!(err("10.1.2.3/8:80").contains("did you mean 10.0.0.0/8?"));
+        assert!(err("fd00::1:80").contains("need brackets"));
+        assert!(err("[example.com]:80").contains("invalid network"));
+        assert!(err("example.com/8:80").contains("invalid network"));
+        assert!(err("bad host!:80").contains("invalid host name"));
+        assert!(err("a..b:80").contains("invalid host name"));
+    }
+
+    #[test]
+    fn network_policy() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let policy = NetworkPolicy {
+            default_allow: None,
+            allow: [
+                "10.0.0.0/8:5432",
+                "api.example.com:443",
+                "*.internal:*",
+                "[fd00::/8]:80",
+            ]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect(),
+        };
+        let cases = [
+            // CIDR rules match on the address, whatever the name
+            ("10.1.2.3:5432", None, true),
+            ("10.255.255.255:5432", Some("db.example.com"), true),
+            ("11.0.0.1:5432", None, false),
+            ("10.1.2.3:5433", None, false),
+            ("[::ffff:10.1.2.3]:5432", None, true),
+            ("[fd12::1]:80", None, true),
+            ("[fe80::1]:80", None, false),
+            // Name rules need the name
+            ("93.184.216.34:443", Some("api.example.com"), true),
+            ("93.184.216.34:443", Some("API.example.com."), true),
+            ("93.184.216.34:443", None, false),
+            ("93.184.216.34:443", Some("www.example.com"), false),
+            ("93.184.216.34:80", Some("api.example.com"), false),
+            // Wildcard ports, and only proper subdomains
+            ("192.0.2.1:1", Some("db.internal"), true),
+            ("192.0.2.1:65535", Some("a.b.internal"), true),
+            ("192.0.2.1:1", Some("internal"), false),
+            ("192.0.2.1:1", Some("notinternal"), false),
+        ];
+        for (a, name, allowed) in cases.iter() {
+            assert_eq!(policy.check(&addr(a), *name), *allowed, "{} {:?}", a, name);
+        }
+
+        let open = NetworkPolicy {
+            default_allow: Some(true),
+            ..Default::default()
+        };
+        assert!(open.check(&addr("198.51.100.1:25"), None));
+        let closed = NetworkPolicy {
+            default_allow: Some(false),
+            ..Default::default()
+        };
+        assert!(!closed.check(&addr("198.51.100.1:25"), None));
+        assert!(!NetworkPolicy::default().check(&addr("198.51.100.1:25"), None));
+    }
+
+    #[test]
+    fn network_policy_config() {
+        let env = EnvConfig::default()
+            .allow_net("10.0.0.0/8:5432".parse().unwrap())
+            .allow_net("*.example.com:443".parse().unwrap());
+        let json = serde_json::to_value(&env).unwrap();
+        assert_eq!(
+            json["network"],
+            serde_json::json!({"allow": ["10.0.0.0/8:5432", "*.example.com:443"]})
+        );
+        let back: EnvConfig = serde_json::from_value(json).unwrap();
+        assert_eq!(back.network, env.network);
+        assert!(serde_json::to_value(EnvConfig::default())
+            .unwrap()
+            .get("network")
+            .is_none());
+
+        let err =
+            serde_json::from_str::<EnvConfig>(r#"{"network": {"allow": ["nope"]}}"#).unwrap_err();
+        assert!(err.to_string().contains("requires HOST:PORT"), "{}", err);
+
+        let with_default = |default_allow| {
+            let mut env = EnvConfig::default();
+            env.network.default_allow = default_allow;
+            env
+        };
+        let merged = env.merge(with_default(Some(true)).allow_net("*:80".parse().unwrap()));
+        assert_eq!(merged.network.allow.len(), 3);
+        assert_eq!(merged.network.default_allow, Some(true));
+
+        // A layer that doesn't say leaves the policy alone, and a later
+        // layer can close it again
+        let merged = merged.merge(with_default(None));
+        assert_eq!(merged.network.default_allow, Some(true));
+        let closed = merged.merge(with_default(Some(false)));
+        assert_eq!(closed.network.default_allow, Some(false));
+        assert_eq!(closed.network.allow.len(), 3);
+
+        let json = r#"{"network": {"default_allow": false}}"#;
+        let env: EnvConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(env.network.default_allow, Some(false));
+        assert!(!env.network.is_default());
+    }
+
+    #[test]
+    fn merge() {
+        let base = || {
+            let mut env = EnvConfig::default()
+                .env("A", "base")
+                .env("B", "base")
+                .preopen("/srv", "/data")
+                .inherit_stdin()
+                .cwd("/data");
+            env.args = vec!["base".into()];
+            env.push_secret("TOKEN", "base");
+            env
+        };
+        let debug = |env: &EnvConfig| format!("{:?}", env);
+
+        // Merging nothing changes nothing, whichever side it's on
+        assert_eq!(debug(&base().merge(EnvConfig::default())), debug(&base()));
+        assert_eq!(debug(&EnvConfig::default().merge(base())), debug(&base()));
+
+        let mut over = EnvConfig::default()
+            .env("B", "over")
+            .env("C", "over")
+            .preopen("/tmp", "/data/")
+            .stdout_to_file("/tmp/out.log", false);
+        over.push_value("A", "@env:A").unwrap();
+        over.push_env("TOKEN", "public").unwrap();
+        let merged = base().merge(over);
+        assert_eq!(
+            merged.envs,
+            vec![
+                ("B".to_string(), "over".to_string()),
+                ("C".to_string(), "over".to_string()),
+                ("TOKEN".to_string(), "public".to_string()),
+            ]
+        );
+        assert_eq!(
+            merged.secrets,
+            vec![("A".to_string(), SecretSource::Env("A".into()))]
+        );
+        assert!(merged.sensitive.is_empty());
+        assert_eq!(merged.args, vec!["base"]);
+        assert_eq!(
+            merged.preopens,
+            vec![(PathBuf::from("/tmp"), "/data/".to_string())]
+        );
+        assert!(matches!(merged.stdin, Some(ReadHandle::Inherit(_))));
+        assert!(matches!(merged.stdout, Some(WriteHandle::File(_))));
+        assert_eq!(merged.cwd, Some(PathBuf::from("/data")));
+
+        let args = EnvConfig {
+            args: vec!["over".into()],
+            ..Default::default()
+        };
+        assert_eq!(base().merge(args).args, vec!["over"]);
+    }
+
+    #[test]
+    fn merge_args() {
+        let base = || {
+            let mut env = EnvConfig::default().env("X", "base").inherit_stdio();
+            env.args = vec!["--base".into()];
+            env
+        };
+        let over = || {
+            let mut env = EnvConfig::default().env("X", "over");
+            env.args = vec!["--over".into()];
+            env
+        };
+        let merged = base().merge_args(over());
+        assert_eq!(merged.args, vec!["--base", "--over"]);
+        // Everything else is the same as merge()
+        let plain = base().merge(over());
+        assert_eq!(plain.args, vec!["--over"]);
+        assert_eq!(merged.envs, plain.envs);
+        assert!(matches!(merged.stdin, Some(ReadHandle::Inherit(_))));
+        assert_eq!(base().merge_args(EnvConfig::default()).args, vec!["--base"]);
+    }
+
+    #[test]
+    fn merge_is_associative() {
+        let a = || EnvConfig::default().env("X", "a").env("Y", "a");
+        let b = || {
+            let mut env = EnvConfig::default().env("Y", "b").env("Z", "b");
+            env.args = vec!["b".into()];
+            env.push_secret("X", "b");
+            env
+        };
+        let c = || {
+            let mut env = EnvConfig::default();
+            env.push_value("Z", "@file:/c").unwrap();
+            env.push_env("W", "c").unwrap();
+            env.push_env("W", "c2").unwrap();
+            env
+        };
+        let left = a().merge(b()).merge(c());
+        let right = a().merge(b().merge(c()));
+        assert_eq!(left.envs, right.envs);
+        assert_eq!(left.secrets, right.secrets);
+        assert_eq!(left.sensitive, right.sensitive);
+        assert_eq!(left.args, right.args);
+        assert_eq!(
+            left.envs,
+            vec![
+                ("Y".to_string(), "b".to_string()),
+                ("X".to_string(), "b".to_string()),
+                ("W".to_string(), "c".to_string()),
+                ("W".to_string(), "c2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn pipe_handles() {
+        let (reader, writer) = pipe().unwrap();
+        let (read_fd, write_fd) = (reader.as_raw_fd(), writer.as_raw_fd());
+        let env = EnvConfig::default()
+            .stdin_from_fd(reader)
+            .stdout_to_fd(writer);
+        let (stdin, stdout) = (env.stdin.clone().unwrap(), env.stdout.clone().unwrap());
+        assert_eq!(stdin.raw_fd(), Some(read_fd));
+        assert_eq!(stdout.raw_fd(), Some(write_fd));
+        assert_eq!(stdout.to_string(), format!("<pipe fd {}>", write_fd));
+        assert!(stdout.to_string().parse::<WriteHandle>().is_err());
+        assert!(serde_json::to_string(&env).is_err());
+
+        // The config owns the write end, so the reader only sees EOF once
+        // the config and everything resolved from it are gone
+        File::from(stdout.resolve().unwrap())
+            .write_all(b"hello")
+            .unwrap();
+        drop((env, stdout));
+        let mut buf = String::new();
+        File::from(stdin.resolve().unwrap())
+            .read_to_string(&mut buf)
+            .unwrap();
+        assert_eq!(buf, "hello");
+    }
+}
@@ -14,9 +14,13 @@ fn main() -> Result<(), std::io::Error> {
     for file in &proto_files {
         println!("cargo:rerun-if-changed={}", file)
     }
+    // compat.rs checks this against the ones committed under fixtures/
+    let out_dir = std::env::var_os("OUT_DIR").expect("OUT_DIR is set by cargo");
+    let descriptor_path = std::path::Path::new(&out_dir).join("enarx.v0.desc");
     tonic_build::configure()
         .build_client(true)
         .build_server(true)
         .out_dir("src/")
+        .file_descriptor_set_path(descriptor_path)
         .compile(&proto_files, &proto_include_path)
 }
\ No newline at end of file
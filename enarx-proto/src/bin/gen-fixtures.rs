@@ -0,0 +1,31 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Write this release's compatibility fixtures into `fixtures/VERSION/`:
+//! an encoded sample of every top-level message, plus the descriptor set.
+//! Run it with `cargo run --bin gen-fixtures` when cutting a release and
+//! commit what it writes; see `enarx_proto::compat` for how they're used.
+
+use enarx_proto::compat;
+use std::path::Path;
+
+fn main() -> std::io::Result<()> {
+    let version = env!("CARGO_PKG_VERSION");
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join(version);
+    // Regenerating a release's fixtures would hide any break from it
+    if dir.exists() {
+        eprintln!(
+            "{} already exists; fixtures are only written once per version",
+            dir.display()
+        );
+        std::process::exit(1);
+    }
+    std::fs::create_dir_all(&dir)?;
+    for (name, bytes) in compat::samples() {
+        std::fs::write(dir.join(format!("{}.bin", name)), bytes)?;
+    }
+    std::fs::write(dir.join("descriptor.bin"), compat::FILE_DESCRIPTOR_SET)?;
+    println!("wrote fixtures for {} to {}", version, dir.display());
+    Ok(())
+}
@@ -0,0 +1,444 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Wire compatibility with earlier releases.
+//!
+//! Each release checks in a `fixtures/VERSION/` directory, written by the
+//! `gen-fixtures` binary: one encoded sample of every top-level message
+//! (from `samples()`) and the release's descriptor set. The tests here
+//! decode every one of those fixtures with the current code, and use
+//! `check_descriptor_sets()` to refuse any change that gives an old field
+//! number a new meaning.
+
+use crate::v0::boot_request::{boot_item, BootItem};
+use crate::v0::{
+    backend_info, BackendInfo, BootRequest, Code, FieldViolation, HostInventory, InfoRequest,
+    KeepldrInfo, Result,
+};
+use prost::Message;
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+use std::collections::BTreeMap;
+
+/// The descriptor set for the current protocol, as built by build.rs
+pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/enarx.v0.desc"));
+
+/// An encoded sample of every top-level message, by message name.
+///
+/// Fixtures made from these are checked field by field in the tests, so
+/// once a release has shipped, don't change the value of any field that's
+/// already set here. New fields can be given values, though.
+pub fn samples() -> Vec<(&'static str, Vec<u8>)> {
+    let item = |bytes: &[u8]| BootItem {
+        from: Some(boot_item::From::Blob(bytes.to_vec())),
+    };
+    let violation = FieldViolation {
+        field: "exec.blob".into(),
+        description: "empty".into(),
+    };
+    vec![
+        ("InfoRequest", InfoRequest {}.encode_to_vec()),
+        (
+            "KeepldrInfo",
+            KeepldrInfo {
+                name: "keepldr".into(),
+                version: "0.1.0".into(),
+                sallyport_version: "0.1.0".into(),
+                backend: Some(BackendInfo {
+                    kvm: Some(backend_info::KvmInfo {}),
+                    sgx: Some(backend_info::SgxInfo {
+                        max_enclave_size_bits: 36,
+                    }),
+                    sev: None,
+                }),
+                inventory: Some(HostInventory {
+                    kernel_version: Some("6.2.0".into()),
+                    cpu_count: Some(4),
+                    iommu: Some(false),
+                    ..Default::default()
+                }),
+            }
+            .encode_to_vec(),
+        ),
+        (
+            "BootRequest",
+            BootRequest {
+                shim: Some(item(b"shim")),
+                exec: Some(item(b"exec")),
+                work: Some(item(b"\0asm\x01\0\0\0")),
+                listen_fds: vec![],
+            }
+            .encode_to_vec(),
+        ),
+        (
+            "Result",
+            Result {
+                code: Code::Invalid as i32,
+                message: "invalid request".into(),
+                details: vec![prost_types::Any {
+                    type_url: "type.googleapis.com/enarx.v0.FieldViolation".into(),
+                    value: violation.encode_to_vec(),
+                }],
+            }
+            .encode_to_vec(),
+        ),
+        ("FieldViolation", violation.encode_to_vec()),
+    ]
+}
+
+/// A change to a field that breaks messages encoded by older code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldChange {
+    /// A field number now means something else: a different name, type or
+    /// label, or it was reserved and is in use again
+    Reused {
+        path: String,
+        number: i32,
+        old: String,
+        new: String,
+    },
+    /// A field was dropped without reserving its number, so nothing stops
+    /// the next one from reusing it
+    Unreserved { path: String, number: i32 },
+}
+
+impl std::fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldChange::Reused {
+                path,
+                number,
+                old,
+                new,
+            } => write!(
+                f,
+                "{} (field {}) was `{}`, now `{}`",
+                path, number, old, new
+            ),
+            FieldChange::Unreserved { path, number } => write!(
+                f,
+                "{} (field {}) was removed without reserving its number",
+                path, number
+            ),
+        }
+    }
+}
+
+/// Compare two encoded descriptor sets, returning every field in `old`
+/// whose number `new` has reused or left unreserved. Messages that are only
+/// in one of them are skipped.
+pub fn check_descriptor_sets(
+    old: &[u8],
+    new: &[u8],
+) -> std::result::Result<Vec<FieldChange>, prost::DecodeError> {
+    let old = FileDescriptorSet::decode(old)?;
+    let new = FileDescriptorSet::decode(new)?;
+    let new_messages = messages(&new);
+    let mut changes = Vec::new();
+    for (name, old_msg) in messages(&old) {
+        let new_msg = match new_messages.get(&name) {
+            Some(msg) => msg,
+            None => continue,
+        };
+        for old_field in &old_msg.field {
+            let number = old_field.number();
+            let path = format!("{}.{}", name, old_field.name());
+            match new_msg.field.iter().find(|f| f.number() == number) {
+                Some(new_field) if describe(new_field) != describe(old_field) => {
+                    changes.push(FieldChange::Reused {
+                        path,
+                        number,
+                        old: describe(old_field),
+                        new: describe(new_field),
+                    })
+                }
+                Some(_) => {}
+                None if !is_reserved(new_msg, number) => {
+                    changes.push(FieldChange::Unreserved { path, number })
+                }
+                None => {}
+            }
+        }
+        for new_field in &new_msg.field {
+            let number = new_field.number();
+            if is_reserved(old_msg, number) {
+                changes.push(FieldChange::Reused {
+                    path: format!("{}.{}", name, new_field.name()),
+                    number,
+                    old: "reserved".into(),
+                    new: describe(new_field),
+                });
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// Every message in `set`, nested ones included, by full name (like
+/// `enarx.v0.BootRequest.BootItem`)
+fn messages(set: &FileDescriptorSet) -> BTreeMap<String, &DescriptorProto> {
+    fn add<'a>(
+        out: &mut BTreeMap<String, &'a DescriptorProto>,
+        prefix: &str,
+        messages: &'a [DescriptorProto],
+    ) {
+        for msg in messages {
+            let name = format!("{}.{}", prefix, msg.name());
+            add(out, &name, &msg.nested_type);
+            out.insert(name, msg);
+        }
+    }
+    let mut out = BTreeMap::new();
+    for file in &set.file {
+        add(&mut out, file.package(), &file.message_type);
+    }
+    out
+}
+
+/// A field as it'd be declared, like `optional bytes blob` or
+/// `repeated .google.protobuf.Any details`
+fn describe(field: &FieldDescriptorProto) -> String {
+    let ty = match field.type_name() {
+        "" => format!("{:?}", field.r#type()).to_lowercase(),
+        name => name.to_string(),
+    };
+    let label = format!("{:?}", field.label()).to_lowercase();
+    format!("{} {} {}", label, ty, field.name())
+}
+
+fn is_reserved(msg: &DescriptorProto, number: i32) -> bool {
+    (msg.reserved_range.iter()).any(|r| (r.start()..r.end()).contains(&number))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_types::descriptor_proto::ReservedRange;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::FileDescriptorProto;
+    use std::path::{Path, PathBuf};
+
+    /// Parse a fixtures directory name like "0.1.0"; None if it isn't one
+    fn fixture_version(name: &str) -> Option<Vec<u64>> {
+        name.split('.').map(|n| n.parse().ok()).collect()
+    }
+
+    /// The fixtures/VERSION directories, oldest first. Anything else in
+    /// fixtures/ (a README, editor droppings) is skipped.
+    fn fixture_dirs() -> Vec<PathBuf> {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let mut dirs: Vec<(Vec<u64>, PathBuf)> = std::fs::read_dir(root)
+            .unwrap()
+            .filter_map(|entry| {
+                let path = entry.unwrap().path();
+                let version = fixture_version(path.file_name()?.to_str()?)?;
+                Some((version, path)).filter(|(_, path)| path.is_dir())
+            })
+            .collect();
+        dirs.sort();
+        dirs.into_iter().map(|(_, path)| path).collect()
+    }
+
+    fn blob(item: Option<BootItem>) -> Vec<u8> {
+        match item.and_then(|item| item.from) {
+            Some(boot_item::From::Blob(blob)) => blob,
+            None => panic!("no blob"),
+        }
+    }
+
+    /// Decode `bytes` and check that encoding it again gives the same bytes
+    fn round_trip<M: Message + Default>(bytes: &[u8]) -> M {
+        let msg = M::decode(bytes).unwrap();
+        assert_eq!(msg.encode_to_vec(), bytes);
+        msg
+    }
+
+    /// Check the fields of fixture `name` that every release has had
+    fn check_fixture(name: &str, bytes: &[u8]) {
+        match name {
+            "InfoRequest" => {
+                round_trip::<InfoRequest>(bytes);
+            }
+            "KeepldrInfo" => {
+                let info: KeepldrInfo = round_trip(bytes);
+                assert_eq!(info.name, "keepldr");
+                assert_eq!(info.version, "0.1.0");
+                assert_eq!(info.sallyport_version, "0.1.0");
+                let backend = info.backend.unwrap();
+                assert!(backend.kvm.is_some() && backend.sev.is_none());
+                assert_eq!(backend.sgx.unwrap().max_enclave_size_bits, 36);
+            }
+            "BootRequest" => {
+                let boot: BootRequest = round_trip(bytes);
+                assert_eq!(blob(boot.shim), b"shim");
+                assert_eq!(blob(boot.exec), b"exec");
+                assert_eq!(blob(boot.work), b"\0asm\x01\0\0\0");
+            }
+            "Result" => {
+                let result: Result = round_trip(bytes);
+                assert_eq!(result.code(), Code::Invalid);
+                assert_eq!(result.message, "invalid request");
+                let violation = FieldViolation::decode(&*result.details[0].value).unwrap();
+                assert_eq!(violation.field, "exec.blob");
+            }
+            "FieldViolation" => {
+                let violation: FieldViolation = round_trip(bytes);
+                assert_eq!(violation.field, "exec.blob");
+                assert_eq!(violation.description, "empty");
+            }
+            _ => panic!("no check for fixture {:?}", name),
+        }
+    }
+
+    #[test]
+    fn historical_fixtures() {
+        let dirs = fixture_dirs();
+        assert!(!dirs.is_empty());
+        for dir in dirs {
+            let mut seen = 0;
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                let name = path.file_stem().unwrap().to_str().unwrap();
+                if name != "descriptor" {
+                    check_fixture(name, &std::fs::read(&path).unwrap());
+                    seen += 1;
+                }
+            }
+            // Every release covers at least the messages the first one did
+            assert!(seen >= 5, "{} is missing fixtures", dir.display());
+        }
+    }
+
+    #[test]
+    fn fixture_versions() {
+        assert_eq!(fixture_version("0.1.0"), Some(vec![0, 1, 0]));
+        assert_eq!(fixture_version("0.10.2"), Some(vec![0, 10, 2]));
+        assert_eq!(fixture_version("README.md"), None);
+        assert_eq!(fixture_version("0.2.0-rc1"), None);
+        assert_eq!(fixture_version(".gitkeep"), None);
+    }
+
+    #[test]
+    fn samples_pass_fixture_checks() {
+        for (name, bytes) in samples() {
+            check_fixture(name, &bytes);
+        }
+    }
+
+    #[test]
+    fn no_field_number_reuse() {
+        for dir in fixture_dirs() {
+            let old = std::fs::read(dir.join("descriptor.bin")).unwrap();
+            let changes = check_descriptor_sets(&old, FILE_DESCRIPTOR_SET).unwrap();
+            let changes: Vec<String> = changes.iter().map(ToString::to_string).collect();
+            assert!(
+                changes.is_empty(),
+                "incompatible with {}:\n{}",
+                dir.display(),
+                changes.join("\n")
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let (_, mut bytes) = samples().remove(2);
+        // Field 1000, varint 1: what a newer client might send
+        bytes.extend(&[0xc0, 0x3e, 0x01]);
+        let boot = BootRequest::decode(&*bytes).unwrap();
+        assert_eq!(blob(boot.shim.clone()), b"shim");
+        // prost doesn't keep unknown fields, so they're lost on re-encoding
+        assert_eq!(boot.encode_to_vec(), samples().remove(2).1);
+    }
+
+    fn field(name: &str, number: i32, ty: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.into()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(ty as i32),
+            ..Default::default()
+        }
+    }
+
+    fn set(fields: Vec<FieldDescriptorProto>, reserved: &[i32]) -> Vec<u8> {
+        let msg = DescriptorProto {
+            name: Some("Item".into()),
+            field: fields,
+            reserved_range: (reserved.iter())
+                .map(|&n| ReservedRange {
+                    start: Some(n),
+                    end: Some(n + 1),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let outer = DescriptorProto {
+            name: Some("Request".into()),
+            nested_type: vec![msg],
+            ..Default::default()
+        };
+        FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                package: Some("test".into()),
+                message_type: vec![outer],
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    #[test]
+    fn descriptor_changes() {
+        let old = set(
+            vec![field("blob", 1, Type::Bytes), field("url", 2, Type::String)],
+            &[3],
+        );
+        let check = |new: Vec<u8>| check_descriptor_sets(&old, &new).unwrap();
+
+        // Adding a field, or dropping one and reserving its number, is fine
+        let added = vec![
+            field("blob", 1, Type::Bytes),
+            field("url", 2, Type::String),
+            field("digest", 4, Type::Bytes),
+        ];
+        assert!(check(set(added, &[3])).is_empty());
+        assert!(check(set(vec![field("blob", 1, Type::Bytes)], &[2, 3])).is_empty());
+
+        let reused = vec![
+            field("blob", 1, Type::Bytes),
+            field("path", 2, Type::String),
+        ];
+        let changes = check(set(reused, &[3]));
+        assert_eq!(
+            changes[0].to_string(),
+            "test.Request.Item.url (field 2) was `optional string url`, now `optional string path`"
+        );
+
+        let retyped = vec![
+            field("blob", 1, Type::String),
+            field("url", 2, Type::String),
+        ];
+        assert!(matches!(
+            &check(set(retyped, &[3]))[..],
+            [FieldChange::Reused { path, number: 1, .. }] if path == "test.Request.Item.blob"
+        ));
+
+        let dropped = vec![field("blob", 1, Type::Bytes)];
+        assert_eq!(
+            check(set(dropped, &[3])),
+            vec![FieldChange::Unreserved {
+                path: "test.Request.Item.url".into(),
+                number: 2
+            }]
+        );
+
+        let unreserved = vec![
+            field("blob", 1, Type::Bytes),
+            field("url", 2, Type::String),
+            field("old", 3, Type::Bytes),
+        ];
+        assert!(matches!(
+            &check(set(unreserved, &[]))[..],
+            [FieldChange::Reused { number: 3, old, .. }] if old == "reserved"
+        ));
+    }
+}
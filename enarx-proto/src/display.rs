@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Log-safe rendering of requests.
+//!
+//! The derived `Debug` output for a message includes every byte of every
+//! field, which is both huge and (for anything carrying secrets) a leak.
+//! `SafeDisplay` shows a message's structure and sizes instead, so it's
+//! what should go into logs and error messages.
+
+use crate::v0::boot_request::{boot_item, BootItem};
+use crate::v0::{BootRequest, FieldViolation, Result};
+use prost::Message;
+use std::fmt;
+
+/// The `type_url` of a `FieldViolation` packed into a `Result`'s details
+const FIELD_VIOLATION_URL: &str = "type.googleapis.com/enarx.v0.FieldViolation";
+
+/// Messages that can describe themselves without showing their contents
+pub trait SafeDisplay {
+    fn safe_fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+
+    /// Wrap `self` in something that `Display`s with `safe_fmt()`
+    fn safe_display(&self) -> Safe<'_, Self> {
+        Safe(self)
+    }
+}
+
+/// A message that implements `Display` by way of `SafeDisplay`
+pub struct Safe<'a, T: ?Sized>(&'a T);
+
+impl<T: SafeDisplay + ?Sized> fmt::Display for Safe<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.safe_fmt(f)
+    }
+}
+
+impl<T: SafeDisplay> SafeDisplay for Option<T> {
+    fn safe_fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Some(inner) => inner.safe_fmt(f),
+            None => write!(f, "none"),
+        }
+    }
+}
+
+impl SafeDisplay for boot_item::From {
+    fn safe_fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            boot_item::From::Blob(blob) => write!(f, "blob ({} bytes)", blob.len()),
+        }
+    }
+}
+
+impl SafeDisplay for BootItem {
+    fn safe_fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.from {
+            Some(from) => from.safe_fmt(f),
+            None => write!(f, "empty"),
+        }
+    }
+}
+
+impl SafeDisplay for BootRequest {
+    fn safe_fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "shim: {}, exec: {}, work: {}",
+            self.shim.safe_display(),
+            self.exec.safe_display(),
+            self.work.safe_display()
+        )?;
+        // Socket names come from the host's config, so they're safe to show
+        for (i, lfd) in self.listen_fds.iter().enumerate() {
+            let sep = if i == 0 { ", listen_fds: " } else { ", " };
+            write!(f, "{}{:?} on fd {}", sep, lfd.name, lfd.fd)?;
+        }
+        Ok(())
+    }
+}
+
+/// Shows the code, the message, and each of the details that's a
+/// `FieldViolation` as `field: description`. Other details only get their
+/// type and size shown.
+impl SafeDisplay for Result {
+    fn safe_fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code(), self.message)?;
+        for detail in &self.details {
+            match FieldViolation::decode(detail.value.as_slice()) {
+                Ok(violation) if detail.type_url == FIELD_VIOLATION_URL => {
+                    write!(f, "; {}", violation)?
+                }
+                _ => write!(f, "; {} ({} bytes)", detail.type_url, detail.value.len())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v0::boot_request::ListenFd;
+
+    fn blob(bytes: &[u8]) -> Option<BootItem> {
+        Some(BootItem {
+            from: Some(boot_item::From::Blob(bytes.to_vec())),
+        })
+    }
+
+    #[test]
+    fn boot_request() {
+        let canary = b"CANARY-hunter2";
+        let req = BootRequest {
+            shim: None,
+            exec: Some(BootItem { from: None }),
+            work: blob(canary),
+            listen_fds: vec![],
+        };
+        let shown = req.safe_display().to_string();
+        assert_eq!(shown, "shim: none, exec: empty, work: blob (14 bytes)");
+        assert!(!shown.contains("CANARY"));
+        assert!(format!("{:?}", req).contains("67, 65, 78"));
+    }
+
+    #[test]
+    fn result() {
+        let violation = FieldViolation {
+            field: "exec.blob".into(),
+            description: "empty".into(),
+        };
+        let result = Result {
+            code: crate::v0::Code::Invalid as i32,
+            message: "invalid request".into(),
+            details: vec![
+                prost_types::Any {
+                    type_url: FIELD_VIOLATION_URL.into(),
+                    value: violation.encode_to_vec(),
+                },
+                prost_types::Any {
+                    type_url: "type.googleapis.com/x.Secret".into(),
+                    value: b"hunter2".to_vec(),
+                },
+            ],
+        };
+        assert_eq!(
+            result.safe_display().to_string(),
+            "Invalid: invalid request; exec.blob: empty; type.googleapis.com/x.Secret (7 bytes)"
+        );
+    }
+
+    #[test]
+    fn boot_request_listen_fds() {
+        let lfd = |name: &str, fd| ListenFd {
+            name: name.into(),
+            fd,
+        };
+        let req = BootRequest {
+            shim: blob(b"shim"),
+            exec: blob(b"exec"),
+            work: None,
+            listen_fds: vec![lfd("web", 3), lfd("admin", 4)],
+        };
+        assert_eq!(
+            req.safe_display().to_string(),
+            "shim: blob (4 bytes), exec: blob (4 bytes), work: none, \
+            listen_fds: \"web\" on fd 3, \"admin\" on fd 4"
+        );
+    }
+}
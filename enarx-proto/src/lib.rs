@@ -7,11 +7,16 @@ pub mod v0;
 /* If we're using OUT_DIR in build.rs, then this works */
 //pub mod v0 { tonic::include_proto!("enarx.v0"); }
 
+pub mod compat;
+pub mod display;
+pub mod validate;
+
 #[cfg(test)]
 mod tests {
     // Check for expected public struct names / behaviors
     #[test]
     fn pub_names() {
+        #[allow(unused_imports)]
         use crate::v0::{BackendInfo, BootRequest, Code, InfoRequest, KeepldrInfo, Result};
         let r = Result {
             code: Code::Ok as i32,
@@ -0,0 +1,235 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sanity checks for incoming (and outgoing) requests.
+//!
+//! Protobuf will happily decode a message with empty or enormous fields, so
+//! both ends should check messages against some caller-supplied `Limits`
+//! before doing anything else with them.
+
+use crate::v0::boot_request::{boot_item, BootItem};
+use crate::v0::{BootRequest, FieldViolation};
+use prost::Message;
+
+/// Limits on the size of request fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    /// Maximum size of a single blob, in bytes
+    pub max_blob_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_blob_size: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Why a field was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    Missing,
+    Empty,
+    /// Only the host fills this in; clients must leave it unset
+    HostOnly,
+    TooLarge {
+        size: usize,
+        limit: usize,
+    },
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::Missing => write!(f, "missing"),
+            Reason::Empty => write!(f, "empty"),
+            Reason::HostOnly => write!(f, "set by the host, not the client"),
+            Reason::TooLarge { size, limit } => {
+                write!(f, "too large ({} bytes, limit {})", size, limit)
+            }
+        }
+    }
+}
+
+/// A field that failed validation, and the path to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub field: String,
+    pub reason: Reason,
+}
+
+impl ValidationError {
+    pub fn new(field: &str, reason: Reason) -> Self {
+        Self {
+            field: field.to_string(),
+            reason,
+        }
+    }
+
+    /// Prefix the field path with the name of the containing field
+    fn within(mut self, parent: &str) -> Self {
+        self.field = format!("{}.{}", parent, self.field);
+        self
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.reason)
+    }
+}
+
+impl From<ValidationError> for FieldViolation {
+    fn from(error: ValidationError) -> Self {
+        FieldViolation {
+            field: error.field,
+            description: error.reason.to_string(),
+        }
+    }
+}
+
+/// Shows the violation as `field: description`, the same way as the
+/// `ValidationError` it came from
+impl std::fmt::Display for FieldViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.description)
+    }
+}
+
+impl From<ValidationError> for tonic::Status {
+    fn from(error: ValidationError) -> Self {
+        let violation = FieldViolation::from(error);
+        tonic::Status::with_details(
+            tonic::Code::InvalidArgument,
+            violation.to_string(),
+            violation.encode_to_vec().into(),
+        )
+    }
+}
+
+/// Messages that can check themselves against some `Limits`
+pub trait Validate {
+    fn validate(&self, limits: &Limits) -> Result<(), ValidationError>;
+}
+
+impl Validate for BootItem {
+    fn validate(&self, limits: &Limits) -> Result<(), ValidationError> {
+        match &self.from {
+            None => Err(ValidationError::new("from", Reason::Missing)),
+            Some(boot_item::From::Blob(blob)) if blob.is_empty() => {
+                Err(ValidationError::new("blob", Reason::Empty))
+            }
+            Some(boot_item::From::Blob(blob)) if blob.len() > limits.max_blob_size => {
+                let reason = Reason::TooLarge {
+                    size: blob.len(),
+                    limit: limits.max_blob_size,
+                };
+                Err(ValidationError::new("blob", reason))
+            }
+            Some(boot_item::From::Blob(_)) => Ok(()),
+        }
+    }
+}
+
+/// A BootRequest needs a shim and an exec; the workload is optional.
+impl Validate for BootRequest {
+    fn validate(&self, limits: &Limits) -> Result<(), ValidationError> {
+        let items = [
+            ("shim", &self.shim, true),
+            ("exec", &self.exec, true),
+            ("work", &self.work, false),
+        ];
+        for (name, item, required) in items.iter() {
+            match item {
+                Some(item) => item.validate(limits).map_err(|e| e.within(name))?,
+                None if *required => return Err(ValidationError::new(name, Reason::Missing)),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits { max_blob_size: 16 };
+
+    fn blob(size: usize) -> Option<BootItem> {
+        Some(BootItem {
+            from: Some(boot_item::From::Blob(vec![0u8; size])),
+        })
+    }
+
+    fn request(field: &str, item: Option<BootItem>) -> BootRequest {
+        let mut req = BootRequest {
+            shim: blob(1),
+            exec: blob(1),
+            work: None,
+            listen_fds: vec![],
+        };
+        match field {
+            "shim" => req.shim = item,
+            "exec" => req.exec = item,
+            "work" => req.work = item,
+            _ => unreachable!(),
+        }
+        req
+    }
+
+    #[test]
+    fn boot_request_boundaries() {
+        let too_large = Reason::TooLarge {
+            size: 17,
+            limit: 16,
+        };
+        for field in &["shim", "exec", "work"] {
+            let path = format!("{}.blob", field);
+            let cases = [
+                (blob(0), Err(ValidationError::new(&path, Reason::Empty))),
+                (blob(1), Ok(())),
+                (blob(16), Ok(())),
+                (
+                    blob(17),
+                    Err(ValidationError::new(&path, too_large.clone())),
+                ),
+                (
+                    Some(BootItem { from: None }),
+                    Err(ValidationError::new(
+                        &format!("{}.from", field),
+                        Reason::Missing,
+                    )),
+                ),
+                (
+                    None,
+                    match *field {
+                        "work" => Ok(()),
+                        _ => Err(ValidationError::new(field, Reason::Missing)),
+                    },
+                ),
+            ];
+            for (item, expected) in cases.iter() {
+                let req = request(field, item.clone());
+                assert_eq!(req.validate(&LIMITS), *expected, "{:?}", req);
+            }
+        }
+    }
+
+    #[test]
+    fn status_details() {
+        let req = request("exec", blob(0));
+        let status = tonic::Status::from(req.validate(&LIMITS).unwrap_err());
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "exec.blob: empty");
+        let violation = FieldViolation::decode(status.details()).unwrap();
+        assert_eq!(violation.field, "exec.blob");
+        assert_eq!(violation.description, "empty");
+        assert_eq!(violation.to_string(), status.message());
+
+        let status = tonic::Status::from(request("shim", None).validate(&LIMITS).unwrap_err());
+        assert_eq!(status.message(), "shim: missing");
+    }
+}
@@ -0,0 +1,16 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+use enarx_proto::v0::BootRequest;
+use enarx_proto::validate::{Limits, Validate};
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+
+fuzz_target!(|data: &[u8]| {
+    let limits = Limits {
+        max_blob_size: 4096,
+    };
+    if let Ok(request) = BootRequest::decode_length_delimited(data) {
+        let _ = request.validate(&limits);
+    }
+});
@@ -0,0 +1,17 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+use enarx_config::{EnvConfig, WasmFeatureFlags};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|s: &str| {
+    // NAME=VALUE, the way `enarx run --env` splits it
+    if let Some((name, value)) = s.split_once('=') {
+        let mut env = EnvConfig::default();
+        if env.push_value(name, value).is_ok() {
+            let _ = env.validate();
+        }
+        let _ = format!("{:?}", env);
+    }
+    let _ = s.parse::<WasmFeatureFlags>();
+});
@@ -0,0 +1,20 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+use enarx_config::{ReadHandle, WriteHandle};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|s: &str| {
+    // Anything that parses should print back as something that parses the
+    // same way
+    if let Ok(handle) = s.parse::<ReadHandle>() {
+        let printed = handle.to_string();
+        let reparsed: ReadHandle = printed.parse().expect("ReadHandle round trip");
+        assert_eq!(reparsed.to_string(), printed);
+    }
+    if let Ok(handle) = s.parse::<WriteHandle>() {
+        let printed = handle.to_string();
+        let reparsed: WriteHandle = printed.parse().expect("WriteHandle round trip");
+        assert_eq!(reparsed.to_string(), printed);
+    }
+});
@@ -0,0 +1,9 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+use enarx_config::parse_size;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|s: &str| {
+    let _ = parse_size(s);
+});
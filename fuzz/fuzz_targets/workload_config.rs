@@ -0,0 +1,11 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+use enarx_config::fuzzing::parse_workload_config;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|s: &str| {
+    if let Ok(config) = parse_workload_config(s) {
+        let _ = config.env.validate();
+    }
+});